@@ -1,4 +1,5 @@
-use crate::interfaces::{OrderBook, Side, Update};
+use crate::interfaces::{OrderBook, Price, Side, Update};
+use crate::orderbook::splitmix64;
 use std::hint::black_box;
 use std::time::Instant;
 
@@ -16,6 +17,11 @@ pub struct BenchmarkResult {
     pub avg_best_bid_ns: f64,
     pub avg_best_ask_ns: f64,
     pub avg_random_read_ns: f64,
+    pub avg_best_removal_ns: f64,
+    pub avg_sparse_depth_ns: f64,
+    pub avg_sparse_depth_into_ns: f64,
+    pub avg_packet_sequential_ns: f64,
+    pub avg_packet_batched_ns: f64,
     pub p50_update_ns: f64,
     pub p95_update_ns: f64,
     pub p99_update_ns: f64,
@@ -36,12 +42,21 @@ impl OrderBookBenchmark {
         let best_bid_timings = Self::benchmark_best_bid(&ob, iterations / 10);
         let best_ask_timings = Self::benchmark_best_ask(&ob, iterations / 10);
         let read_timings = Self::benchmark_random_reads(&ob, iterations / 10);
+        let removal_timings = Self::benchmark_best_removal(&mut ob, iterations / 10);
+        let depth_timings = Self::benchmark_sparse_depth::<T>(iterations / 100);
+        let depth_into_timings = Self::benchmark_sparse_depth_into::<T>(iterations / 100);
+        let (sequential_timings, batched_timings) = Self::benchmark_packets::<T>(iterations / 100);
 
         let avg_update = Self::average(&update_timings);
         let avg_spread = Self::average(&spread_timings);
         let avg_best_bid = Self::average(&best_bid_timings);
         let avg_best_ask = Self::average(&best_ask_timings);
         let avg_read = Self::average(&read_timings);
+        let avg_removal = Self::average(&removal_timings);
+        let avg_depth = Self::average(&depth_timings);
+        let avg_depth_into = Self::average(&depth_into_timings);
+        let avg_sequential = Self::average(&sequential_timings);
+        let avg_batched = Self::average(&batched_timings);
 
         let mut sorted_updates = update_timings.clone();
         sorted_updates.sort_by(|a, b| a.partial_cmp(b).unwrap());
@@ -53,6 +68,11 @@ impl OrderBookBenchmark {
             avg_best_bid_ns: avg_best_bid,
             avg_best_ask_ns: avg_best_ask,
             avg_random_read_ns: avg_read,
+            avg_best_removal_ns: avg_removal,
+            avg_sparse_depth_ns: avg_depth,
+            avg_sparse_depth_into_ns: avg_depth_into,
+            avg_packet_sequential_ns: avg_sequential,
+            avg_packet_batched_ns: avg_batched,
             p50_update_ns: sorted_updates[sorted_updates.len() / 2],
             p95_update_ns: sorted_updates[sorted_updates.len() * 95 / 100],
             p99_update_ns: sorted_updates[sorted_updates.len() * 99 / 100],
@@ -91,7 +111,7 @@ impl OrderBookBenchmark {
 
             let start = Instant::now();
             for _ in 0..BATCH {
-                black_box(ob.apply_update(update.clone()));
+                ob.apply_update(black_box(update.clone()));
             }
             let elapsed = start.elapsed().as_nanos() as f64;
 
@@ -177,6 +197,159 @@ impl OrderBookBenchmark {
         timings
     }
 
+    // =========================================================================
+    // BENCHMARK BEST REMOVAL
+    // =========================================================================
+    // Remove the best bid and put it back: each pair forces a best recompute
+    fn benchmark_best_removal<T: OrderBook>(ob: &mut T, iterations: usize) -> Vec<f64> {
+        let mut timings = Vec::with_capacity(iterations);
+        let Some(best) = ob.get_best_bid() else {
+            return timings;
+        };
+        let quantity = ob.get_quantity_at(best, Side::Bid).unwrap_or(1);
+
+        for _ in 0..iterations {
+            let start = Instant::now();
+            for _ in 0..BATCH {
+                ob.apply_update(black_box(Update::Remove {
+                    price: best,
+                    side: Side::Bid,
+                }));
+                ob.apply_update(black_box(Update::Set {
+                    price: best,
+                    quantity,
+                    side: Side::Bid,
+                }));
+            }
+            let elapsed = start.elapsed().as_nanos() as f64;
+            timings.push(elapsed / (2 * BATCH) as f64);
+        }
+
+        timings
+    }
+
+    // =========================================================================
+    // BENCHMARK SPARSE DEPTH
+    // =========================================================================
+    // Top 10 of each side of a fresh book holding 20 levels per side, 1600
+    // ticks apart: a depth query has to skip long runs of empty slots
+    fn benchmark_sparse_depth<T: OrderBook>(iterations: usize) -> Vec<f64> {
+        let mut timings = Vec::with_capacity(iterations);
+        let ob = Self::sparse_book::<T>();
+
+        for i in 0..iterations {
+            let side = if i % 2 == 0 { Side::Bid } else { Side::Ask };
+
+            let start = Instant::now();
+            for _ in 0..BATCH {
+                black_box(ob.get_top_levels(black_box(side), 10));
+            }
+            let elapsed = start.elapsed().as_nanos() as f64;
+            timings.push(elapsed / BATCH as f64);
+        }
+
+        timings
+    }
+
+    // The same query written into a reused buffer instead of a fresh Vec
+    fn benchmark_sparse_depth_into<T: OrderBook>(iterations: usize) -> Vec<f64> {
+        let mut timings = Vec::with_capacity(iterations);
+        let ob = Self::sparse_book::<T>();
+        let mut out = [(0, 0); 10];
+
+        for i in 0..iterations {
+            let side = if i % 2 == 0 { Side::Bid } else { Side::Ask };
+
+            let start = Instant::now();
+            for _ in 0..BATCH {
+                black_box(ob.get_top_levels_into(black_box(side), &mut out));
+            }
+            let elapsed = start.elapsed().as_nanos() as f64;
+            timings.push(elapsed / BATCH as f64);
+        }
+
+        timings
+    }
+
+    fn sparse_book<T: OrderBook>() -> T {
+        let mut ob = T::new();
+        for i in 0..20 {
+            ob.apply_update(Update::Set {
+                price: 100_000 - i * 1_600,
+                quantity: 100,
+                side: Side::Bid,
+            });
+            ob.apply_update(Update::Set {
+                price: 100_010 + i * 1_600,
+                quantity: 100,
+                side: Side::Ask,
+            });
+        }
+        ob
+    }
+
+    // =========================================================================
+    // BENCHMARK PACKETS
+    // =========================================================================
+    // The same packets of 100 updates, 30% of which remove the current best,
+    // applied one by one to one book and through `apply_updates` to another.
+    // Returns per-update timings for each.
+    fn benchmark_packets<T: OrderBook>(packets: usize) -> (Vec<f64>, Vec<f64>) {
+        const PACKET: usize = 100;
+        let mut sequential = T::new();
+        let mut batched = T::new();
+        Self::warmup(&mut sequential);
+        Self::warmup(&mut batched);
+
+        // Generate against a third book so removals track the real best
+        let mut model = T::new();
+        Self::warmup(&mut model);
+        let mut workload = Vec::with_capacity(packets);
+        for p in 0..packets {
+            let mut packet = Vec::with_capacity(PACKET);
+            for i in 0..PACKET {
+                let n = (p * PACKET + i) as i64;
+                let side = if n % 2 == 0 { Side::Bid } else { Side::Ask };
+                let best = match side {
+                    Side::Bid => model.get_best_bid(),
+                    Side::Ask => model.get_best_ask(),
+                };
+                let update = match (n % 10, best) {
+                    (0..3, Some(price)) => Update::Remove { price, side },
+                    _ => Update::Set {
+                        price: match side {
+                            Side::Bid => 100_990 - (n * 37 % 100) * 10,
+                            Side::Ask => 101_100 + (n * 37 % 100) * 10,
+                        },
+                        quantity: 50 + (n as u64 % 200),
+                        side,
+                    },
+                };
+                model.apply_update(update.clone());
+                packet.push(update);
+            }
+            workload.push(packet);
+        }
+
+        let mut sequential_timings = Vec::with_capacity(packets);
+        let mut batched_timings = Vec::with_capacity(packets);
+        for packet in &workload {
+            let start = Instant::now();
+            for update in packet {
+                sequential.apply_update(black_box(update.clone()));
+            }
+            let elapsed = start.elapsed().as_nanos() as f64;
+            sequential_timings.push(elapsed / PACKET as f64);
+
+            let start = Instant::now();
+            batched.apply_updates(black_box(packet));
+            let elapsed = start.elapsed().as_nanos() as f64;
+            batched_timings.push(elapsed / PACKET as f64);
+        }
+
+        (sequential_timings, batched_timings)
+    }
+
     // =========================================================================
     // STATS
     // =========================================================================
@@ -200,6 +373,372 @@ impl OrderBookBenchmark {
         println!("  Get Best Ask:   {:.3} ns", result.avg_best_ask_ns);
         println!("  Get Spread:     {:.3} ns", result.avg_spread_ns);
         println!("  Random Reads:   {:.3} ns", result.avg_random_read_ns);
+        println!("  Best Removal:   {:.3} ns", result.avg_best_removal_ns);
+        println!("  Sparse Depth:");
+        println!("    Vec:        {:.3} ns", result.avg_sparse_depth_ns);
+        println!("    Buffer:     {:.3} ns", result.avg_sparse_depth_into_ns);
+        println!("  Packet Updates:");
+        println!("    Sequential: {:.3} ns", result.avg_packet_sequential_ns);
+        println!("    Batched:    {:.3} ns", result.avg_packet_batched_ns);
+        println!("{}", "=".repeat(60));
+    }
+}
+
+// ============================================================================
+// LATENCY HISTOGRAM
+// ============================================================================
+// Averages hide the tail: the odd update that empties the best level and
+// sends the book hunting for the next one costs far more than the rest, but
+// barely moves the mean. The latency benchmark times operations one at a
+// time and buckets them here instead.
+
+/// Bits of sub-bucket resolution per power of two: values are kept to
+/// within 1/16 (about 6%), and everything below 16 ns exactly
+const SUB_BITS: u32 = 4;
+const SUB_BUCKETS: usize = 1 << SUB_BITS;
+const BUCKETS: usize = (64 - SUB_BITS as usize + 1) * SUB_BUCKETS;
+
+/// Log-scaled histogram of latencies in nanoseconds
+#[derive(Debug, Clone)]
+pub struct LatencyHistogram {
+    counts: Box<[u64; BUCKETS]>,
+    total: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        Self {
+            counts: Box::new([0; BUCKETS]),
+            total: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+
+    /// Bucket holding `ns`: values below `SUB_BUCKETS` get a bucket each,
+    /// and every power of two above is split into `SUB_BUCKETS` equal parts
+    fn bucket_of(ns: u64) -> usize {
+        if ns < SUB_BUCKETS as u64 {
+            return ns as usize;
+        }
+        let shift = 63 - ns.leading_zeros() - SUB_BITS;
+        (shift as usize + 1) * SUB_BUCKETS + ((ns >> shift) as usize & (SUB_BUCKETS - 1))
+    }
+
+    /// Largest value that lands in `bucket`
+    fn bucket_high(bucket: usize) -> u64 {
+        if bucket < SUB_BUCKETS {
+            return bucket as u64;
+        }
+        let shift = (bucket / SUB_BUCKETS - 1) as u32;
+        let low = ((SUB_BUCKETS | (bucket % SUB_BUCKETS)) as u64) << shift;
+        low + ((1u64 << shift) - 1)
+    }
+
+    pub fn record(&mut self, ns: u64) {
+        self.counts[Self::bucket_of(ns)] += 1;
+        self.total += 1;
+        self.min = self.min.min(ns);
+        self.max = self.max.max(ns);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    /// Smallest recorded value, `None` if nothing was recorded
+    pub fn min(&self) -> Option<u64> {
+        (self.total > 0).then_some(self.min)
+    }
+
+    /// Largest recorded value, `None` if nothing was recorded
+    pub fn max(&self) -> Option<u64> {
+        (self.total > 0).then_some(self.max)
+    }
+
+    /// Value at or below which `pct` percent of the samples fall, reported
+    /// as the top of its bucket (and never past `max`). `pct` is clamped to
+    /// 0..=100; 0 gives `min`.
+    pub fn percentile(&self, pct: f64) -> Option<u64> {
+        if self.total == 0 {
+            return None;
+        }
+        let rank = ((pct.clamp(0.0, 100.0) * self.total as f64 / 100.0).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(Self::bucket_high(bucket).clamp(self.min, self.max));
+            }
+        }
+        Some(self.max)
+    }
+}
+
+// ============================================================================
+// LATENCY WORKLOADS
+// ============================================================================
+
+/// Shape of the update stream driven through the latency benchmark
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Workload {
+    /// Sets and removes within a few ticks of the touch
+    Clustered,
+    /// Sets and removes spread evenly over a wide price range
+    Uniform,
+    /// Mostly removals of the current best, refilled just behind it
+    BboChurn,
+}
+
+impl Workload {
+    pub const ALL: [Workload; 3] = [Workload::Clustered, Workload::Uniform, Workload::BboChurn];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Workload::Clustered => "clustered",
+            Workload::Uniform => "uniform",
+            Workload::BboChurn => "churn",
+        }
+    }
+
+    /// Inverse of `name`
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|w| w.name() == name)
+    }
+
+    /// Next update for `ob`, drawn from `rng`
+    fn next_update<T: OrderBook>(self, ob: &T, rng: &mut u64) -> Update {
+        const MID: Price = 100_000;
+        const TICK: Price = 10;
+        let roll = splitmix64(rng);
+        let side = if roll & 1 == 0 { Side::Bid } else { Side::Ask };
+        let quantity = 1 + (roll >> 8) % 500;
+        // Price `ticks` away from the middle of the book, on `side`'s side
+        let away = |ticks: u64| match side {
+            Side::Bid => MID - (1 + ticks as Price) * TICK,
+            Side::Ask => MID + (1 + ticks as Price) * TICK,
+        };
+        let best = match side {
+            Side::Bid => ob.get_best_bid(),
+            Side::Ask => ob.get_best_ask(),
+        };
+
+        match self {
+            Workload::Clustered => {
+                let price = away((roll >> 32) % 20);
+                if (roll >> 16).is_multiple_of(5) {
+                    Update::Remove { price, side }
+                } else {
+                    Update::Set {
+                        price,
+                        quantity,
+                        side,
+                    }
+                }
+            }
+            Workload::Uniform => {
+                let price = away((roll >> 32) % 10_000);
+                if (roll >> 16).is_multiple_of(4) {
+                    Update::Remove { price, side }
+                } else {
+                    Update::Set {
+                        price,
+                        quantity,
+                        side,
+                    }
+                }
+            }
+            Workload::BboChurn => match best {
+                Some(price) if (roll >> 16).is_multiple_of(2) => Update::Remove { price, side },
+                _ => Update::Set {
+                    price: away(5 + (roll >> 32) % 50),
+                    quantity,
+                    side,
+                },
+            },
+        }
+    }
+}
+
+/// Per-operation latency histograms for one workload
+#[derive(Debug, Clone)]
+pub struct LatencyReport {
+    pub name: String,
+    pub workload: Workload,
+    pub apply_update: LatencyHistogram,
+    pub get_best_bid: LatencyHistogram,
+    pub get_top_levels: LatencyHistogram,
+}
+
+impl OrderBookBenchmark {
+    /// Drive `samples` updates of `workload` through a fresh book, timing
+    /// every update and one `get_best_bid` and `get_top_levels(_, 10)` after
+    /// each. Samples have the cost of reading the clock subtracted.
+    pub fn run_latency<T: OrderBook>(
+        name: &str,
+        workload: Workload,
+        samples: usize,
+    ) -> LatencyReport {
+        let mut ob = T::new();
+        let mut rng = 0x5eed ^ workload as u64;
+        // Settle the book into the workload's steady state before sampling
+        for _ in 0..samples.min(50_000) {
+            let update = workload.next_update(&ob, &mut rng);
+            ob.apply_update(update);
+        }
+
+        let overhead = Self::clock_overhead();
+        let mut report = LatencyReport {
+            name: name.to_string(),
+            workload,
+            apply_update: LatencyHistogram::new(),
+            get_best_bid: LatencyHistogram::new(),
+            get_top_levels: LatencyHistogram::new(),
+        };
+        for i in 0..samples {
+            let update = workload.next_update(&ob, &mut rng);
+            let start = Instant::now();
+            ob.apply_update(black_box(update));
+            let elapsed = start.elapsed().as_nanos() as u64;
+            report.apply_update.record(elapsed.saturating_sub(overhead));
+
+            let start = Instant::now();
+            black_box(ob.get_best_bid());
+            let elapsed = start.elapsed().as_nanos() as u64;
+            report.get_best_bid.record(elapsed.saturating_sub(overhead));
+
+            let side = if i % 2 == 0 { Side::Bid } else { Side::Ask };
+            let start = Instant::now();
+            black_box(ob.get_top_levels(black_box(side), 10));
+            let elapsed = start.elapsed().as_nanos() as u64;
+            report
+                .get_top_levels
+                .record(elapsed.saturating_sub(overhead));
+        }
+
+        report
+    }
+
+    /// Fastest observed back-to-back `Instant::now()` / `elapsed()` pair
+    fn clock_overhead() -> u64 {
+        (0..10_000)
+            .map(|_| {
+                let start = Instant::now();
+                black_box(start.elapsed().as_nanos() as u64)
+            })
+            .min()
+            .unwrap_or(0)
+    }
+
+    pub fn print_latency(report: &LatencyReport) {
+        println!("\n{}", "=".repeat(60));
+        println!(
+            "  LATENCY: {} ({} workload)",
+            report.name,
+            report.workload.name()
+        );
+        println!("{}", "=".repeat(60));
+        println!(
+            "  {:<16}{:>7}{:>7}{:>7}{:>7}{:>8}{:>8}",
+            "ns", "min", "p50", "p90", "p99", "p99.9", "max"
+        );
+        for (op, histogram) in [
+            ("apply_update", &report.apply_update),
+            ("get_best_bid", &report.get_best_bid),
+            ("get_top_levels", &report.get_top_levels),
+        ] {
+            let show = |v: Option<u64>| v.map_or("-".to_string(), |v| v.to_string());
+            println!(
+                "  {:<16}{:>7}{:>7}{:>7}{:>7}{:>8}{:>8}",
+                op,
+                show(histogram.min()),
+                show(histogram.percentile(50.0)),
+                show(histogram.percentile(90.0)),
+                show(histogram.percentile(99.0)),
+                show(histogram.percentile(99.9)),
+                show(histogram.max()),
+            );
+        }
         println!("{}", "=".repeat(60));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orderbook::OrderBookImpl;
+
+    #[test]
+    fn test_histogram_exact_below_sub_buckets() {
+        let mut h = LatencyHistogram::new();
+        assert_eq!(h.percentile(50.0), None);
+        assert_eq!(h.min(), None);
+
+        for ns in (1..=10).rev() {
+            h.record(ns);
+        }
+        assert_eq!(h.count(), 10);
+        assert_eq!(h.min(), Some(1));
+        assert_eq!(h.max(), Some(10));
+        assert_eq!(h.percentile(0.0), Some(1));
+        assert_eq!(h.percentile(10.0), Some(1));
+        assert_eq!(h.percentile(50.0), Some(5));
+        assert_eq!(h.percentile(55.0), Some(6));
+        assert_eq!(h.percentile(90.0), Some(9));
+        assert_eq!(h.percentile(100.0), Some(10));
+    }
+
+    #[test]
+    fn test_histogram_tail_percentiles() {
+        let mut h = LatencyHistogram::new();
+        for _ in 0..998 {
+            h.record(3);
+        }
+        h.record(40);
+        h.record(1_000_000);
+
+        assert_eq!(h.percentile(99.0), Some(3));
+        assert_eq!(h.percentile(99.8), Some(3));
+        // Rank 999 of 1000 is the 40, reported as the top of its bucket
+        assert_eq!(h.percentile(99.9), Some(41));
+        assert_eq!(h.percentile(100.0), Some(1_000_000));
+    }
+
+    #[test]
+    fn test_histogram_bucket_bounds() {
+        let mut previous = None;
+        for ns in (0..5_000).chain([u64::MAX / 3, u64::MAX - 1, u64::MAX]) {
+            let bucket = LatencyHistogram::bucket_of(ns);
+            let high = LatencyHistogram::bucket_high(bucket);
+            assert!(high >= ns, "{ns} above the top of its bucket");
+            assert!(
+                high - ns <= ns / SUB_BUCKETS as u64,
+                "{ns} reported as {high}"
+            );
+            assert_eq!(LatencyHistogram::bucket_of(high), bucket);
+            assert!(previous.is_none_or(|p| bucket >= p));
+            previous = Some(bucket);
+        }
+        assert_eq!(LatencyHistogram::bucket_of(u64::MAX), BUCKETS - 1);
+    }
+
+    #[test]
+    fn test_run_latency_records_every_sample() {
+        for workload in Workload::ALL {
+            assert_eq!(Workload::parse(workload.name()), Some(workload));
+            let report = OrderBookBenchmark::run_latency::<OrderBookImpl>("test", workload, 500);
+            assert_eq!(report.apply_update.count(), 500);
+            assert_eq!(report.get_best_bid.count(), 500);
+            assert_eq!(report.get_top_levels.count(), 500);
+        }
+        assert_eq!(Workload::parse("bursty"), None);
+    }
+}
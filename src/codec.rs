@@ -0,0 +1,144 @@
+// ============================================================================
+// BINARY CODEC HELPERS
+// ============================================================================
+// LEB128 varints with zigzag mapping for signed values, shared by the wire
+// formats in `orderbook.rs`.
+
+/// Failure while decoding a binary stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The buffer ended in the middle of a record
+    Truncated,
+    /// A varint ran past 64 bits
+    Overflow,
+    /// Unknown op byte
+    InvalidOp(u8),
+    /// The buffer does not start with the expected magic bytes
+    BadMagic,
+    /// Format version this build cannot read
+    UnsupportedVersion(u8),
+    /// A level repeats a price or comes before a better one
+    UnorderedLevel { price: i64 },
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "buffer truncated"),
+            DecodeError::Overflow => write!(f, "varint overflows 64 bits"),
+            DecodeError::InvalidOp(op) => write!(f, "invalid op byte {op:#04x}"),
+            DecodeError::BadMagic => write!(f, "missing magic bytes"),
+            DecodeError::UnsupportedVersion(v) => write!(f, "unsupported format version {v}"),
+            DecodeError::UnorderedLevel { price } => {
+                write!(f, "level {price} is repeated or out of order")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Size of one fixed-width record in the packed update format:
+/// op (u8), side (u8), price (i64 LE), quantity (i64 LE)
+pub const PACKED_RECORD_LEN: usize = 18;
+
+/// Failure while applying a packed update stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackedError {
+    /// The buffer length is not a whole number of records
+    Truncated { len: usize },
+    /// Op byte other than 0 (set) or 1 (remove)
+    InvalidOp { record: usize, op: u8 },
+    /// Side byte other than 0 (bid) or 1 (ask)
+    InvalidSide { record: usize, side: u8 },
+    /// A set record carried a negative quantity
+    NegativeQuantity { record: usize, quantity: i64 },
+}
+
+impl std::fmt::Display for PackedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PackedError::Truncated { len } => {
+                write!(f, "{len} bytes is not a multiple of {PACKED_RECORD_LEN}")
+            }
+            PackedError::InvalidOp { record, op } => {
+                write!(f, "record {record}: invalid op byte {op:#04x}")
+            }
+            PackedError::InvalidSide { record, side } => {
+                write!(f, "record {record}: invalid side byte {side:#04x}")
+            }
+            PackedError::NegativeQuantity { record, quantity } => {
+                write!(f, "record {record}: negative quantity {quantity}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PackedError {}
+
+#[inline]
+pub(crate) fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+#[inline]
+pub(crate) fn unzigzag(v: u64) -> i64 {
+    (v >> 1) as i64 ^ -((v & 1) as i64)
+}
+
+pub(crate) fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+/// Read a varint starting at `*pos`, advancing it past the value
+pub(crate) fn get_varint(buf: &[u8], pos: &mut usize) -> Result<u64, DecodeError> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *buf.get(*pos).ok_or(DecodeError::Truncated)?;
+        *pos += 1;
+        // The 10th byte only has room for the top bit
+        if shift == 63 && byte > 1 {
+            return Err(DecodeError::Overflow);
+        }
+        v |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    Err(DecodeError::Overflow)
+}
+
+/// CRC-32 (IEEE 802.3, reflected polynomial 0xEDB88320) lookup table
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Continue a CRC-32 over `bytes`. Start from 0; chaining calls gives the
+/// CRC of the concatenated input.
+pub(crate) fn crc32_extend(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in bytes {
+        crc = CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
@@ -0,0 +1,315 @@
+// ============================================================================
+// SHADOW BOOK
+// ============================================================================
+// Runs two `OrderBook` implementations in lockstep: every update goes to both,
+// and in debug builds every query checks that they agree before returning the
+// primary's answer. Release builds only pay for the second `apply_update`.
+
+use crate::interfaces::{OrderBook, Price, Quantity, Side, Update};
+
+/// A primary book `A` shadowed by a reference book `B`
+pub struct DualBook<A: OrderBook, B: OrderBook> {
+    primary: A,
+    shadow: B,
+}
+
+impl<A: OrderBook, B: OrderBook> DualBook<A, B> {
+    /// Wrap two books that already hold the same state
+    pub fn from_parts(primary: A, shadow: B) -> Self {
+        Self { primary, shadow }
+    }
+
+    pub fn primary(&self) -> &A {
+        &self.primary
+    }
+
+    pub fn shadow(&self) -> &B {
+        &self.shadow
+    }
+
+    pub fn into_parts(self) -> (A, B) {
+        (self.primary, self.shadow)
+    }
+}
+
+impl<A: OrderBook, B: OrderBook> OrderBook for DualBook<A, B> {
+    fn new() -> Self {
+        Self {
+            primary: A::new(),
+            shadow: B::new(),
+        }
+    }
+
+    fn apply_update(&mut self, update: Update) {
+        self.shadow.apply_update(update.clone());
+        self.primary.apply_update(update);
+    }
+
+    fn apply_updates(&mut self, updates: &[Update]) {
+        self.shadow.apply_updates(updates);
+        self.primary.apply_updates(updates);
+    }
+
+    fn clear(&mut self) {
+        self.shadow.clear();
+        self.primary.clear();
+    }
+
+    fn clear_side(&mut self, side: Side) {
+        self.shadow.clear_side(side);
+        self.primary.clear_side(side);
+    }
+
+    fn get_spread(&self) -> Option<Price> {
+        let spread = self.primary.get_spread();
+        debug_assert_eq!(spread, self.shadow.get_spread(), "books disagree on spread");
+        spread
+    }
+
+    fn get_best_bid(&self) -> Option<Price> {
+        let bid = self.primary.get_best_bid();
+        debug_assert_eq!(
+            bid,
+            self.shadow.get_best_bid(),
+            "books disagree on best bid"
+        );
+        bid
+    }
+
+    fn get_best_ask(&self) -> Option<Price> {
+        let ask = self.primary.get_best_ask();
+        debug_assert_eq!(
+            ask,
+            self.shadow.get_best_ask(),
+            "books disagree on best ask"
+        );
+        ask
+    }
+
+    fn get_quantity_at(&self, price: Price, side: Side) -> Option<Quantity> {
+        let qty = self.primary.get_quantity_at(price, side);
+        debug_assert_eq!(
+            qty,
+            self.shadow.get_quantity_at(price, side),
+            "books disagree on {side:?} quantity at {price}"
+        );
+        qty
+    }
+
+    fn get_top_levels(&self, side: Side, n: usize) -> Vec<(Price, Quantity)> {
+        let levels = self.primary.get_top_levels(side, n);
+        debug_assert_eq!(
+            levels,
+            self.shadow.get_top_levels(side, n),
+            "books disagree on top {n} {side:?} levels"
+        );
+        levels
+    }
+
+    fn get_total_quantity(&self, side: Side) -> Quantity {
+        let total = self.primary.get_total_quantity(side);
+        debug_assert_eq!(
+            total,
+            self.shadow.get_total_quantity(side),
+            "books disagree on {side:?} total quantity"
+        );
+        total
+    }
+
+    fn get_mid_price(&self) -> Option<f64> {
+        let mid = self.primary.get_mid_price();
+        debug_assert_eq!(mid, self.shadow.get_mid_price(), "books disagree on mid");
+        mid
+    }
+
+    fn get_microprice(&self) -> Option<f64> {
+        let micro = self.primary.get_microprice();
+        debug_assert_eq!(
+            micro,
+            self.shadow.get_microprice(),
+            "books disagree on microprice"
+        );
+        micro
+    }
+
+    fn get_imbalance(&self, depth: usize) -> Option<f64> {
+        let imbalance = self.primary.get_imbalance(depth);
+        debug_assert_eq!(
+            imbalance,
+            self.shadow.get_imbalance(depth),
+            "books disagree on imbalance over {depth} levels"
+        );
+        imbalance
+    }
+
+    fn get_top_levels_into(&self, side: Side, out: &mut [(Price, Quantity)]) -> usize {
+        let written = self.primary.get_top_levels_into(side, out);
+        debug_assert_eq!(
+            &out[..written],
+            self.shadow.get_top_levels(side, out.len()),
+            "books disagree on top {} {side:?} levels",
+            out.len()
+        );
+        written
+    }
+
+    fn iter_levels(&self, side: Side) -> impl Iterator<Item = (Price, Quantity)> + '_ {
+        debug_assert!(
+            self.primary
+                .iter_levels(side)
+                .eq(self.shadow.iter_levels(side)),
+            "books disagree on {side:?} levels"
+        );
+        self.primary.iter_levels(side)
+    }
+
+    fn get_quantity_up_to(&self, limit: Price, side: Side) -> Quantity {
+        let qty = self.primary.get_quantity_up_to(limit, side);
+        debug_assert_eq!(
+            qty,
+            self.shadow.get_quantity_up_to(limit, side),
+            "books disagree on {side:?} quantity up to {limit}"
+        );
+        qty
+    }
+
+    fn get_sweep_cost(&self, qty: Quantity, side: Side) -> Option<(Price, i128)> {
+        let cost = self.primary.get_sweep_cost(qty, side);
+        debug_assert_eq!(
+            cost,
+            self.shadow.get_sweep_cost(qty, side),
+            "books disagree on {side:?} sweep cost of {qty}"
+        );
+        cost
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orderbook::OrderBookImpl;
+    use std::collections::BTreeMap;
+
+    /// Map-backed reference book that (deliberately) ignores `Remove`
+    #[derive(Default)]
+    struct ForgetfulBook {
+        bids: BTreeMap<Price, Quantity>,
+        asks: BTreeMap<Price, Quantity>,
+    }
+
+    impl OrderBook for ForgetfulBook {
+        fn new() -> Self {
+            Self::default()
+        }
+
+        fn apply_update(&mut self, update: Update) {
+            if let Update::Set {
+                price,
+                quantity,
+                side,
+            } = update
+            {
+                let levels = match side {
+                    Side::Bid => &mut self.bids,
+                    Side::Ask => &mut self.asks,
+                };
+                if quantity == 0 {
+                    levels.remove(&price);
+                } else {
+                    levels.insert(price, quantity);
+                }
+            }
+        }
+
+        fn get_spread(&self) -> Option<Price> {
+            Some(self.get_best_ask()? - self.get_best_bid()?)
+        }
+
+        fn get_best_bid(&self) -> Option<Price> {
+            self.bids.keys().next_back().copied()
+        }
+
+        fn get_best_ask(&self) -> Option<Price> {
+            self.asks.keys().next().copied()
+        }
+
+        fn get_quantity_at(&self, price: Price, side: Side) -> Option<Quantity> {
+            match side {
+                Side::Bid => self.bids.get(&price).copied(),
+                Side::Ask => self.asks.get(&price).copied(),
+            }
+        }
+
+        fn get_top_levels(&self, side: Side, n: usize) -> Vec<(Price, Quantity)> {
+            match side {
+                Side::Bid => self
+                    .bids
+                    .iter()
+                    .rev()
+                    .take(n)
+                    .map(|(&p, &q)| (p, q))
+                    .collect(),
+                Side::Ask => self.asks.iter().take(n).map(|(&p, &q)| (p, q)).collect(),
+            }
+        }
+
+        fn get_total_quantity(&self, side: Side) -> Quantity {
+            match side {
+                Side::Bid => self.bids.values().sum(),
+                Side::Ask => self.asks.values().sum(),
+            }
+        }
+    }
+
+    fn seed<T: OrderBook>(ob: &mut T) {
+        for (price, side) in [(10000, Side::Bid), (9990, Side::Bid), (10050, Side::Ask)] {
+            ob.apply_update(Update::Set {
+                price,
+                quantity: 100,
+                side,
+            });
+        }
+    }
+
+    #[test]
+    fn test_dual_book_agrees() {
+        let mut ob = DualBook::<OrderBookImpl, ForgetfulBook>::new();
+        seed(&mut ob);
+        ob.apply_update(Update::Set {
+            price: 10000,
+            quantity: 0,
+            side: Side::Bid,
+        });
+
+        assert_eq!(ob.get_best_bid(), Some(9990));
+        assert_eq!(ob.get_spread(), Some(60));
+        assert_eq!(ob.get_top_levels(Side::Bid, 5), [(9990, 100)]);
+        let mut out = [(0, 0); 3];
+        assert_eq!(ob.get_top_levels_into(Side::Bid, &mut out), 1);
+        assert_eq!(
+            ob.iter_levels(Side::Ask).collect::<Vec<_>>(),
+            [(10050, 100)]
+        );
+        assert_eq!(ob.get_total_quantity(Side::Ask), 100);
+        assert_eq!(ob.get_quantity_at(10000, Side::Bid), None);
+        assert_eq!(ob.get_quantity_up_to(9990, Side::Bid), 100);
+        assert_eq!(ob.get_sweep_cost(50, Side::Ask), Some((10050, 502_500)));
+        assert_eq!(ob.get_mid_price(), Some(10020.0));
+        assert_eq!(ob.get_microprice(), Some(10020.0));
+        assert_eq!(ob.get_imbalance(5), Some(0.0));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "books disagree on best bid")]
+    fn test_dual_book_panics_on_disagreement() {
+        let mut ob = DualBook::<OrderBookImpl, ForgetfulBook>::new();
+        seed(&mut ob);
+        ob.apply_update(Update::Remove {
+            price: 10000,
+            side: Side::Bid,
+        });
+
+        ob.get_best_bid();
+    }
+}
@@ -19,8 +19,41 @@ pub enum Side {
     Ask,
 }
 
+/// A single price level
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PriceLevel {
+    #[cfg_attr(feature = "serde", serde(rename = "px"))]
+    pub price: Price,
+    #[cfg_attr(feature = "serde", serde(rename = "qty"))]
+    pub quantity: Quantity,
+}
+
+/// Top-of-book view of both sides, best levels first
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BookSnapshot {
+    pub bid: Vec<PriceLevel>,
+    pub ask: Vec<PriceLevel>,
+    pub spread: Option<Price>,
+    pub mid: Option<f64>,
+}
+
+impl From<(Price, Quantity)> for PriceLevel {
+    fn from((price, quantity): (Price, Quantity)) -> Self {
+        PriceLevel { price, quantity }
+    }
+}
+
+impl From<PriceLevel> for (Price, Quantity) {
+    fn from(level: PriceLevel) -> Self {
+        (level.price, level.quantity)
+    }
+}
+
 /// Order book update operation
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Update {
     /// Add or update a price level (price, quantity, side)
     /// If quantity is 0, this level should be removed
@@ -30,10 +63,51 @@ pub enum Update {
         side: Side,
     },
 
+    /// Set a price level along with its hidden reserve quantity
+    /// The displayed quantity behaves exactly as in `Set`; the reserve is
+    /// carried alongside it and dropped when the level goes away.
+    SetWithReserve {
+        price: Price,
+        quantity: Quantity,
+        reserve: Quantity,
+        side: Side,
+    },
+
     /// Remove a price level completely
     Remove { price: Price, side: Side },
+
+    /// Adjust a price level by a signed amount (price, delta, side)
+    /// A missing level counts as 0 and the level is removed once it reaches 0.
+    /// Decrements past zero saturate: they simply remove the level.
+    Change {
+        price: Price,
+        delta: i64,
+        side: Side,
+    },
+}
+
+/// Reasons an update cannot be applied safely
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderBookError {
+    /// The price falls outside the representable slot window
+    OutOfRange { price: Price },
+    /// A removal targets a level that does not exist
+    LevelNotFound { price: Price, side: Side },
+}
+
+impl std::fmt::Display for OrderBookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OrderBookError::OutOfRange { price } => write!(f, "price {price} is out of range"),
+            OrderBookError::LevelNotFound { price, side } => {
+                write!(f, "no {side:?} level at price {price}")
+            }
+        }
+    }
 }
 
+impl std::error::Error for OrderBookError {}
+
 /// The main trait that students must implement
 pub trait OrderBook: Send + Sync {
     /// Create a new orderbook instance
@@ -45,6 +119,29 @@ pub trait OrderBook: Send + Sync {
     /// This is the HOT PATH - optimize heavily!
     fn apply_update(&mut self, update: Update);
 
+    /// Return the book to the state `new()` creates
+    fn clear(&mut self)
+    where
+        Self: Sized,
+    {
+        *self = Self::new();
+    }
+
+    /// Remove every level of one side, leaving the other as is
+    fn clear_side(&mut self, side: Side) {
+        for (price, _) in self.get_top_levels(side, usize::MAX) {
+            self.apply_update(Update::Remove { price, side });
+        }
+    }
+
+    /// Apply a packet of updates in order
+    /// The resulting state must match applying them one by one
+    fn apply_updates(&mut self, updates: &[Update]) {
+        for update in updates {
+            self.apply_update(update.clone());
+        }
+    }
+
     /// Get the current spread (best_ask - best_bid)
     /// Returns None if either side is empty
     /// This is also HOT PATH
@@ -66,4 +163,117 @@ pub trait OrderBook: Send + Sync {
 
     /// Get total quantity across all levels for a side
     fn get_total_quantity(&self, side: Side) -> Quantity;
+
+    /// Fill `out` with the top levels of a side, best prices first
+    /// Returns how many entries were written; the rest of `out` is untouched
+    fn get_top_levels_into(&self, side: Side, out: &mut [(Price, Quantity)]) -> usize {
+        let levels = self.get_top_levels(side, out.len());
+        out[..levels.len()].copy_from_slice(&levels);
+        levels.len()
+    }
+
+    /// Iterate over every level of a side, best prices first
+    fn iter_levels(&self, side: Side) -> impl Iterator<Item = (Price, Quantity)> + '_ {
+        self.get_top_levels(side, usize::MAX).into_iter()
+    }
+
+    /// Whether the best bid is above the best ask
+    /// False if either side is empty
+    fn is_crossed(&self) -> bool {
+        matches!((self.get_best_bid(), self.get_best_ask()), (Some(bid), Some(ask)) if bid > ask)
+    }
+
+    /// Whether the best bid equals the best ask
+    /// False if either side is empty
+    fn is_locked(&self) -> bool {
+        matches!((self.get_best_bid(), self.get_best_ask()), (Some(bid), Some(ask)) if bid == ask)
+    }
+
+    /// Get the midpoint between the best bid and best ask, unrounded
+    /// Returns None if either side is empty
+    fn get_mid_price(&self) -> Option<f64> {
+        Some((self.get_best_bid()? + self.get_best_ask()?) as f64 / 2.0)
+    }
+
+    /// Get the touch prices weighted by the opposite side's size:
+    /// (bid * ask_qty + ask * bid_qty) / (bid_qty + ask_qty)
+    /// Returns None if either side is empty; the mid if both sizes are 0
+    fn get_microprice(&self) -> Option<f64> {
+        let bid = self.get_best_bid()?;
+        let ask = self.get_best_ask()?;
+        let bid_qty = self.get_quantity_at(bid, Side::Bid).unwrap_or(0);
+        let ask_qty = self.get_quantity_at(ask, Side::Ask).unwrap_or(0);
+        Some(microprice((bid, bid_qty), (ask, ask_qty)))
+    }
+
+    /// Get (bid_qty - ask_qty) / (bid_qty + ask_qty) over the top `depth`
+    /// levels of each side, in [-1, 1]
+    /// Returns None if either side is empty or both sums are 0
+    fn get_imbalance(&self, depth: usize) -> Option<f64> {
+        let sum = |side| {
+            let levels = self.get_top_levels(side, depth);
+            (!levels.is_empty()).then(|| levels.iter().map(|&(_, qty)| qty).sum())
+        };
+        imbalance(sum(Side::Bid)?, sum(Side::Ask)?)
+    }
+
+    /// Get the quantity resting from the best price through `limit` (inclusive)
+    /// Returns 0 if `limit` is better than the best price or the side is empty
+    fn get_quantity_up_to(&self, limit: Price, side: Side) -> Quantity {
+        self.get_top_levels(side, usize::MAX)
+            .into_iter()
+            .take_while(|&(price, _)| match side {
+                Side::Bid => price >= limit,
+                Side::Ask => price <= limit,
+            })
+            .map(|(_, qty)| qty)
+            .sum()
+    }
+
+    /// Get the cost of taking `qty` from a side best-first
+    /// Returns (worst price reached, notional), pro-rating the last level used,
+    /// or None if the side holds less than `qty` (or is empty)
+    fn get_sweep_cost(&self, qty: Quantity, side: Side) -> Option<(Price, i128)> {
+        sweep_cost(self.get_top_levels(side, usize::MAX).into_iter(), qty)
+    }
+}
+
+/// `OrderBook::get_microprice` of a touch given as (price, quantity) pairs
+pub(crate) fn microprice(
+    (bid, bid_qty): (Price, Quantity),
+    (ask, ask_qty): (Price, Quantity),
+) -> f64 {
+    if bid_qty == 0 && ask_qty == 0 {
+        return (bid + ask) as f64 / 2.0;
+    }
+    let (bq, aq) = (bid_qty as f64, ask_qty as f64);
+    (bid as f64 * aq + ask as f64 * bq) / (bq + aq)
+}
+
+/// `OrderBook::get_imbalance` of summed bid and ask quantities
+pub(crate) fn imbalance(bid_qty: Quantity, ask_qty: Quantity) -> Option<f64> {
+    if bid_qty == 0 && ask_qty == 0 {
+        return None;
+    }
+    let (bid, ask) = (bid_qty as f64, ask_qty as f64);
+    Some((bid - ask) / (bid + ask))
+}
+
+/// Walk best-first `levels` until `qty` is covered, as in
+/// `OrderBook::get_sweep_cost`
+pub(crate) fn sweep_cost(
+    levels: impl Iterator<Item = (Price, Quantity)>,
+    qty: Quantity,
+) -> Option<(Price, i128)> {
+    let mut remaining = qty;
+    let mut notional = 0;
+    for (price, level_qty) in levels {
+        let take = level_qty.min(remaining);
+        notional += price as i128 * take as i128;
+        remaining -= take;
+        if remaining == 0 {
+            return Some((price, notional));
+        }
+    }
+    None
 }
@@ -0,0 +1,8 @@
+pub mod benchmarks;
+pub mod codec;
+pub mod dual;
+pub mod interfaces;
+pub mod naive;
+pub mod orderbook;
+pub mod orderbook_l3;
+pub mod seqlock;
@@ -1,8 +1,8 @@
-use crate::{benchmarks::OrderBookBenchmark, orderbook::OrderBookImpl};
-
-mod benchmarks;
-mod interfaces;
-mod orderbook;
+use rust_3::{
+    benchmarks::{OrderBookBenchmark, Workload},
+    naive::NaiveOrderBook,
+    orderbook::OrderBookImpl,
+};
 
 // Objective: Complete the orderbook implementation at ./orderbook.rs and run this file to see how fast it is. Faster implementation wins !
 
@@ -11,11 +11,34 @@ mod orderbook;
 // ============================================================================
 
 fn main() {
+    // Optional workload name for the latency run; all of them by default
+    let workloads = match std::env::args().nth(1) {
+        Some(name) => match Workload::parse(&name) {
+            Some(workload) => vec![workload],
+            None => {
+                let names: Vec<_> = Workload::ALL.iter().map(|w| w.name()).collect();
+                eprintln!("unknown workload {name:?}, expected one of {names:?}");
+                std::process::exit(2);
+            }
+        },
+        None => Workload::ALL.to_vec(),
+    };
+
     println!("Running Naive OrderBook Benchmark...\n");
 
     let result = OrderBookBenchmark::run::<OrderBookImpl>("OrderBook", 100_000);
     OrderBookBenchmark::print_results(&result);
 
+    // BTreeMap reference book, as a baseline
+    let baseline = OrderBookBenchmark::run::<NaiveOrderBook>("NaiveOrderBook", 100_000);
+    OrderBookBenchmark::print_results(&baseline);
+
+    for workload in workloads {
+        let report =
+            OrderBookBenchmark::run_latency::<OrderBookImpl>("OrderBook", workload, 1_000_000);
+        OrderBookBenchmark::print_latency(&report);
+    }
+
     println!("\n Competition Goal: Achieve sub-nanosecond operations!");
     println!(" Tips:");
     println!("   - Use cache-friendly data structures");
@@ -31,7 +54,7 @@ fn main() {
 
 #[cfg(test)]
 mod tests {
-    use crate::{
+    use rust_3::{
         interfaces::{OrderBook, Side, Update},
         orderbook::OrderBookImpl,
     };
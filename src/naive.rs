@@ -0,0 +1,311 @@
+// ============================================================================
+// REFERENCE BOOK
+// ============================================================================
+// The most obvious `OrderBook` there is: one ordered map per side, every query
+// answered straight from the maps. It is slow on purpose and exists as a
+// model to check `OrderBookImpl` against and as a baseline in the benchmarks.
+
+use std::collections::BTreeMap;
+
+use crate::interfaces::{OrderBook, Price, Quantity, Side, Update};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NaiveOrderBook {
+    bids: BTreeMap<Price, Quantity>,
+    asks: BTreeMap<Price, Quantity>,
+}
+
+impl NaiveOrderBook {
+    fn side(&self, side: Side) -> &BTreeMap<Price, Quantity> {
+        match side {
+            Side::Bid => &self.bids,
+            Side::Ask => &self.asks,
+        }
+    }
+
+    fn set(&mut self, side: Side, price: Price, quantity: Quantity) {
+        let levels = match side {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        };
+        if quantity == 0 {
+            levels.remove(&price);
+        } else {
+            levels.insert(price, quantity);
+        }
+    }
+}
+
+impl OrderBook for NaiveOrderBook {
+    fn new() -> Self {
+        Self::default()
+    }
+
+    fn apply_update(&mut self, update: Update) {
+        match update {
+            Update::Set {
+                price,
+                quantity,
+                side,
+            }
+            | Update::SetWithReserve {
+                price,
+                quantity,
+                side,
+                ..
+            } => self.set(side, price, quantity),
+            Update::Remove { price, side } => self.set(side, price, 0),
+            Update::Change { price, delta, side } => {
+                let current = self.get_quantity_at(price, side).unwrap_or(0);
+                self.set(side, price, current.saturating_add_signed(delta));
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.bids.clear();
+        self.asks.clear();
+    }
+
+    fn clear_side(&mut self, side: Side) {
+        match side {
+            Side::Bid => self.bids.clear(),
+            Side::Ask => self.asks.clear(),
+        }
+    }
+
+    fn get_spread(&self) -> Option<Price> {
+        Some(self.get_best_ask()? - self.get_best_bid()?)
+    }
+
+    fn get_best_bid(&self) -> Option<Price> {
+        self.bids.keys().next_back().copied()
+    }
+
+    fn get_best_ask(&self) -> Option<Price> {
+        self.asks.keys().next().copied()
+    }
+
+    fn get_quantity_at(&self, price: Price, side: Side) -> Option<Quantity> {
+        self.side(side).get(&price).copied()
+    }
+
+    fn get_top_levels(&self, side: Side, n: usize) -> Vec<(Price, Quantity)> {
+        let levels = self.side(side).iter().map(|(&p, &q)| (p, q));
+        match side {
+            Side::Bid => levels.rev().take(n).collect(),
+            Side::Ask => levels.take(n).collect(),
+        }
+    }
+
+    fn get_total_quantity(&self, side: Side) -> Quantity {
+        self.side(side).values().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orderbook::{CAP, OrderBookImpl, splitmix64};
+
+    const STEPS: usize = 2_000;
+    const SEEDS: u64 = 48;
+
+    /// Random updates against a pool of 64 prices, so levels are hit again
+    /// and again, with zero quantities, removals of missing levels, signed
+    /// changes, prices outside the bitmap window and bursts that delete and
+    /// recreate the touch. Driven by `model` so the touch is the real one.
+    fn generate(seed: u64, steps: usize) -> Vec<Update> {
+        let mut rng = seed;
+        let mut model = NaiveOrderBook::new();
+        let mut updates = Vec::with_capacity(steps);
+        while updates.len() < steps {
+            let roll = splitmix64(&mut rng);
+            let side = if roll & 1 == 0 { Side::Bid } else { Side::Ask };
+            let price = 10_000 + ((roll >> 8) % 64) as Price * 10;
+            let quantity = if (roll >> 16).is_multiple_of(8) {
+                0
+            } else {
+                (roll >> 24) % 300
+            };
+            let best = match side {
+                Side::Bid => model.get_best_bid(),
+                Side::Ask => model.get_best_ask(),
+            };
+
+            let burst = match ((roll >> 40) % 10, best) {
+                (0..4, _) => vec![Update::Set {
+                    price,
+                    quantity,
+                    side,
+                }],
+                (4 | 5, _) => vec![Update::Remove { price, side }],
+                (6, _) => vec![Update::Change {
+                    price,
+                    delta: ((roll >> 48) % 301) as i64 - 150,
+                    side,
+                }],
+                (7 | 8, Some(best)) => vec![
+                    Update::Remove { price: best, side },
+                    Update::Set {
+                        price: best,
+                        quantity: quantity.max(1),
+                        side,
+                    },
+                    Update::Set {
+                        price: best,
+                        quantity: 0,
+                        side,
+                    },
+                ],
+                _ => {
+                    let far = CAP as Price * ((roll >> 48) % 3) as Price;
+                    vec![Update::Set {
+                        price: match side {
+                            Side::Bid => price - far,
+                            Side::Ask => price + far,
+                        },
+                        quantity,
+                        side,
+                    }]
+                }
+            };
+            for update in burst {
+                model.apply_update(update.clone());
+                updates.push(update);
+            }
+        }
+        updates
+    }
+
+    /// Every query the differential test compares, as answered by one book
+    #[derive(Debug, PartialEq)]
+    struct Observation {
+        best: (Option<Price>, Option<Price>),
+        spread: Option<Price>,
+        /// At the updated price and one tick either side, bids then asks
+        quantities: Vec<Option<Quantity>>,
+        totals: (Quantity, Quantity),
+        /// Top 1, 5 and all levels, bids then asks
+        top: Vec<Vec<(Price, Quantity)>>,
+    }
+
+    fn observe<T: OrderBook>(ob: &T, price: Price) -> Observation {
+        let sides = [Side::Bid, Side::Ask];
+        Observation {
+            best: (ob.get_best_bid(), ob.get_best_ask()),
+            spread: ob.get_spread(),
+            quantities: sides
+                .iter()
+                .flat_map(|&side| {
+                    [price, price + 10, price - 10].map(|p| ob.get_quantity_at(p, side))
+                })
+                .collect(),
+            totals: (
+                ob.get_total_quantity(Side::Bid),
+                ob.get_total_quantity(Side::Ask),
+            ),
+            top: sides
+                .iter()
+                .flat_map(|&side| [1, 5, usize::MAX].map(|n| ob.get_top_levels(side, n)))
+                .collect(),
+        }
+    }
+
+    /// Replay `updates` on both books, comparing every query after each one.
+    /// Returns the first step at which they disagree and both observations.
+    fn first_divergence(updates: &[Update]) -> Option<(usize, String)> {
+        let mut naive = NaiveOrderBook::new();
+        let mut fast = OrderBookImpl::new();
+        for (step, update) in updates.iter().enumerate() {
+            naive.apply_update(update.clone());
+            fast.apply_update(update.clone());
+
+            let price = match *update {
+                Update::Set { price, .. }
+                | Update::SetWithReserve { price, .. }
+                | Update::Remove { price, .. }
+                | Update::Change { price, .. } => price,
+            };
+            let (expected, actual) = (observe(&naive, price), observe(&fast, price));
+            if expected != actual {
+                return Some((step, format!("naive {expected:?}\nbitmap {actual:?}")));
+            }
+        }
+        None
+    }
+
+    /// Drop updates one at a time for as long as the books still disagree
+    fn shrink(mut updates: Vec<Update>) -> Vec<Update> {
+        let mut i = 0;
+        while i < updates.len() {
+            let mut candidate = updates.clone();
+            candidate.remove(i);
+            if first_divergence(&candidate).is_some() {
+                updates = candidate;
+            } else {
+                i += 1;
+            }
+        }
+        updates
+    }
+
+    #[test]
+    fn test_matches_naive_book_on_random_updates() {
+        for seed in 0..SEEDS {
+            let updates = generate(seed, STEPS);
+            if let Some((step, _)) = first_divergence(&updates) {
+                let minimal = shrink(updates[..=step].to_vec());
+                let (_, reason) = first_divergence(&minimal).unwrap();
+                panic!("seed {seed} diverges at step {step}:\n{reason}\nminimal: {minimal:#?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_generator_covers_edge_cases() {
+        let updates = generate(1, STEPS);
+        let mut book = NaiveOrderBook::new();
+        let (mut zero_sets, mut missing_removes, mut best_removes, mut far) = (0, 0, 0, 0);
+        for update in &updates {
+            match *update {
+                Update::Set { quantity: 0, .. } => zero_sets += 1,
+                Update::Remove { price, side } => {
+                    let best = match side {
+                        Side::Bid => book.get_best_bid(),
+                        Side::Ask => book.get_best_ask(),
+                    };
+                    if book.get_quantity_at(price, side).is_none() {
+                        missing_removes += 1;
+                    } else if best == Some(price) {
+                        best_removes += 1;
+                    }
+                }
+                Update::Set { price, .. } if (price - 10_000).abs() >= CAP as Price => far += 1,
+                _ => {}
+            }
+            book.apply_update(update.clone());
+        }
+        assert!(zero_sets > 50, "{zero_sets}");
+        assert!(missing_removes > 50, "{missing_removes}");
+        assert!(best_removes > 50, "{best_removes}");
+        assert!(far > 20, "{far}");
+    }
+
+    #[test]
+    fn test_naive_change_saturates() {
+        let mut ob = NaiveOrderBook::new();
+        ob.apply_update(Update::Change {
+            price: 100,
+            delta: 30,
+            side: Side::Ask,
+        });
+        ob.apply_update(Update::Change {
+            price: 100,
+            delta: -50,
+            side: Side::Ask,
+        });
+        assert_eq!(ob.get_quantity_at(100, Side::Ask), None);
+        assert_eq!(ob.get_total_quantity(Side::Ask), 0);
+    }
+}
@@ -1,41 +1,6989 @@
 // ============================================================================
-// REFERENCE IMPLEMENTATION (Naive, for correctness comparison)
+// BITMAP ORDERBOOK
 // ============================================================================
+// Price levels live in flat arrays indexed by `(price - base) & MASK`. A three-level
+// bitmap hierarchy (root -> L2 -> L1) tracks which slots are populated so the
+// best price can be recovered with a handful of leading/trailing zero counts.
+//
+//   L1: one bit per slot            (CAP / 64 = 1024 words)
+//   L2: one bit per non-empty L1 word (1024 / 64 = 16 words)
+//   root: one bit per non-empty L2 word (16 bits used)
+//
+// Prices outside the window `[base, base + CAP)` go to a per-side overflow map
+// keyed by the real price, so they never alias onto a slot. An empty book
+// re-anchors the window around the first price it receives.
 
-use crate::interfaces::{OrderBook, Price, Quantity, Side, Update};
+use std::collections::BTreeMap;
+#[cfg(feature = "timestamps")]
+use std::collections::HashMap;
+use std::collections::btree_map;
 
-pub struct OrderBookImpl {}
+use crate::codec::{
+    DecodeError, PACKED_RECORD_LEN, PackedError, crc32_extend, get_varint, put_varint, unzigzag,
+    zigzag,
+};
+use crate::interfaces::{
+    BookSnapshot, OrderBook, OrderBookError, Price, PriceLevel, Quantity, Side, Update, imbalance,
+    microprice, sweep_cost,
+};
+
+/// Number of price slots per side
+pub const CAP: usize = 1 << 16;
+
+/// Mask mapping a price to its slot
+pub const MASK: usize = CAP - 1;
+
+/// Default bound on slots visited by a single traversal
+pub const DEFAULT_TRAVERSAL_LIMIT: usize = CAP;
+
+const L1_WORDS: usize = CAP / 64;
+const L2_WORDS: usize = L1_WORDS / 64;
+
+pub struct OrderBookImpl {
+    bid_qty: Box<[Quantity; CAP]>,
+    ask_qty: Box<[Quantity; CAP]>,
+    bid_prices: Box<[Price; CAP]>,
+    ask_prices: Box<[Price; CAP]>,
+    /// Hidden quantity behind the displayed one, 0 for slots without a level
+    bid_reserve: Box<[Quantity; CAP]>,
+    ask_reserve: Box<[Quantity; CAP]>,
+
+    l1_bid: Box<[u64; L1_WORDS]>,
+    l1_ask: Box<[u64; L1_WORDS]>,
+    l2_bid: [u64; L2_WORDS],
+    l2_ask: [u64; L2_WORDS],
+    root_bid: u64,
+    root_ask: u64,
+
+    /// Levels outside the window, keyed by price
+    bid_overflow: BTreeMap<Price, Quantity>,
+    ask_overflow: BTreeMap<Price, Quantity>,
+    /// Non-zero reserves of overflow levels
+    bid_overflow_reserve: BTreeMap<Price, Quantity>,
+    ask_overflow_reserve: BTreeMap<Price, Quantity>,
+
+    best_bid: Option<Price>,
+    best_ask: Option<Price>,
+    total_bid_qty: Quantity,
+    total_ask_qty: Quantity,
+    /// `bbo_checksum_of` the current touch, refreshed whenever it changes
+    bbo_checksum: u64,
+
+    traversal_limit: usize,
+
+    /// Lowest price of the representable window `[base, base + CAP)`
+    base: Price,
+    /// Set while levels are laid out in a window the caller chose, so an
+    /// empty book does not re-centre on the first of them
+    window_pinned: bool,
+
+    tick_size: Option<Price>,
+
+    cross_policy: CrossPolicy,
+    /// Updates rejected or resolved under `cross_policy`
+    cross_interventions: u64,
+
+    last_sequence: Option<u64>,
+    desynced: bool,
+    /// Updates applied through `apply_provisional` since the last snapshot
+    provisional_updates: u64,
+
+    #[cfg(feature = "stats")]
+    crossed_event_count: u64,
+    #[cfg(feature = "stats")]
+    session_high_bid: Option<Price>,
+    #[cfg(feature = "stats")]
+    session_low_ask: Option<Price>,
+    #[cfg(feature = "stats")]
+    last_bbo_change_ts: u64,
+    #[cfg(feature = "stats")]
+    max_best_recompute_steps: u32,
+
+    /// Caller-supplied time, in whatever unit the feed uses
+    clock: u64,
+
+    top_cache: Option<Box<TopNCache>>,
+
+    /// Capture in progress between `start_recording` and `stop_recording`
+    recording: Option<Box<Scenario>>,
+
+    touch_hook: Option<TouchSizeHook>,
+
+    #[cfg(feature = "mid-history")]
+    mid_history: Box<MidHistory>,
+
+    /// Recent update times per live level, dropped with the level
+    #[cfg(feature = "timestamps")]
+    bid_update_times: HashMap<Price, SlotHistory<()>>,
+    #[cfg(feature = "timestamps")]
+    ask_update_times: HashMap<Price, SlotHistory<()>>,
+    /// Recent (clock, quantity) trades per live level, dropped with the level
+    #[cfg(feature = "timestamps")]
+    bid_trades: HashMap<Price, SlotHistory<Quantity>>,
+    #[cfg(feature = "timestamps")]
+    ask_trades: HashMap<Price, SlotHistory<Quantity>>,
+}
+
+/// Number of mids retained by the `mid-history` ring buffer
+#[cfg(feature = "mid-history")]
+pub const MID_HISTORY_LEN: usize = 256;
+
+/// Fixed-capacity ring of the (clock, mid) pairs seen on recent BBO changes
+#[cfg(feature = "mid-history")]
+struct MidHistory {
+    mids: [(u64, f64); MID_HISTORY_LEN],
+    head: usize,
+    len: usize,
+}
+
+#[cfg(feature = "mid-history")]
+impl MidHistory {
+    fn push(&mut self, ts: u64, mid: f64) {
+        self.mids[self.head] = (ts, mid);
+        self.head = (self.head + 1) % MID_HISTORY_LEN;
+        self.len = (self.len + 1).min(MID_HISTORY_LEN);
+    }
+
+    /// Oldest first
+    fn iter(&self) -> impl Iterator<Item = (u64, f64)> + '_ {
+        let start = (self.head + MID_HISTORY_LEN - self.len) % MID_HISTORY_LEN;
+        (0..self.len).map(move |i| self.mids[(start + i) % MID_HISTORY_LEN])
+    }
+}
+
+/// Number of update times (and trades) retained per slot by the
+/// `timestamps` feature. Rates measured over a window saturate at this many
+/// updates.
+#[cfg(feature = "timestamps")]
+pub const UPDATE_TIMES_LEN: usize = 16;
+
+/// Fixed-capacity ring of recent timestamped events at one price
+#[cfg(feature = "timestamps")]
+#[derive(Default)]
+struct SlotHistory<T> {
+    entries: [(u64, T); UPDATE_TIMES_LEN],
+    head: usize,
+    len: usize,
+}
+
+#[cfg(feature = "timestamps")]
+impl<T: Copy> SlotHistory<T> {
+    fn push(&mut self, ts: u64, value: T) {
+        self.entries[self.head] = (ts, value);
+        self.head = (self.head + 1) % UPDATE_TIMES_LEN;
+        self.len = (self.len + 1).min(UPDATE_TIMES_LEN);
+    }
+
+    /// Newest first
+    fn iter(&self) -> impl Iterator<Item = (u64, T)> + '_ {
+        (1..=self.len)
+            .map(move |i| self.entries[(self.head + UPDATE_TIMES_LEN - i) % UPDATE_TIMES_LEN])
+    }
+}
+
+/// A level as it was before a write: side, price, quantity and reserve
+type PreImage = (Side, Price, Quantity, Quantity);
+
+/// Saved book state for `OrderBookImpl::restore`. Holds only the populated
+/// levels, so it stays small for sparse books.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    bids: Vec<(Price, Quantity)>,
+    asks: Vec<(Price, Quantity)>,
+    last_sequence: Option<u64>,
+    desynced: bool,
+    provisional_updates: u64,
+}
+
+/// Fixed-layout, allocation-free top-of-book image for shared-memory
+/// publishing. Only the first `*_count` entries of each array are valid.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RingSnapshot<const N: usize> {
+    pub bids: [PriceLevel; N],
+    pub asks: [PriceLevel; N],
+    pub bid_count: u8,
+    pub ask_count: u8,
+}
+
+/// Full book image published by an exchange, used to recover from gaps
+#[derive(Debug, Clone, Default)]
+pub struct ExchangeSnapshot {
+    pub seq: u64,
+    pub bids: Vec<(Price, Quantity)>,
+    pub asks: Vec<(Price, Quantity)>,
+}
+
+/// Leading bytes of an encoded `Snapshot`
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"OBSN";
+
+/// Version of the `Snapshot` encoding written by `to_bytes`
+pub const SNAPSHOT_VERSION: u8 = 1;
+
+/// Persistable image of a book: its window base and the populated levels
+/// of each side, best first
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub base: Price,
+    pub bids: Vec<(Price, Quantity)>,
+    pub asks: Vec<(Price, Quantity)>,
+}
+
+impl Snapshot {
+    /// `SNAPSHOT_MAGIC`, a version byte, the zigzag varint base, then per
+    /// side (bids first) a varint level count and for each level the zigzag
+    /// varint delta from the previous price (the base for the first) and a
+    /// varint quantity
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(16 + 4 * (self.bids.len() + self.asks.len()));
+        buf.extend_from_slice(&SNAPSHOT_MAGIC);
+        buf.push(SNAPSHOT_VERSION);
+        put_varint(&mut buf, zigzag(self.base));
+        for levels in [&self.bids, &self.asks] {
+            put_varint(&mut buf, levels.len() as u64);
+            let mut last = self.base;
+            for &(price, quantity) in levels {
+                put_varint(&mut buf, zigzag(price.wrapping_sub(last)));
+                put_varint(&mut buf, quantity);
+                last = price;
+            }
+        }
+        buf
+    }
+
+    /// Parse bytes written by `to_bytes`, rejecting levels that are not
+    /// strictly best-first
+    pub fn from_bytes(buf: &[u8]) -> Result<Snapshot, DecodeError> {
+        if buf.len() < SNAPSHOT_MAGIC.len() + 1 {
+            return Err(DecodeError::Truncated);
+        }
+        if buf[..SNAPSHOT_MAGIC.len()] != SNAPSHOT_MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+        let version = buf[SNAPSHOT_MAGIC.len()];
+        if version != SNAPSHOT_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let mut pos = SNAPSHOT_MAGIC.len() + 1;
+        let base = unzigzag(get_varint(buf, &mut pos)?);
+        let mut sides = [Vec::new(), Vec::new()];
+        for (levels, side) in sides.iter_mut().zip([Side::Bid, Side::Ask]) {
+            let len = get_varint(buf, &mut pos)?;
+            let mut last = base;
+            for i in 0..len {
+                let price = last.wrapping_add(unzigzag(get_varint(buf, &mut pos)?));
+                let ordered = i == 0
+                    || match side {
+                        Side::Bid => price < last,
+                        Side::Ask => price > last,
+                    };
+                if !ordered {
+                    return Err(DecodeError::UnorderedLevel { price });
+                }
+                levels.push((price, get_varint(buf, &mut pos)?));
+                last = price;
+            }
+        }
+        let [bids, asks] = sides;
+        Ok(Snapshot { base, bids, asks })
+    }
+}
+
+/// Level writes between checksum checkpoints in a recorded `Scenario`
+pub const SCENARIO_CHECKPOINT_INTERVAL: usize = 64;
+
+/// Reproducible capture of a book's activity: the state at the start, every
+/// level write since, and full-book `checksum`s taken along the way.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Scenario {
+    /// Sets rebuilding the book as it was when recording started
+    pub initial: Vec<Update>,
+    pub updates: Vec<Update>,
+    /// (number of `updates` applied, expected checksum) in ascending order
+    pub checkpoints: Vec<(usize, u32)>,
+}
+
+/// First checkpoint at which a replayed `Scenario` diverged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScenarioMismatch {
+    /// Number of updates applied when the checksums were compared
+    pub applied: usize,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl std::fmt::Display for ScenarioMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "checksum {:#010x} after {} updates, expected {:#010x}",
+            self.actual, self.applied, self.expected
+        )
+    }
+}
+
+impl std::error::Error for ScenarioMismatch {}
+
+impl Scenario {
+    /// Serialise onto `buf`: varint-prefixed lists of initial updates,
+    /// updates (both in the `encode_diff` record format) and checkpoints
+    /// (varint count, varint checksum)
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        for list in [&self.initial, &self.updates] {
+            put_varint(buf, list.len() as u64);
+            let mut last = 0;
+            for update in list {
+                put_update(buf, &mut last, update);
+            }
+        }
+        put_varint(buf, self.checkpoints.len() as u64);
+        for &(applied, crc) in &self.checkpoints {
+            put_varint(buf, applied as u64);
+            put_varint(buf, crc as u64);
+        }
+    }
+
+    /// Parse a buffer written by `encode`
+    pub fn decode(buf: &[u8]) -> Result<Scenario, DecodeError> {
+        let mut pos = 0;
+        let mut lists = [Vec::new(), Vec::new()];
+        for list in &mut lists {
+            let len = get_varint(buf, &mut pos)?;
+            let mut last = 0;
+            for _ in 0..len {
+                list.push(get_update(buf, &mut pos, &mut last)?);
+            }
+        }
+        let [initial, updates] = lists;
+        let len = get_varint(buf, &mut pos)?;
+        let mut checkpoints = Vec::new();
+        for _ in 0..len {
+            let applied = get_varint(buf, &mut pos)? as usize;
+            let crc =
+                u32::try_from(get_varint(buf, &mut pos)?).map_err(|_| DecodeError::Overflow)?;
+            checkpoints.push((applied, crc));
+        }
+        Ok(Scenario {
+            initial,
+            updates,
+            checkpoints,
+        })
+    }
+}
+
+/// What `apply_update` does with an update that would lock or cross the book
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CrossPolicy {
+    /// Apply it as is
+    #[default]
+    Allow,
+    /// Drop it
+    Reject,
+    /// Remove the opposite levels it locks or crosses, then apply it
+    Resolve,
+}
+
+/// How the top of book moved between two consecutive states
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BboEvent {
+    NoChange,
+    BidImproved,
+    BidWorsened,
+    AskImproved,
+    AskWorsened,
+    /// Both sides moved away from each other
+    Widened,
+    /// Both sides moved towards each other
+    Tightened,
+    /// Bid improved while the ask worsened (the whole quote moved up)
+    ShiftedUp,
+    /// Bid worsened while the ask improved (the whole quote moved down)
+    ShiftedDown,
+}
+
+/// Best prices before and after a batch of updates, from
+/// `OrderBookImpl::apply_batch_reporting`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BboDelta {
+    pub bid_before: Option<Price>,
+    pub ask_before: Option<Price>,
+    pub bid_after: Option<Price>,
+    pub ask_after: Option<Price>,
+}
+
+impl BboDelta {
+    /// Whether either best price ended up somewhere else
+    pub fn changed(&self) -> bool {
+        self.bid_before != self.bid_after || self.ask_before != self.ask_after
+    }
+
+    /// The net move classified as in `OrderBookImpl::classify_bbo_transition`
+    pub fn event(&self) -> BboEvent {
+        classify_bests(
+            (self.bid_before, self.ask_before),
+            (self.bid_after, self.ask_after),
+        )
+    }
+}
+
+/// Outcome of one `OrderBookImpl::self_check` consistency check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub passed: bool,
+    /// One line per side that failed, empty when the check passed
+    pub details: Vec<String>,
+}
+
+/// Every consistency check run by `OrderBookImpl::self_check`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfCheckReport {
+    pub checks: Vec<CheckResult>,
+}
+
+impl SelfCheckReport {
+    pub fn is_healthy(&self) -> bool {
+        self.checks.iter().all(|c| c.passed)
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &CheckResult> {
+        self.checks.iter().filter(|c| !c.passed)
+    }
+}
+
+/// Decides how much of an `execute` fills at each level it reaches
+pub trait FillModel {
+    /// Quantity filled at `price`, where `available` rests and `requested`
+    /// is still unfilled. Results above either are capped.
+    fn consume(&mut self, price: Price, available: Quantity, requested: Quantity) -> Quantity;
+}
+
+/// Fills everything it is offered, sweeping deterministically from the top
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FullFillModel;
+
+impl FillModel for FullFillModel {
+    fn consume(&mut self, _price: Price, available: Quantity, requested: Quantity) -> Quantity {
+        available.min(requested)
+    }
+}
+
+/// Callback for `OrderBookImpl::on_touch_size_change`, given the side and
+/// the best level's price and new quantity
+pub type TouchSizeHook = Box<dyn FnMut(Side, Price, Quantity) + Send + Sync>;
+
+/// Incrementally maintained copy of the top `n` levels of each side.
+/// While a side holds fewer than `n` levels the cache holds all of them, so
+/// only removals from a full cache need a bitmap lookup to refill.
+struct TopNCache {
+    n: usize,
+    bids: Vec<(Price, Quantity)>,
+    asks: Vec<(Price, Quantity)>,
+}
+
+impl TopNCache {
+    fn side_mut(&mut self, side: Side) -> &mut Vec<(Price, Quantity)> {
+        match side {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        }
+    }
+
+    /// Mirror a level change already applied to `book`
+    fn apply(&mut self, book: &OrderBookImpl, side: Side, price: Price, quantity: Quantity) {
+        let n = self.n;
+        let better = |a: Price, b: Price| match side {
+            Side::Bid => a > b,
+            Side::Ask => a < b,
+        };
+        let levels = self.side_mut(side);
+        let pos = levels.partition_point(|&(p, _)| better(p, price));
+        let present = levels.get(pos).is_some_and(|&(p, _)| p == price);
+
+        if quantity != 0 {
+            if present {
+                levels[pos].1 = quantity;
+            } else if pos < n {
+                levels.insert(pos, (price, quantity));
+                levels.truncate(n);
+            }
+            return;
+        }
+
+        if !present {
+            return;
+        }
+        let was_full = levels.len() == n;
+        levels.remove(pos);
+        if was_full && let Some(level) = book.levels(side).nth(n - 1) {
+            levels.push(level);
+        }
+    }
+}
+
+/// Best-first walk over the populated levels of one side: overflow levels
+/// better than the window, then the window's slots, then overflow levels
+/// worse than it
+struct Levels<'a> {
+    book: &'a OrderBookImpl,
+    side: Side,
+    better: btree_map::Range<'a, Price, Quantity>,
+    next: Option<usize>,
+    steps: usize,
+    worse: btree_map::Range<'a, Price, Quantity>,
+}
+
+impl Iterator for Levels<'_> {
+    type Item = (Price, Quantity);
+
+    #[inline(always)]
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(level) = pop_best(self.side, &mut self.better) {
+            return Some(level);
+        }
+        let Some(slot) = self.next else {
+            return pop_best(self.side, &mut self.worse);
+        };
+        if !self.book.guard_step(&mut self.steps) {
+            self.next = None;
+            return None;
+        }
+        self.next = self.book.next_slot(self.side, slot);
+        let level = self.book.level_at(self.side, slot);
+        debug_assert!(level.1 != 0, "slot {slot} is marked active but empty");
+        Some(level)
+    }
+}
+
+/// Take the best remaining level of `side` from an overflow range
+#[inline(always)]
+fn pop_best(
+    side: Side,
+    range: &mut btree_map::Range<'_, Price, Quantity>,
+) -> Option<(Price, Quantity)> {
+    let level = match side {
+        Side::Bid => range.next_back(),
+        Side::Ask => range.next(),
+    };
+    level.map(|(&price, &qty)| (price, qty))
+}
+
+/// FxHash multiplier (as used by rustc)
+const FX_SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+#[inline(always)]
+const fn fx_add(hash: u64, word: u64) -> u64 {
+    (hash.rotate_left(5) ^ word).wrapping_mul(FX_SEED)
+}
+
+/// Checksum of a top of book; an empty side hashes as price `u64::MAX`, size 0
+const fn bbo_checksum_of(bid: Option<(Price, Quantity)>, ask: Option<(Price, Quantity)>) -> u64 {
+    let mut hash = 0;
+    let mut i = 0;
+    while i < 2 {
+        let (price, qty) = match if i == 0 { bid } else { ask } {
+            Some((price, qty)) => (price as u64, qty),
+            None => (u64::MAX, 0),
+        };
+        hash = fx_add(fx_add(hash, price), qty);
+        i += 1;
+    }
+    hash
+}
+
+/// SplitMix64 step: advances `state` and returns the next pseudo-random word
+#[inline(always)]
+pub(crate) fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Greatest common divisor, with `gcd(a, 0) == a`
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// Append one update record: an op byte (bit 0: set/remove, bit 1: ask side,
+/// bit 2: change, bit 3: set with reserve), the zigzag varint delta from
+/// `*last` (the previous record's price), then for sets a varint quantity
+/// (followed by a varint reserve if bit 3 is set) and for changes a zigzag
+/// varint delta
+fn put_update(buf: &mut Vec<u8>, last: &mut Price, update: &Update) {
+    let (op, price, side) = match *update {
+        Update::Set { price, side, .. } => (OP_SET, price, side),
+        Update::SetWithReserve { price, side, .. } => (OP_SET | OP_RESERVE, price, side),
+        Update::Remove { price, side } => (OP_REMOVE, price, side),
+        Update::Change { price, side, .. } => (OP_CHANGE, price, side),
+    };
+    buf.push(op | if side == Side::Ask { SIDE_ASK_BIT } else { 0 });
+    put_varint(buf, zigzag(price.wrapping_sub(*last)));
+    match *update {
+        Update::Set { quantity, .. } => put_varint(buf, quantity),
+        Update::SetWithReserve {
+            quantity, reserve, ..
+        } => {
+            put_varint(buf, quantity);
+            put_varint(buf, reserve);
+        }
+        Update::Remove { .. } => {}
+        Update::Change { delta, .. } => put_varint(buf, zigzag(delta)),
+    }
+    *last = price;
+}
+
+/// Read one record written by `put_update`
+fn get_update(buf: &[u8], pos: &mut usize, last: &mut Price) -> Result<Update, DecodeError> {
+    let op = *buf.get(*pos).ok_or(DecodeError::Truncated)?;
+    *pos += 1;
+    let kind = op & !SIDE_ASK_BIT;
+    if !matches!(kind, OP_SET | OP_REMOVE | OP_CHANGE) && kind != OP_SET | OP_RESERVE {
+        return Err(DecodeError::InvalidOp(op));
+    }
+    let side = if op & SIDE_ASK_BIT != 0 {
+        Side::Ask
+    } else {
+        Side::Bid
+    };
+    let price = last.wrapping_add(unzigzag(get_varint(buf, pos)?));
+    *last = price;
+    Ok(match kind {
+        OP_CHANGE => Update::Change {
+            price,
+            delta: unzigzag(get_varint(buf, pos)?),
+            side,
+        },
+        OP_REMOVE => Update::Remove { price, side },
+        OP_SET => Update::Set {
+            price,
+            quantity: get_varint(buf, pos)?,
+            side,
+        },
+        _ => Update::SetWithReserve {
+            price,
+            quantity: get_varint(buf, pos)?,
+            reserve: get_varint(buf, pos)?,
+            side,
+        },
+    })
+}
+
+/// Append the updates turning `old` into `new`, both best-first on `side`
+fn diff_side(
+    side: Side,
+    old: impl Iterator<Item = (Price, Quantity)>,
+    new: impl Iterator<Item = (Price, Quantity)>,
+    out: &mut Vec<Update>,
+) {
+    let better = |a: Price, b: Price| match side {
+        Side::Bid => a > b,
+        Side::Ask => a < b,
+    };
+    let mut old = old.peekable();
+    let mut new = new.peekable();
+    loop {
+        match (old.peek().copied(), new.peek().copied()) {
+            (None, None) => break,
+            (Some((p, _)), n) if n.is_none_or(|(np, _)| better(p, np)) => {
+                out.push(Update::Remove { price: p, side });
+                old.next();
+            }
+            (o, Some((price, quantity))) => {
+                if o != Some((price, quantity)) {
+                    out.push(Update::Set {
+                        price,
+                        quantity,
+                        side,
+                    });
+                }
+                if o.is_some_and(|(p, _)| p == price) {
+                    old.next();
+                }
+                new.next();
+            }
+            (Some(_), None) => unreachable!(),
+        }
+    }
+}
+
+const OP_SET: u8 = 0;
+const OP_REMOVE: u8 = 1;
+const SIDE_ASK_BIT: u8 = 0b10;
+const OP_CHANGE: u8 = 0b100;
+const OP_RESERVE: u8 = 0b1000;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Move {
+    Same,
+    Improved,
+    Worsened,
+}
+
+/// `OrderBookImpl::classify_bbo_transition` on (bid, ask) pairs
+fn classify_bests(
+    (prev_bid, prev_ask): (Option<Price>, Option<Price>),
+    (curr_bid, curr_ask): (Option<Price>, Option<Price>),
+) -> BboEvent {
+    let bid = match (prev_bid, curr_bid) {
+        (Some(a), Some(b)) if b > a => Move::Improved,
+        (Some(a), Some(b)) if b < a => Move::Worsened,
+        (None, Some(_)) => Move::Improved,
+        (Some(_), None) => Move::Worsened,
+        _ => Move::Same,
+    };
+    let ask = match (prev_ask, curr_ask) {
+        (Some(a), Some(b)) if b < a => Move::Improved,
+        (Some(a), Some(b)) if b > a => Move::Worsened,
+        (None, Some(_)) => Move::Improved,
+        (Some(_), None) => Move::Worsened,
+        _ => Move::Same,
+    };
+
+    match (bid, ask) {
+        (Move::Same, Move::Same) => BboEvent::NoChange,
+        (Move::Improved, Move::Same) => BboEvent::BidImproved,
+        (Move::Worsened, Move::Same) => BboEvent::BidWorsened,
+        (Move::Same, Move::Improved) => BboEvent::AskImproved,
+        (Move::Same, Move::Worsened) => BboEvent::AskWorsened,
+        (Move::Improved, Move::Improved) => BboEvent::Tightened,
+        (Move::Worsened, Move::Worsened) => BboEvent::Widened,
+        (Move::Improved, Move::Worsened) => BboEvent::ShiftedUp,
+        (Move::Worsened, Move::Improved) => BboEvent::ShiftedDown,
+    }
+}
+
+fn boxed_array<T: Copy, const N: usize>(value: T) -> Box<[T; N]> {
+    vec![value; N].into_boxed_slice().try_into().ok().unwrap()
+}
+
+// ============================================================================
+// BITMAP PRIMITIVES
+// ============================================================================
+
+#[inline(always)]
+fn set_bit(root: &mut u64, l2: &mut [u64; L2_WORDS], l1: &mut [u64; L1_WORDS], slot: usize) {
+    let w1 = slot >> 6;
+    let w2 = slot >> 12;
+    l1[w1] |= 1 << (slot & 63);
+    l2[w2] |= 1 << (w1 & 63);
+    *root |= 1 << w2;
+}
+
+#[inline(always)]
+fn clear_bit(root: &mut u64, l2: &mut [u64; L2_WORDS], l1: &mut [u64; L1_WORDS], slot: usize) {
+    let w1 = slot >> 6;
+    let w2 = slot >> 12;
+    l1[w1] &= !(1 << (slot & 63));
+    if l1[w1] == 0 {
+        l2[w2] &= !(1 << (w1 & 63));
+        if l2[w2] == 0 {
+            *root &= !(1 << w2);
+        }
+    }
+}
+
+/// Highest populated slot
+#[inline(always)]
+fn highest_slot(root: u64, l2: &[u64; L2_WORDS], l1: &[u64; L1_WORDS]) -> Option<usize> {
+    if root == 0 {
+        return None;
+    }
+    let w2 = 63 - root.leading_zeros() as usize;
+    let w1 = (w2 << 6) | (63 - l2[w2].leading_zeros() as usize);
+    Some((w1 << 6) | (63 - l1[w1].leading_zeros() as usize))
+}
+
+/// Lowest populated slot
+#[inline(always)]
+fn lowest_slot(root: u64, l2: &[u64; L2_WORDS], l1: &[u64; L1_WORDS]) -> Option<usize> {
+    if root == 0 {
+        return None;
+    }
+    let w2 = root.trailing_zeros() as usize;
+    let w1 = (w2 << 6) | l2[w2].trailing_zeros() as usize;
+    Some((w1 << 6) | l1[w1].trailing_zeros() as usize)
+}
+
+/// Highest populated slot strictly below `slot`
+fn next_slot_below(
+    root: u64,
+    l2: &[u64; L2_WORDS],
+    l1: &[u64; L1_WORDS],
+    slot: usize,
+) -> Option<usize> {
+    let w1 = slot >> 6;
+    let below = l1[w1] & ((1u64 << (slot & 63)) - 1);
+    if below != 0 {
+        return Some((w1 << 6) | (63 - below.leading_zeros() as usize));
+    }
+
+    let w2 = slot >> 12;
+    let below = l2[w2] & ((1u64 << (w1 & 63)) - 1);
+    if below != 0 {
+        let w1 = (w2 << 6) | (63 - below.leading_zeros() as usize);
+        return Some((w1 << 6) | (63 - l1[w1].leading_zeros() as usize));
+    }
+
+    let below = root & ((1u64 << w2) - 1);
+    if below != 0 {
+        let w2 = 63 - below.leading_zeros() as usize;
+        let w1 = (w2 << 6) | (63 - l2[w2].leading_zeros() as usize);
+        return Some((w1 << 6) | (63 - l1[w1].leading_zeros() as usize));
+    }
+
+    None
+}
+
+/// Lowest populated slot strictly above `slot`
+fn next_slot_above(
+    root: u64,
+    l2: &[u64; L2_WORDS],
+    l1: &[u64; L1_WORDS],
+    slot: usize,
+) -> Option<usize> {
+    let w1 = slot >> 6;
+    let above = l1[w1] & (!1u64 << (slot & 63));
+    if above != 0 {
+        return Some((w1 << 6) | above.trailing_zeros() as usize);
+    }
+
+    let w2 = slot >> 12;
+    let above = l2[w2] & (!1u64 << (w1 & 63));
+    if above != 0 {
+        let w1 = (w2 << 6) | above.trailing_zeros() as usize;
+        return Some((w1 << 6) | l1[w1].trailing_zeros() as usize);
+    }
+
+    let above = root & (!1u64 << w2);
+    if above != 0 {
+        let w2 = above.trailing_zeros() as usize;
+        let w1 = (w2 << 6) | l2[w2].trailing_zeros() as usize;
+        return Some((w1 << 6) | l1[w1].trailing_zeros() as usize);
+    }
+
+    None
+}
+
+impl OrderBookImpl {
+    // =========================================================================
+    // HOT PATH
+    // =========================================================================
+
+    /// Slot for `price`. Prices outside the window alias onto it.
+    #[inline(always)]
+    fn slot(&self, price: Price) -> usize {
+        price.wrapping_sub(self.base) as usize & MASK
+    }
+
+    /// Whether `price` lies in the representable window
+    #[inline(always)]
+    fn in_window(&self, price: Price) -> bool {
+        price
+            .checked_sub(self.base)
+            .is_some_and(|offset| (0..CAP as Price).contains(&offset))
+    }
+
+    #[inline(always)]
+    fn update_bid(&mut self, price: Price, quantity: Quantity) {
+        let prev_best = self.best_bid;
+        let prev_touch = self
+            .touch_hook
+            .is_some()
+            .then(|| self.best_level(Side::Bid));
+        self.write_bid::<false>(price, quantity);
+        if self.best_bid != prev_best || self.best_bid == Some(price) {
+            self.refresh_bbo_checksum();
+        }
+        #[cfg(any(feature = "stats", feature = "mid-history"))]
+        if self.best_bid != prev_best {
+            self.on_bbo_change();
+        }
+        if self.top_cache.is_some() {
+            self.sync_top_cache(Side::Bid, price, quantity);
+        }
+        if let Some(prev_touch) = prev_touch {
+            self.notify_touch(Side::Bid, prev_touch);
+        }
+        #[cfg(feature = "timestamps")]
+        self.record_update_time(Side::Bid, price, quantity);
+        if self.recording.is_some() {
+            self.record_write(Side::Bid, price, quantity);
+        }
+        #[cfg(feature = "stats")]
+        if self.best_bid > self.session_high_bid {
+            self.session_high_bid = self.best_bid;
+        }
+    }
+
+    /// Write one bid level. With `DEFER`, removing the best leaves the cached
+    /// best stale instead of recomputing it and returns true; the caller must
+    /// call `find_new_best_bid` before anything reads it.
+    #[inline(always)]
+    fn write_bid<const DEFER: bool>(&mut self, price: Price, quantity: Quantity) -> bool {
+        if !self.in_window(price) && !self.anchor_window(price, quantity) {
+            self.write_overflow(Side::Bid, price, quantity);
+            return false;
+        }
+        let slot = self.slot(price);
+        let old_qty = self.bid_qty[slot];
+
+        if quantity == 0 {
+            if old_qty == 0 {
+                return false;
+            }
+            self.bid_qty[slot] = 0;
+            self.bid_reserve[slot] = 0;
+            self.total_bid_qty -= old_qty;
+            clear_bit(&mut self.root_bid, &mut self.l2_bid, &mut self.l1_bid, slot);
+            if self.best_bid == Some(price) {
+                if DEFER {
+                    return true;
+                }
+                // The next best usually shares the vacated slot's L1 word
+                let word = self.l1_bid[slot >> 6];
+                if word != 0 {
+                    self.best_bid =
+                        Some(self.bid_prices[(slot & !63) | (63 - word.leading_zeros() as usize)]);
+                } else {
+                    self.find_new_best_bid();
+                }
+                #[cfg(feature = "stats")]
+                self.record_best_recompute(slot, self.best_bid);
+            }
+            return false;
+        }
+
+        self.bid_qty[slot] = quantity;
+        self.bid_prices[slot] = price;
+        self.total_bid_qty = self.total_bid_qty + quantity - old_qty;
+        if old_qty == 0 {
+            set_bit(&mut self.root_bid, &mut self.l2_bid, &mut self.l1_bid, slot);
+        }
+        if self.best_bid.is_none_or(|best| price > best) {
+            self.best_bid = Some(price);
+        }
+        false
+    }
+
+    #[inline(always)]
+    fn update_ask(&mut self, price: Price, quantity: Quantity) {
+        let prev_best = self.best_ask;
+        let prev_touch = self
+            .touch_hook
+            .is_some()
+            .then(|| self.best_level(Side::Ask));
+        self.write_ask::<false>(price, quantity);
+        if self.best_ask != prev_best || self.best_ask == Some(price) {
+            self.refresh_bbo_checksum();
+        }
+        #[cfg(any(feature = "stats", feature = "mid-history"))]
+        if self.best_ask != prev_best {
+            self.on_bbo_change();
+        }
+        if self.top_cache.is_some() {
+            self.sync_top_cache(Side::Ask, price, quantity);
+        }
+        if let Some(prev_touch) = prev_touch {
+            self.notify_touch(Side::Ask, prev_touch);
+        }
+        #[cfg(feature = "timestamps")]
+        self.record_update_time(Side::Ask, price, quantity);
+        if self.recording.is_some() {
+            self.record_write(Side::Ask, price, quantity);
+        }
+        #[cfg(feature = "stats")]
+        if let Some(ask) = self.best_ask
+            && self.session_low_ask.is_none_or(|low| ask < low)
+        {
+            self.session_low_ask = Some(ask);
+        }
+    }
+
+    /// Ask-side `write_bid`
+    #[inline(always)]
+    fn write_ask<const DEFER: bool>(&mut self, price: Price, quantity: Quantity) -> bool {
+        if !self.in_window(price) && !self.anchor_window(price, quantity) {
+            self.write_overflow(Side::Ask, price, quantity);
+            return false;
+        }
+        let slot = self.slot(price);
+        let old_qty = self.ask_qty[slot];
+
+        if quantity == 0 {
+            if old_qty == 0 {
+                return false;
+            }
+            self.ask_qty[slot] = 0;
+            self.ask_reserve[slot] = 0;
+            self.total_ask_qty -= old_qty;
+            clear_bit(&mut self.root_ask, &mut self.l2_ask, &mut self.l1_ask, slot);
+            if self.best_ask == Some(price) {
+                if DEFER {
+                    return true;
+                }
+                // The next best usually shares the vacated slot's L1 word
+                let word = self.l1_ask[slot >> 6];
+                if word != 0 {
+                    self.best_ask =
+                        Some(self.ask_prices[(slot & !63) | (word.trailing_zeros() as usize)]);
+                } else {
+                    self.find_new_best_ask();
+                }
+                #[cfg(feature = "stats")]
+                self.record_best_recompute(slot, self.best_ask);
+            }
+            return false;
+        }
+
+        self.ask_qty[slot] = quantity;
+        self.ask_prices[slot] = price;
+        self.total_ask_qty = self.total_ask_qty + quantity - old_qty;
+        if old_qty == 0 {
+            set_bit(&mut self.root_ask, &mut self.l2_ask, &mut self.l1_ask, slot);
+        }
+        if self.best_ask.is_none_or(|best| price < best) {
+            self.best_ask = Some(price);
+        }
+        false
+    }
+
+    /// Centre the window on `price` if the book is empty and `quantity` would
+    /// add a level. Returns whether the window moved.
+    #[cold]
+    fn anchor_window(&mut self, price: Price, quantity: Quantity) -> bool {
+        if quantity == 0 || self.window_pinned || self.best_bid.is_some() || self.best_ask.is_some()
+        {
+            return false;
+        }
+        self.base = price.saturating_sub((CAP / 2) as Price);
+        true
+    }
+
+    /// `write_bid`/`write_ask` for a price outside the window
+    #[cold]
+    fn write_overflow(&mut self, side: Side, price: Price, quantity: Quantity) {
+        let (overflow, reserve, total, best) = match side {
+            Side::Bid => (
+                &mut self.bid_overflow,
+                &mut self.bid_overflow_reserve,
+                &mut self.total_bid_qty,
+                self.best_bid,
+            ),
+            Side::Ask => (
+                &mut self.ask_overflow,
+                &mut self.ask_overflow_reserve,
+                &mut self.total_ask_qty,
+                self.best_ask,
+            ),
+        };
+        let old_qty = if quantity == 0 {
+            reserve.remove(&price);
+            overflow.remove(&price)
+        } else {
+            overflow.insert(price, quantity)
+        }
+        .unwrap_or(0);
+        *total = *total + quantity - old_qty;
+
+        if quantity == 0 {
+            if old_qty != 0 && best == Some(price) {
+                match side {
+                    Side::Bid => self.find_new_best_bid(),
+                    Side::Ask => self.find_new_best_ask(),
+                }
+            }
+            return;
+        }
+        let improves = best.is_none_or(|best| match side {
+            Side::Bid => price > best,
+            Side::Ask => price < best,
+        });
+        if improves {
+            match side {
+                Side::Bid => self.best_bid = Some(price),
+                Side::Ask => self.best_ask = Some(price),
+            }
+        }
+    }
+
+    /// Fire the touch hook if the best level of `side` kept its price but
+    /// not its quantity since `prev`
+    #[cold]
+    fn notify_touch(&mut self, side: Side, prev: Option<(Price, Quantity)>) {
+        if let (Some((prev_price, prev_qty)), Some((price, qty))) = (prev, self.best_level(side))
+            && price == prev_price
+            && qty != prev_qty
+            && let Some(hook) = self.touch_hook.as_mut()
+        {
+            hook(side, price, qty);
+        }
+    }
+
+    #[cold]
+    fn sync_top_cache(&mut self, side: Side, price: Price, quantity: Quantity) {
+        if let Some(mut cache) = self.top_cache.take() {
+            cache.apply(self, side, price, quantity);
+            self.top_cache = Some(cache);
+        }
+    }
+
+    fn find_new_best_bid(&mut self) {
+        let in_window = highest_slot(self.root_bid, &self.l2_bid, &self.l1_bid)
+            .map(|slot| self.bid_prices[slot]);
+        let overflow = self.bid_overflow.last_key_value().map(|(&price, _)| price);
+        self.best_bid = in_window.max(overflow);
+    }
+
+    fn find_new_best_ask(&mut self) {
+        let in_window = lowest_slot(self.root_ask, &self.l2_ask, &self.l1_ask)
+            .map(|slot| self.ask_prices[slot]);
+        let overflow = self.ask_overflow.first_key_value().map(|(&price, _)| price);
+        self.best_ask = match (in_window, overflow) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+    }
+
+    /// Apply the cross policy to an update about to go in: count it if it
+    /// adds liquidity at or through the opposite best, and under `Resolve`
+    /// clear the opposite levels in its way, logging their pre-images to
+    /// `undo` if given. Returns false if it must be dropped.
+    fn enforce_cross_policy(
+        &mut self,
+        update: &Update,
+        mut undo: Option<&mut Vec<PreImage>>,
+    ) -> bool {
+        let (side, price) = match *update {
+            Update::Set {
+                price,
+                quantity,
+                side,
+            }
+            | Update::SetWithReserve {
+                price,
+                quantity,
+                side,
+                ..
+            } if quantity > 0 => (side, price),
+            Update::Change { price, delta, side } if delta > 0 => (side, price),
+            _ => return true,
+        };
+        let opposite = match side {
+            Side::Bid => Side::Ask,
+            Side::Ask => Side::Bid,
+        };
+        // Whether `best`, the opposite best, sits at or through `price`
+        let reaches = |best: Price| match side {
+            Side::Bid => best <= price,
+            Side::Ask => best >= price,
+        };
+        if !self
+            .best_level(opposite)
+            .is_some_and(|(best, _)| reaches(best))
+        {
+            return true;
+        }
+
+        self.cross_interventions += 1;
+        match self.cross_policy {
+            CrossPolicy::Allow => true,
+            CrossPolicy::Reject => false,
+            CrossPolicy::Resolve => {
+                while let Some((best, _)) =
+                    self.best_level(opposite).filter(|&(best, _)| reaches(best))
+                {
+                    if let Some(undo) = undo.as_deref_mut() {
+                        undo.push(self.pre_image(opposite, best));
+                    }
+                    self.set_level(opposite, best, 0);
+                }
+                true
+            }
+        }
+    }
+
+    /// Recompute whichever cached bests a deferred write left stale
+    fn settle_best(&mut self, stale_bid: &mut bool, stale_ask: &mut bool) {
+        if std::mem::take(stale_bid) {
+            self.find_new_best_bid();
+        }
+        if std::mem::take(stale_ask) {
+            self.find_new_best_ask();
+        }
+    }
+
+    #[inline(always)]
+    fn refresh_bbo_checksum(&mut self) {
+        self.bbo_checksum = bbo_checksum_of(self.best_level(Side::Bid), self.best_level(Side::Ask));
+    }
+
+    /// Next populated bid slot strictly worse (lower) than `slot`
+    fn find_next_bid(&self, slot: usize) -> Option<usize> {
+        next_slot_below(self.root_bid, &self.l2_bid, &self.l1_bid, slot)
+    }
+
+    /// Next populated ask slot strictly worse (higher) than `slot`
+    fn find_next_ask(&self, slot: usize) -> Option<usize> {
+        next_slot_above(self.root_ask, &self.l2_ask, &self.l1_ask, slot)
+    }
+
+    // =========================================================================
+    // SIDE-GENERIC TRAVERSAL
+    // =========================================================================
+
+    /// Slot holding the best level of `side`
+    #[inline(always)]
+    fn best_slot(&self, side: Side) -> Option<usize> {
+        match side {
+            Side::Bid => highest_slot(self.root_bid, &self.l2_bid, &self.l1_bid),
+            Side::Ask => lowest_slot(self.root_ask, &self.l2_ask, &self.l1_ask),
+        }
+    }
+
+    /// Next populated slot strictly worse than `slot` on `side`
+    #[inline(always)]
+    fn next_slot(&self, side: Side, slot: usize) -> Option<usize> {
+        match side {
+            Side::Bid => self.find_next_bid(slot),
+            Side::Ask => self.find_next_ask(slot),
+        }
+    }
+
+    /// (price, quantity) stored in `slot`
+    #[inline(always)]
+    fn level_at(&self, side: Side, slot: usize) -> (Price, Quantity) {
+        match side {
+            Side::Bid => (self.bid_prices[slot], self.bid_qty[slot]),
+            Side::Ask => (self.ask_prices[slot], self.ask_qty[slot]),
+        }
+    }
+
+    fn levels(&self, side: Side) -> Levels<'_> {
+        let (below, above) = self.overflow_ranges(side);
+        let (better, worse) = match side {
+            Side::Bid => (above, below),
+            Side::Ask => (below, above),
+        };
+        Levels {
+            book: self,
+            side,
+            better,
+            next: self.best_slot(side),
+            steps: 0,
+            worse,
+        }
+    }
+
+    /// Overflow levels of `side` below and above the window
+    #[inline(always)]
+    fn overflow_ranges(
+        &self,
+        side: Side,
+    ) -> (
+        btree_map::Range<'_, Price, Quantity>,
+        btree_map::Range<'_, Price, Quantity>,
+    ) {
+        let overflow = match side {
+            Side::Bid => &self.bid_overflow,
+            Side::Ask => &self.ask_overflow,
+        };
+        let top = self.base.saturating_add(CAP as Price);
+        (overflow.range(..self.base), overflow.range(top..))
+    }
+
+    /// Quantity resting at `price` on `side`, 0 if none
+    #[inline(always)]
+    fn quantity(&self, side: Side, price: Price) -> Quantity {
+        if !self.in_window(price) {
+            let overflow = match side {
+                Side::Bid => &self.bid_overflow,
+                Side::Ask => &self.ask_overflow,
+            };
+            return overflow.get(&price).copied().unwrap_or(0);
+        }
+        match side {
+            Side::Bid => self.bid_qty[self.slot(price)],
+            Side::Ask => self.ask_qty[self.slot(price)],
+        }
+    }
+
+    /// Best (price, quantity) of `side`, from the cached best
+    #[inline(always)]
+    fn best_level(&self, side: Side) -> Option<(Price, Quantity)> {
+        match side {
+            Side::Bid => self.best_bid.map(|p| (p, self.quantity(Side::Bid, p))),
+            Side::Ask => self.best_ask.map(|p| (p, self.quantity(Side::Ask, p))),
+        }
+    }
+
+    /// Count one traversal step against the guard.
+    /// Returns false once the limit is exceeded; debug builds panic instead,
+    /// since a consistent book never needs more than `CAP` steps.
+    #[inline(always)]
+    fn guard_step(&self, steps: &mut usize) -> bool {
+        *steps += 1;
+        let ok = *steps <= self.traversal_limit;
+        debug_assert!(ok, "traversal exceeded {} steps", self.traversal_limit);
+        ok
+    }
+
+    /// Set the maximum number of slots a traversal may visit before bailing out
+    pub fn set_traversal_limit(&mut self, limit: usize) {
+        self.traversal_limit = limit;
+    }
+
+    /// Empty `slot` regardless of which price it holds. Does not touch the
+    /// cached best; callers recompute it once they are done.
+    fn clear_slot(&mut self, side: Side, slot: usize) {
+        match side {
+            Side::Bid => {
+                self.total_bid_qty -= self.bid_qty[slot];
+                self.bid_qty[slot] = 0;
+                self.bid_reserve[slot] = 0;
+                clear_bit(&mut self.root_bid, &mut self.l2_bid, &mut self.l1_bid, slot);
+            }
+            Side::Ask => {
+                self.total_ask_qty -= self.ask_qty[slot];
+                self.ask_qty[slot] = 0;
+                self.ask_reserve[slot] = 0;
+                clear_bit(&mut self.root_ask, &mut self.l2_ask, &mut self.l1_ask, slot);
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn set_level(&mut self, side: Side, price: Price, quantity: Quantity) {
+        match side {
+            Side::Bid => self.update_bid(price, quantity),
+            Side::Ask => self.update_ask(price, quantity),
+        }
+    }
+
+    /// `apply_update` once the cross policy has let `update` through
+    #[inline(always)]
+    fn write_update(&mut self, update: Update) {
+        #[cfg(feature = "stats")]
+        let was_crossed = self.is_crossed_now();
+
+        match update {
+            Update::Set {
+                price,
+                quantity,
+                side: Side::Bid,
+            } => self.update_bid(price, quantity),
+            Update::Set {
+                price,
+                quantity,
+                side: Side::Ask,
+            } => self.update_ask(price, quantity),
+            Update::Remove {
+                price,
+                side: Side::Bid,
+            } => self.update_bid(price, 0),
+            Update::Remove {
+                price,
+                side: Side::Ask,
+            } => self.update_ask(price, 0),
+            Update::Change {
+                price,
+                delta,
+                side: Side::Bid,
+            } => self.update_bid(
+                price,
+                self.quantity(Side::Bid, price).saturating_add_signed(delta),
+            ),
+            Update::Change {
+                price,
+                delta,
+                side: Side::Ask,
+            } => self.update_ask(
+                price,
+                self.quantity(Side::Ask, price).saturating_add_signed(delta),
+            ),
+            Update::SetWithReserve {
+                price,
+                quantity,
+                reserve,
+                side,
+            } => {
+                self.set_level(side, price, quantity);
+                self.set_reserve(side, price, reserve);
+            }
+        }
+
+        #[cfg(feature = "stats")]
+        self.record_stats(was_crossed);
+    }
+
+    /// What `rollback` needs to put the level at `price` back as it is now
+    fn pre_image(&self, side: Side, price: Price) -> PreImage {
+        (
+            side,
+            price,
+            self.quantity(side, price),
+            self.reserve(side, price),
+        )
+    }
+
+    /// Write `pre_images` back newest first
+    fn rollback(&mut self, pre_images: Vec<PreImage>) {
+        for (side, price, qty, reserve) in pre_images.into_iter().rev() {
+            self.set_level(side, price, qty);
+            self.set_reserve(side, price, reserve);
+        }
+    }
+
+    // =========================================================================
+    // CONFIGURATION
+    // =========================================================================
+
+    /// A book that keeps the top `n` levels of each side cached for
+    /// `cached_top`, kept in sync on every update
+    pub fn with_top_cache(n: usize) -> Self {
+        let mut ob = Self::new();
+        ob.top_cache = Some(Box::new(TopNCache {
+            n,
+            bids: Vec::with_capacity(n + 1),
+            asks: Vec::with_capacity(n + 1),
+        }));
+        ob
+    }
+
+    /// Call `f` whenever the quantity at the best price changes while the
+    /// price itself stays put: refills, partial cancels and partial fills at
+    /// the touch. Replaces any previous callback.
+    pub fn on_touch_size_change(&mut self, f: TouchSizeHook) {
+        self.touch_hook = Some(f);
+    }
+
+    /// The cached top levels of `side`, best first, in O(1).
+    /// Empty unless the book was built with `with_top_cache`.
+    pub fn cached_top(&self, side: Side) -> &[(Price, Quantity)] {
+        match (&self.top_cache, side) {
+            (Some(cache), Side::Bid) => &cache.bids,
+            (Some(cache), Side::Ask) => &cache.asks,
+            (None, _) => &[],
+        }
+    }
+
+    /// Advance the book's notion of time. Time-based statistics stamp
+    /// events with the last value set here.
+    pub fn set_clock(&mut self, now: u64) {
+        self.clock = now;
+    }
+
+    pub fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    /// Choose how `apply_update` treats updates that would lock or cross the
+    /// book. Levels already crossed are left alone.
+    pub fn set_cross_policy(&mut self, policy: CrossPolicy) {
+        self.cross_policy = policy;
+    }
+
+    pub fn cross_policy(&self) -> CrossPolicy {
+        self.cross_policy
+    }
+
+    /// Number of updates rejected or resolved by the cross policy
+    pub fn cross_interventions(&self) -> u64 {
+        self.cross_interventions
+    }
+
+    /// Set the instrument tick size used by tick-denominated queries
+    pub fn set_tick_size(&mut self, tick_size: Price) {
+        assert!(tick_size > 0, "tick size must be positive");
+        self.tick_size = Some(tick_size);
+    }
+
+    pub fn tick_size(&self) -> Option<Price> {
+        self.tick_size
+    }
+
+    /// Tick size, falling back to one price unit when unset
+    #[inline(always)]
+    fn tick(&self) -> Price {
+        self.tick_size.unwrap_or(1)
+    }
+
+    /// Apply `updates` as one packet through `apply_updates` and report the
+    /// net move of the best prices across it, ignoring any intermediate
+    /// moves
+    pub fn apply_batch_reporting(&mut self, updates: &[Update]) -> BboDelta {
+        let (bid_before, ask_before) = (self.best_bid, self.best_ask);
+        self.apply_updates(updates);
+        BboDelta {
+            bid_before,
+            ask_before,
+            bid_after: self.best_bid,
+            ask_after: self.best_ask,
+        }
+    }
+
+    /// Validate and apply `updates` all-or-nothing. On the first invalid
+    /// update everything applied so far is rolled back from recorded
+    /// pre-images, including levels the cross policy cleared, and its index
+    /// and error are returned.
+    ///
+    /// Rollback restores the levels, reserves and `cross_interventions`.
+    /// Observers (touch hook, scenario recording, stats, mid history,
+    /// update times) see the applied writes and then the rollback writes as
+    /// ordinary updates.
+    pub fn apply_batch_transactional(
+        &mut self,
+        updates: &[Update],
+    ) -> Result<(), (usize, OrderBookError)> {
+        let interventions = self.cross_interventions;
+        let mut pre_images = Vec::with_capacity(updates.len());
+        for (i, update) in updates.iter().enumerate() {
+            if let Err(err) = self.validate_update(update) {
+                self.rollback(pre_images);
+                self.cross_interventions = interventions;
+                return Err((i, err));
+            }
+            let (price, side) = match *update {
+                Update::Set { price, side, .. }
+                | Update::SetWithReserve { price, side, .. }
+                | Update::Remove { price, side }
+                | Update::Change { price, side, .. } => (price, side),
+            };
+            pre_images.push(self.pre_image(side, price));
+            if self.cross_policy == CrossPolicy::Allow
+                || self.enforce_cross_policy(update, Some(&mut pre_images))
+            {
+                self.write_update(update.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Fixed-depth replace: `ladder` is the complete current state of `side`
+    /// between its lowest and highest price. Levels inside that span missing
+    /// from the ladder are removed; levels outside it are left untouched.
+    pub fn apply_ladder(&mut self, side: Side, ladder: &[(Price, Quantity)]) {
+        let Some(low) = ladder.iter().map(|&(p, _)| p).min() else {
+            return;
+        };
+        let high = ladder.iter().map(|&(p, _)| p).max().unwrap_or(low);
+
+        let stale: Vec<Price> = self
+            .levels(side)
+            .map(|(price, _)| price)
+            .filter(|&price| (low..=high).contains(&price))
+            .filter(|&price| !ladder.iter().any(|&(p, _)| p == price))
+            .collect();
+        for price in stale {
+            self.set_level(side, price, 0);
+        }
+        for &(price, quantity) in ladder {
+            self.set_level(side, price, quantity);
+        }
+    }
+
+    // =========================================================================
+    // SCENARIO RECORDING
+    // =========================================================================
+
+    /// Start capturing every level write into a `Scenario`, seeded with the
+    /// current state. Restarts any capture already in progress.
+    pub fn start_recording(&mut self) {
+        let initial = [Side::Bid, Side::Ask]
+            .into_iter()
+            .flat_map(|side| {
+                self.levels(side).map(move |(price, quantity)| Update::Set {
+                    price,
+                    quantity,
+                    side,
+                })
+            })
+            .collect();
+        self.recording = Some(Box::new(Scenario {
+            initial,
+            updates: Vec::new(),
+            checkpoints: vec![(0, self.checksum(usize::MAX))],
+        }));
+    }
+
+    /// End the capture and return it, with a final checkpoint on the current
+    /// state. Empty if no recording was in progress.
+    pub fn stop_recording(&mut self) -> Scenario {
+        let Some(mut scenario) = self.recording.take() else {
+            return Scenario::default();
+        };
+        let applied = scenario.updates.len();
+        if scenario
+            .checkpoints
+            .last()
+            .is_none_or(|&(n, _)| n != applied)
+        {
+            scenario
+                .checkpoints
+                .push((applied, self.checksum(usize::MAX)));
+        }
+        *scenario
+    }
+
+    #[cold]
+    fn record_write(&mut self, side: Side, price: Price, quantity: Quantity) {
+        let update = match quantity {
+            0 => Update::Remove { price, side },
+            quantity => Update::Set {
+                price,
+                quantity,
+                side,
+            },
+        };
+        let Some(scenario) = self.recording.as_mut() else {
+            return;
+        };
+        scenario.updates.push(update);
+        let applied = scenario.updates.len();
+        if applied % SCENARIO_CHECKPOINT_INTERVAL == 0 {
+            let crc = self.checksum(usize::MAX);
+            if let Some(scenario) = self.recording.as_mut() {
+                scenario.checkpoints.push((applied, crc));
+            }
+        }
+    }
+
+    /// Replay `scenario` on a fresh book and verify every checkpoint
+    pub fn replay_scenario(scenario: &Scenario) -> Result<(), ScenarioMismatch> {
+        let mut ob = OrderBookImpl::new();
+        for update in &scenario.initial {
+            ob.apply_update(update.clone());
+        }
+        let mut applied = 0;
+        for &(at, expected) in &scenario.checkpoints {
+            for update in scenario.updates.iter().take(at).skip(applied) {
+                ob.apply_update(update.clone());
+            }
+            applied = applied.max(at);
+            let actual = ob.checksum(usize::MAX);
+            if actual != expected {
+                return Err(ScenarioMismatch {
+                    applied: at,
+                    expected,
+                    actual,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // =========================================================================
+    // SEQUENCING
+    // =========================================================================
+
+    /// Apply an update carrying an exchange sequence number.
+    /// Stale sequences are ignored. A gap marks the book desynced, and all
+    /// further updates are dropped until `apply_snapshot` resynchronises it.
+    pub fn apply_sequenced(&mut self, seq: u64, update: Update) {
+        if self.desynced || self.last_sequence.is_some_and(|last| seq <= last) {
+            return;
+        }
+        if self.last_sequence.is_some_and(|last| seq != last + 1) {
+            self.desynced = true;
+            return;
+        }
+        self.apply_update(update);
+        self.last_sequence = Some(seq);
+    }
+
+    /// Replace the whole book with `snap` and resume sequencing from it
+    pub fn apply_snapshot(&mut self, snap: ExchangeSnapshot) {
+        self.remove_all(Side::Bid);
+        self.remove_all(Side::Ask);
+        for (price, quantity) in snap.bids {
+            self.update_bid(price, quantity);
+        }
+        for (price, quantity) in snap.asks {
+            self.update_ask(price, quantity);
+        }
+        self.last_sequence = Some(snap.seq);
+        self.desynced = false;
+        self.provisional_updates = 0;
+    }
+
+    /// Apply `update` outside the sequenced stream, e.g. increments received
+    /// during a gap, and mark the book provisional until the next
+    /// `apply_snapshot`. Bypasses the sequencing checks entirely.
+    pub fn apply_provisional(&mut self, update: Update) {
+        self.apply_update(update);
+        self.provisional_updates += 1;
+    }
+
+    /// Whether the state includes updates from `apply_provisional` that no
+    /// snapshot has confirmed yet
+    pub fn is_provisional(&self) -> bool {
+        self.provisional_updates != 0
+    }
+
+    /// Updates applied through `apply_provisional` since the last snapshot
+    pub fn provisional_update_count(&self) -> u64 {
+        self.provisional_updates
+    }
+
+    /// Replay `updates` and compare `checksum(depth)` against each expected
+    /// CRC in `checkpoints`, returning the first checkpoint sequence that
+    /// does not match. A checkpoint is checked once every update up to its
+    /// sequence has been applied. Both slices must be in ascending sequence
+    /// order; checkpoints past the last update are checked against the final
+    /// state.
+    pub fn find_first_divergence(
+        &mut self,
+        updates: &[(u64, Update)],
+        checkpoints: &[(u64, u32)],
+        depth: usize,
+    ) -> Option<u64> {
+        let mut pending = checkpoints.iter().peekable();
+        for (seq, update) in updates {
+            // Every update up to these checkpoints is in: verify before moving on
+            while let Some(&(cp_seq, crc)) = pending.next_if(|&&(cp_seq, _)| cp_seq < *seq) {
+                if self.checksum(depth) != crc {
+                    return Some(cp_seq);
+                }
+            }
+            self.apply_update(update.clone());
+        }
+        let crc = self.checksum(depth);
+        pending
+            .find(|&&(_, expected)| expected != crc)
+            .map(|&(seq, _)| seq)
+    }
+
+    pub fn last_sequence(&self) -> Option<u64> {
+        self.last_sequence
+    }
+
+    pub fn is_desynced(&self) -> bool {
+        self.desynced
+    }
+
+    /// Remove every level on `side`
+    fn remove_all(&mut self, side: Side) {
+        let mut next = self.best_slot(side);
+        while let Some(slot) = next {
+            next = self.next_slot(side, slot);
+            let (price, _) = self.level_at(side, slot);
+            self.set_level(side, price, 0);
+        }
+        let overflow = match side {
+            Side::Bid => &self.bid_overflow,
+            Side::Ask => &self.ask_overflow,
+        };
+        let prices: Vec<Price> = overflow.keys().copied().collect();
+        for price in prices {
+            self.set_level(side, price, 0);
+        }
+    }
+
+    /// Empty `side` in place, bypassing the per-update bookkeeping: zeroes
+    /// only the slots and bitmap words its bitmaps mark as live, so a sparse
+    /// side costs next to nothing. Leaves the BBO checksum stale.
+    fn wipe_side(&mut self, side: Side) {
+        let (qty, reserve, l1, l2, root, overflow, overflow_reserve, total, best) = match side {
+            Side::Bid => (
+                &mut self.bid_qty,
+                &mut self.bid_reserve,
+                &mut self.l1_bid,
+                &mut self.l2_bid,
+                &mut self.root_bid,
+                &mut self.bid_overflow,
+                &mut self.bid_overflow_reserve,
+                &mut self.total_bid_qty,
+                &mut self.best_bid,
+            ),
+            Side::Ask => (
+                &mut self.ask_qty,
+                &mut self.ask_reserve,
+                &mut self.l1_ask,
+                &mut self.l2_ask,
+                &mut self.root_ask,
+                &mut self.ask_overflow,
+                &mut self.ask_overflow_reserve,
+                &mut self.total_ask_qty,
+                &mut self.best_ask,
+            ),
+        };
+        while *root != 0 {
+            let w2 = root.trailing_zeros() as usize;
+            while l2[w2] != 0 {
+                let w1 = (w2 << 6) | l2[w2].trailing_zeros() as usize;
+                while l1[w1] != 0 {
+                    let slot = (w1 << 6) | l1[w1].trailing_zeros() as usize;
+                    qty[slot] = 0;
+                    reserve[slot] = 0;
+                    l1[w1] &= l1[w1] - 1;
+                }
+                l2[w2] &= l2[w2] - 1;
+            }
+            *root &= *root - 1;
+        }
+        overflow.clear();
+        overflow_reserve.clear();
+        *total = 0;
+        *best = None;
+    }
+
+    // =========================================================================
+    // RESERVE QUANTITY
+    // =========================================================================
+
+    /// Reserve behind the level at `price` on `side`, 0 if none was set.
+    /// None if there is no level there.
+    pub fn get_reserve_at(&self, price: Price, side: Side) -> Option<Quantity> {
+        if self.quantity(side, price) == 0 {
+            return None;
+        }
+        Some(self.reserve(side, price))
+    }
+
+    /// Sum of the reserves behind every level of `side`
+    pub fn total_reserve(&self, side: Side) -> Quantity {
+        self.levels(side)
+            .map(|(price, _)| self.reserve(side, price))
+            .sum()
+    }
+
+    /// Reserve at `price` on `side`, without checking that a level exists
+    fn reserve(&self, side: Side, price: Price) -> Quantity {
+        if !self.in_window(price) {
+            let overflow = match side {
+                Side::Bid => &self.bid_overflow_reserve,
+                Side::Ask => &self.ask_overflow_reserve,
+            };
+            return overflow.get(&price).copied().unwrap_or(0);
+        }
+        match side {
+            Side::Bid => self.bid_reserve[self.slot(price)],
+            Side::Ask => self.ask_reserve[self.slot(price)],
+        }
+    }
+
+    /// Attach `reserve` to the level at `price`. Ignored when there is no
+    /// level: a reserve never outlives its displayed quantity.
+    fn set_reserve(&mut self, side: Side, price: Price, reserve: Quantity) {
+        if self.quantity(side, price) == 0 {
+            return;
+        }
+        if !self.in_window(price) {
+            let overflow = match side {
+                Side::Bid => &mut self.bid_overflow_reserve,
+                Side::Ask => &mut self.ask_overflow_reserve,
+            };
+            if reserve == 0 {
+                overflow.remove(&price);
+            } else {
+                overflow.insert(price, reserve);
+            }
+            return;
+        }
+        let slot = self.slot(price);
+        match side {
+            Side::Bid => self.bid_reserve[slot] = reserve,
+            Side::Ask => self.ask_reserve[slot] = reserve,
+        }
+    }
+
+    // =========================================================================
+    // STATS
+    // =========================================================================
+
+    #[cfg(feature = "stats")]
+    #[inline(always)]
+    fn is_crossed_now(&self) -> bool {
+        matches!((self.best_bid, self.best_ask), (Some(bid), Some(ask)) if bid > ask)
+    }
+
+    /// Bookkeeping run after every `apply_update`
+    #[cfg(feature = "stats")]
+    #[inline(always)]
+    fn record_stats(&mut self, was_crossed: bool) {
+        if !was_crossed && self.is_crossed_now() {
+            self.crossed_event_count += 1;
+        }
+    }
+
+    /// Number of updates that took the book from uncrossed to crossed
+    #[cfg(feature = "stats")]
+    pub fn crossed_event_count(&self) -> u64 {
+        self.crossed_event_count
+    }
+
+    /// Highest best bid and lowest best ask seen since the last reset
+    #[cfg(feature = "stats")]
+    pub fn session_extremes(&self) -> (Option<Price>, Option<Price>) {
+        (self.session_high_bid, self.session_low_ask)
+    }
+
+    /// Time the current best prices have been stable: `now_ts` minus the
+    /// clock value at the last change of either best price
+    #[cfg(feature = "stats")]
+    pub fn bbo_dwell(&self, now_ts: u64) -> u64 {
+        now_ts.saturating_sub(self.last_bbo_change_ts)
+    }
+
+    /// Bitmap levels a search outward from the vacated best slot must climb
+    /// to reach the new best: 1 within the same L1 word, 2 within the same L2
+    /// word, 3 through the root (including when the side emptied)
+    #[cfg(feature = "stats")]
+    #[cold]
+    fn record_best_recompute(&mut self, vacated: usize, new_best: Option<Price>) {
+        let new_slot = new_best.filter(|&price| self.in_window(price));
+        let steps = match new_slot.map(|price| self.slot(price)) {
+            Some(slot) if slot >> 6 == vacated >> 6 => 1,
+            Some(slot) if slot >> 12 == vacated >> 12 => 2,
+            _ => 3,
+        };
+        self.max_best_recompute_steps = self.max_best_recompute_steps.max(steps);
+    }
+
+    /// Deepest best-price recompute since the last reset, in bitmap levels
+    /// climbed from the removed best (1 = L1 word, 2 = L2 word, 3 = root).
+    /// 0 until a best level has been removed.
+    #[cfg(feature = "stats")]
+    pub fn max_best_recompute_steps(&self) -> u32 {
+        self.max_best_recompute_steps
+    }
+
+    /// Forget the session extremes and recompute depth; they re-seed from
+    /// the next update
+    #[cfg(feature = "stats")]
+    pub fn reset_session(&mut self) {
+        self.session_high_bid = None;
+        self.session_low_ask = None;
+        self.max_best_recompute_steps = 0;
+    }
+
+    /// Bookkeeping run whenever either best price changes
+    #[cfg(any(feature = "stats", feature = "mid-history"))]
+    #[cold]
+    fn on_bbo_change(&mut self) {
+        #[cfg(feature = "stats")]
+        {
+            self.last_bbo_change_ts = self.clock;
+        }
+        #[cfg(feature = "mid-history")]
+        if let Some(mid) = self.mid_price() {
+            self.mid_history.push(self.clock, mid);
+        }
+    }
+
+    /// Population standard deviation of the mids recorded on recent BBO
+    /// changes (up to `MID_HISTORY_LEN`). None before any mid was recorded.
+    #[cfg(feature = "mid-history")]
+    pub fn mid_volatility(&self) -> Option<f64> {
+        let history = &self.mid_history;
+        if history.len == 0 {
+            return None;
+        }
+        let n = history.len as f64;
+        let mean = history.iter().map(|(_, m)| m).sum::<f64>() / n;
+        let var = history.iter().map(|(_, m)| (m - mean).powi(2)).sum::<f64>() / n;
+        Some(var.sqrt())
+    }
+
+    /// Time-weighted mid over `[now_ns - window_ns, now_ns]`. Each recorded
+    /// mid counts for the time it stood, from the clock value of its BBO
+    /// change (see `set_clock`) until the next one or `now_ns`; a mid set
+    /// before the window counts from the window start. With no elapsed time
+    /// to weigh, the latest mid. None before any mid was recorded.
+    #[cfg(feature = "mid-history")]
+    pub fn twap_mid(&self, window_ns: u64, now_ns: u64) -> Option<f64> {
+        let history = &self.mid_history;
+        let start = now_ns.saturating_sub(window_ns);
+        let ends = history.iter().skip(1).map(|(ts, _)| ts).chain([now_ns]);
+        let (mut weighted, mut total, mut latest) = (0.0, 0.0, None);
+        for ((ts, mid), end) in history.iter().zip(ends) {
+            latest = Some(mid);
+            let (from, to) = (ts.max(start), end.min(now_ns));
+            if to > from {
+                let dt = (to - from) as f64;
+                weighted += mid * dt;
+                total += dt;
+            }
+        }
+        if total == 0.0 {
+            return latest;
+        }
+        Some(weighted / total)
+    }
+
+    // =========================================================================
+    // UPDATE TIMES
+    // =========================================================================
+
+    /// Log a write of `quantity` to `price`. A write that leaves no level
+    /// drops the price's update times and trades, so only live levels hold
+    /// history.
+    #[cfg(feature = "timestamps")]
+    fn record_update_time(&mut self, side: Side, price: Price, quantity: Quantity) {
+        let (times, trades) = match side {
+            Side::Bid => (&mut self.bid_update_times, &mut self.bid_trades),
+            Side::Ask => (&mut self.ask_update_times, &mut self.ask_trades),
+        };
+        if quantity == 0 {
+            times.remove(&price);
+            trades.remove(&price);
+        } else {
+            times.entry(price).or_default().push(self.clock, ());
+        }
+    }
+
+    #[cfg(feature = "timestamps")]
+    fn update_times(&self, side: Side, price: Price) -> Option<&SlotHistory<()>> {
+        match side {
+            Side::Bid => self.bid_update_times.get(&price),
+            Side::Ask => self.ask_update_times.get(&price),
+        }
+    }
+
+    /// Updates per second written to `price` on `side` during
+    /// `(now_ns - window_ns, now_ns]`, with update times taken from the clock
+    /// (see `set_clock`) in nanoseconds. Only the last `UPDATE_TIMES_LEN`
+    /// updates per slot are kept, which caps the measurable rate.
+    #[cfg(feature = "timestamps")]
+    pub fn update_rate_at(&self, price: Price, side: Side, window_ns: u64, now_ns: u64) -> f64 {
+        if window_ns == 0 {
+            return 0.0;
+        }
+        let start = now_ns.saturating_sub(window_ns);
+        let count = self.update_times(side, price).map_or(0, |t| {
+            t.iter()
+                .filter(|&(ts, _)| ts > start && ts <= now_ns)
+                .count()
+        });
+        count as f64 / (window_ns as f64 / 1e9)
+    }
+
+    /// Clock value of the latest write to `price` on `side`, if any was
+    /// recorded
+    #[cfg(feature = "timestamps")]
+    pub fn last_update_at(&self, price: Price, side: Side) -> Option<u64> {
+        self.update_times(side, price)?
+            .iter()
+            .next()
+            .map(|(ts, _)| ts)
+    }
+
+    /// Record `quantity` traded at `price` against `side` at the current
+    /// clock, for feeds that report trades separately. `execute` records its
+    /// own fills. Trades at a price with no resting level are not kept.
+    #[cfg(feature = "timestamps")]
+    pub fn record_trade(&mut self, price: Price, side: Side, quantity: Quantity) {
+        if self.quantity(side, price) == 0 {
+            return;
+        }
+        let trades = match side {
+            Side::Bid => &mut self.bid_trades,
+            Side::Ask => &mut self.ask_trades,
+        };
+        trades.entry(price).or_default().push(self.clock, quantity);
+    }
+
+    /// Expected clock time until an order of `my_qty` joining the back of
+    /// the queue at `price` fills: the resting quantity ahead plus `my_qty`,
+    /// divided by the level's recent fill rate (volume of the last
+    /// `UPDATE_TIMES_LEN` trades there over the time from the oldest of them
+    /// to the current clock). None without trades at the level or without
+    /// time elapsed since the oldest.
+    #[cfg(feature = "timestamps")]
+    pub fn estimated_time_to_fill(
+        &self,
+        price: Price,
+        side: Side,
+        my_qty: Quantity,
+    ) -> Option<f64> {
+        let trades = match side {
+            Side::Bid => self.bid_trades.get(&price)?,
+            Side::Ask => self.ask_trades.get(&price)?,
+        };
+        let (oldest, _) = trades.iter().last()?;
+        let elapsed = self.clock.checked_sub(oldest).filter(|&dt| dt > 0)?;
+        let volume: Quantity = trades.iter().map(|(_, qty)| qty).sum();
+        let rate = volume as f64 / elapsed as f64;
+        let ahead = self.get_quantity_at(price, side).unwrap_or(0);
+        Some((ahead + my_qty) as f64 / rate)
+    }
+
+    /// Resting quantity of `side` bucketed by age, `now_ns` minus the level's
+    /// last update: bucket `i` covers ages in `[i * bucket_ns, (i + 1) * bucket_ns)`
+    /// and the last bucket also takes everything older.
+    #[cfg(feature = "timestamps")]
+    pub fn age_histogram(
+        &self,
+        side: Side,
+        now_ns: u64,
+        bucket_ns: u64,
+        num_buckets: usize,
+    ) -> Vec<Quantity> {
+        let mut histogram = vec![0; num_buckets];
+        if num_buckets == 0 || bucket_ns == 0 {
+            return histogram;
+        }
+        for (price, qty) in self.levels(side) {
+            let Some(updated) = self.last_update_at(price, side) else {
+                continue;
+            };
+            let bucket = (now_ns.saturating_sub(updated) / bucket_ns) as usize;
+            histogram[bucket.min(num_buckets - 1)] += qty;
+        }
+        histogram
+    }
+
+    // =========================================================================
+    // HEALTH CHECK
+    // =========================================================================
+
+    /// Audit the internal structures against each other by scanning every
+    /// slot, independently of the bitmap walks the hot path relies on. Runs
+    /// in release builds; cost is linear in `CAP`, so keep it off the hot path.
+    pub fn self_check(&self) -> SelfCheckReport {
+        const NAMES: [&str; 5] = [
+            "summary_bitmaps",
+            "bitmap_matches_quantities",
+            "no_empty_active_slots",
+            "total_quantity",
+            "best_price",
+        ];
+        let mut details: [Vec<String>; 5] = Default::default();
+
+        for side in [Side::Bid, Side::Ask] {
+            let (qty, prices, l1, l2, root, overflow, total, best) = match side {
+                Side::Bid => (
+                    &self.bid_qty,
+                    &self.bid_prices,
+                    &self.l1_bid,
+                    &self.l2_bid,
+                    self.root_bid,
+                    &self.bid_overflow,
+                    self.total_bid_qty,
+                    self.best_bid,
+                ),
+                Side::Ask => (
+                    &self.ask_qty,
+                    &self.ask_prices,
+                    &self.l1_ask,
+                    &self.l2_ask,
+                    self.root_ask,
+                    &self.ask_overflow,
+                    self.total_ask_qty,
+                    self.best_ask,
+                ),
+            };
+
+            let stale_l2 =
+                (0..L1_WORDS).find(|&w1| (l1[w1] != 0) != (l2[w1 >> 6] >> (w1 & 63) & 1 != 0));
+            let stale_root = (0..64).find(|&w2| {
+                let populated = w2 < L2_WORDS && l2[w2] != 0;
+                populated != (root >> w2 & 1 != 0)
+            });
+            if let Some(w1) = stale_l2 {
+                details[0].push(format!(
+                    "{side:?}: L2 bit for L1 word {w1} disagrees with it"
+                ));
+            } else if let Some(w2) = stale_root {
+                details[0].push(format!("{side:?}: root bit {w2} disagrees with L2"));
+            }
+
+            let mut unmarked = None;
+            let mut empty = None;
+            let mut sum: Quantity = 0;
+            let mut actual_best: Option<Price> = None;
+            let mut tally = |price: Price, q: Quantity| {
+                sum = sum.wrapping_add(q);
+                let better = actual_best.is_none_or(|b| match side {
+                    Side::Bid => price > b,
+                    Side::Ask => price < b,
+                });
+                if better {
+                    actual_best = Some(price);
+                }
+            };
+            for slot in 0..CAP {
+                let active = l1[slot >> 6] >> (slot & 63) & 1 != 0;
+                match (active, qty[slot]) {
+                    (false, 0) => {}
+                    (false, q) => {
+                        unmarked.get_or_insert(format!(
+                            "{side:?}: slot {slot} holds {q} but is not marked active"
+                        ));
+                    }
+                    (true, 0) => {
+                        empty.get_or_insert(format!(
+                            "{side:?}: slot {slot} is marked active but empty"
+                        ));
+                    }
+                    (true, q) => tally(prices[slot], q),
+                }
+            }
+            for (&price, &q) in overflow {
+                tally(price, q);
+            }
+            details[1].extend(unmarked);
+            details[2].extend(empty);
+            if sum != total {
+                details[3].push(format!(
+                    "{side:?}: cached total {total}, levels sum to {sum}"
+                ));
+            }
+            if best != actual_best {
+                details[4].push(format!(
+                    "{side:?}: cached best {best:?}, levels give {actual_best:?}"
+                ));
+            }
+        }
+
+        SelfCheckReport {
+            checks: NAMES
+                .into_iter()
+                .zip(details)
+                .map(|(name, details)| CheckResult {
+                    name,
+                    passed: details.is_empty(),
+                    details,
+                })
+                .collect(),
+        }
+    }
+
+    /// Slots whose bitmap bit is set while their quantity is zero, ascending.
+    /// Traversals would report the stale price left in such a slot as a live
+    /// level, so any hit means a bitmap update was missed.
+    pub fn find_phantom_slots(&self, side: Side) -> Vec<usize> {
+        let (_, _, l1) = self.bitmaps(side);
+        let qty = match side {
+            Side::Bid => &self.bid_qty,
+            Side::Ask => &self.ask_qty,
+        };
+        let mut phantoms = Vec::new();
+        for (w1, &word) in l1.iter().enumerate() {
+            let mut bits = word;
+            while bits != 0 {
+                let slot = (w1 << 6) | bits.trailing_zeros() as usize;
+                if qty[slot] == 0 {
+                    phantoms.push(slot);
+                }
+                bits &= bits - 1;
+            }
+        }
+        phantoms
+    }
+
+    // =========================================================================
+    // REPAIR
+    // =========================================================================
+
+    /// Repair active slots whose stored price does not map back to them
+    /// (or lies outside the window). Each offending level is moved to the
+    /// slot its price maps to, or to the overflow for prices outside the
+    /// window; if that price already has a level the larger quantity wins.
+    /// Returns the number of conflicts fixed.
+    pub fn dedup_slots(&mut self) -> usize {
+        let mut fixed = 0;
+        for side in [Side::Bid, Side::Ask] {
+            let mut misplaced = Vec::new();
+            let mut next = self.best_slot(side);
+            while let Some(slot) = next {
+                next = self.next_slot(side, slot);
+                let (price, qty) = self.level_at(side, slot);
+                if !self.in_window(price) || self.slot(price) != slot {
+                    misplaced.push((slot, price, qty));
+                }
+            }
+
+            for &(slot, _, _) in &misplaced {
+                self.clear_slot(side, slot);
+            }
+            for (_, price, qty) in misplaced {
+                fixed += 1;
+                if qty > self.quantity(side, price) {
+                    self.set_level(side, price, qty);
+                }
+            }
+        }
+        self.find_new_best_bid();
+        self.find_new_best_ask();
+        self.refresh_bbo_checksum();
+        fixed
+    }
+
+    /// Recompute both side totals from the levels and overwrite the cached
+    /// ones if they drifted. Returns whether a repair was needed.
+    pub fn verify_and_repair_totals(&mut self) -> bool {
+        let bid: Quantity = self.levels(Side::Bid).map(|(_, qty)| qty).sum();
+        let ask: Quantity = self.levels(Side::Ask).map(|(_, qty)| qty).sum();
+        let drifted = bid != self.total_bid_qty || ask != self.total_ask_qty;
+        self.total_bid_qty = bid;
+        self.total_ask_qty = ask;
+        drifted
+    }
+
+    // =========================================================================
+    // CHECKED PATH
+    // =========================================================================
+
+    /// Check that `update` can be applied as intended: rejects removals and
+    /// decrements of levels that do not exist. Every price is representable,
+    /// inside the window or in the overflow.
+    pub fn validate_update(&self, u: &Update) -> Result<(), OrderBookError> {
+        let (price, side, removes) = match *u {
+            Update::Set {
+                price,
+                quantity,
+                side,
+            }
+            | Update::SetWithReserve {
+                price,
+                quantity,
+                side,
+                ..
+            } => (price, side, quantity == 0),
+            Update::Remove { price, side } => (price, side, true),
+            Update::Change { price, delta, side } => (price, side, delta < 0),
+        };
+
+        if removes && self.quantity(side, price) == 0 {
+            return Err(OrderBookError::LevelNotFound { price, side });
+        }
+        Ok(())
+    }
+
+    /// Validate `update`, then apply it through the fast path
+    pub fn try_apply_update(&mut self, update: Update) -> Result<(), OrderBookError> {
+        self.validate_update(&update)?;
+        self.apply_update(update);
+        Ok(())
+    }
+
+    // =========================================================================
+    // ANALYTICS
+    // =========================================================================
+
+    /// Classify how the BBO moved from `prev` to `curr`.
+    /// A side appearing counts as an improvement, a side vanishing as a worsening.
+    pub fn classify_bbo_transition(prev: &OrderBookImpl, curr: &OrderBookImpl) -> BboEvent {
+        classify_bests(
+            (prev.best_bid, prev.best_ask),
+            (curr.best_bid, curr.best_ask),
+        )
+    }
+
+    /// Order-flow imbalance (Cont, Kukanov & Stoikov) from `prev` to `curr`.
+    /// Each side contributes the touch quantity added: the new size if its
+    /// best improved, the size change if it held, minus the old size if it
+    /// worsened. The bid's contribution counts positive and the ask's
+    /// negative. A side appearing counts as an improvement, a side vanishing
+    /// as a worsening.
+    pub fn order_flow_imbalance(prev: &OrderBookImpl, curr: &OrderBookImpl) -> f64 {
+        let flow = |side: Side| -> f64 {
+            match (prev.best_level(side), curr.best_level(side)) {
+                (Some((p0, q0)), Some((p1, q1))) if p0 == p1 => q1 as f64 - q0 as f64,
+                (Some((p0, _)), Some((p1, q1)))
+                    if (side == Side::Bid && p1 > p0) || (side == Side::Ask && p1 < p0) =>
+                {
+                    q1 as f64
+                }
+                (Some((_, q0)), _) => -(q0 as f64),
+                (None, Some((_, q1))) => q1 as f64,
+                (None, None) => 0.0,
+            }
+        };
+        flow(Side::Bid) - flow(Side::Ask)
+    }
+
+    /// Split the move in the size-weighted mid from `prev` to `curr` into
+    /// the part explained by the plain mid moving (the touch prices) and the
+    /// remainder, caused by the touch sizes rebalancing. The two sum to the
+    /// weighted-mid change. None if either book lacks a two-sided touch.
+    pub fn decompose_mid_change(prev: &OrderBookImpl, curr: &OrderBookImpl) -> Option<(f64, f64)> {
+        let price_move = curr.mid_price()? - prev.mid_price()?;
+        let weighted_move = curr.weighted_mid()? - prev.weighted_mid()?;
+        Some((price_move, weighted_move - price_move))
+    }
+
+    /// Realized spread of a passive fill at `fill_price` quoted in `prev` and
+    /// marked against the mid of `curr`: `2 * direction * (fill_price - mid)`,
+    /// where direction is -1 when our resting bid was hit (we bought) and +1
+    /// when our resting ask was lifted (we sold). Positive means spread
+    /// captured. None if either book lacks a mid.
+    pub fn realized_spread(
+        prev: &OrderBookImpl,
+        curr: &OrderBookImpl,
+        fill_side: Side,
+        fill_price: Price,
+    ) -> Option<f64> {
+        prev.mid_price()?;
+        let mid = curr.mid_price()?;
+        let direction = match fill_side {
+            Side::Bid => -1.0,
+            Side::Ask => 1.0,
+        };
+        Some(2.0 * direction * (fill_price as f64 - mid))
+    }
+
+    /// Hash of the best prices and sizes, kept current on every change to
+    /// the touch so it costs nothing to read. Equal tops give equal
+    /// checksums. FxHash based: cheap, not collision resistant.
+    #[inline(always)]
+    pub fn bbo_checksum(&self) -> u64 {
+        self.bbo_checksum
+    }
+
+    /// Pack the BBO prices into one `u64` for cheap change detection.
+    ///
+    /// The high 32 bits hold the low 32 bits of the best bid, the low 32 bits
+    /// those of the best ask; an empty side is encoded as `u32::MAX`. The key
+    /// is therefore exact for any pair of books whose prices lie within a 2^32
+    /// wide range. It collides only when prices differ by a multiple of 2^32,
+    /// or when a price's low 32 bits are all ones (indistinguishable from an
+    /// empty side).
+    #[inline(always)]
+    pub fn bbo_key(&self) -> u64 {
+        let bid = self.best_bid.map_or(u32::MAX, |p| p as u32);
+        let ask = self.best_ask.map_or(u32::MAX, |p| p as u32);
+        ((bid as u64) << 32) | ask as u64
+    }
+
+    /// The `n`th level of `side` (0 = best)
+    pub fn get_nth_level(&self, side: Side, n: usize) -> Option<(Price, Quantity)> {
+        self.levels(side).nth(n)
+    }
+
+    /// `get_nth_level` returning a `PriceLevel`
+    pub fn get_nth_level_struct(&self, side: Side, n: usize) -> Option<PriceLevel> {
+        self.get_nth_level(side, n).map(PriceLevel::from)
+    }
+
+    /// `get_top_levels` returning `PriceLevel`s
+    pub fn get_top_levels_struct(&self, side: Side, n: usize) -> Vec<PriceLevel> {
+        self.levels(side).take(n).map(PriceLevel::from).collect()
+    }
+
+    /// Top `depth` levels of both sides with the derived spread and mid
+    pub fn snapshot(&self, depth: usize) -> BookSnapshot {
+        BookSnapshot {
+            bid: self.get_top_levels_struct(Side::Bid, depth),
+            ask: self.get_top_levels_struct(Side::Ask, depth),
+            spread: self.get_spread(),
+            mid: self.mid_price(),
+        }
+    }
+
+    /// `snapshot(depth)` as JSON, e.g.
+    /// `{"bid":[{"px":10000,"qty":5}],"ask":[],"spread":null,"mid":null}`
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self, depth: usize) -> String {
+        serde_json::to_string(&self.snapshot(depth)).expect("snapshot is always serializable")
+    }
+
+    /// The best price and the price `fallback_depth` levels behind it
+    /// (0 = best itself), read in a single walk
+    pub fn best_with_fallback(
+        &self,
+        side: Side,
+        fallback_depth: usize,
+    ) -> (Option<Price>, Option<Price>) {
+        let mut levels = self.levels(side).map(|(price, _)| price);
+        let best = levels.next();
+        let fallback = match fallback_depth {
+            0 => best,
+            depth => levels.nth(depth - 1),
+        };
+        (best, fallback)
+    }
+
+    /// Top `N` levels of both sides written into a fixed-size, `#[repr(C)]`
+    /// image without touching the heap. `N` must fit the `u8` counts.
+    pub fn ring_snapshot<const N: usize>(&self) -> RingSnapshot<N> {
+        const { assert!(N <= u8::MAX as usize, "RingSnapshot depth must fit in a u8") };
+        let mut snap = RingSnapshot {
+            bids: [PriceLevel::default(); N],
+            asks: [PriceLevel::default(); N],
+            bid_count: 0,
+            ask_count: 0,
+        };
+        for (slot, level) in snap.bids.iter_mut().zip(self.levels(Side::Bid)) {
+            *slot = level.into();
+            snap.bid_count += 1;
+        }
+        for (slot, level) in snap.asks.iter_mut().zip(self.levels(Side::Ask)) {
+            *slot = level.into();
+            snap.ask_count += 1;
+        }
+        snap
+    }
+
+    /// Top `depth` levels of each side allocated in `arena` instead of the
+    /// global allocator, as (bids, asks)
+    #[cfg(feature = "bumpalo")]
+    pub fn snapshot_in<'a>(
+        &self,
+        arena: &'a bumpalo::Bump,
+        depth: usize,
+    ) -> (&'a [PriceLevel], &'a [PriceLevel]) {
+        let side_in = |side| {
+            let len = self.levels(side).take(depth).count();
+            let out = arena.alloc_slice_fill_copy(len, PriceLevel::default());
+            for (slot, level) in out.iter_mut().zip(self.levels(side)) {
+                *slot = level.into();
+            }
+            &*out
+        };
+        (side_in(Side::Bid), side_in(Side::Ask))
+    }
+
+    /// Midpoint between the best bid and best ask
+    pub fn mid_price(&self) -> Option<f64> {
+        match (self.best_bid, self.best_ask) {
+            (Some(bid), Some(ask)) => Some((bid + ask) as f64 / 2.0),
+            _ => None,
+        }
+    }
+
+    /// Average price paid to take `quantity` from `side`, walking best-first.
+    /// Returns None if the side cannot fill it (or `quantity` is zero).
+    pub fn vwap(&self, side: Side, quantity: Quantity) -> Option<f64> {
+        if quantity == 0 {
+            return None;
+        }
+        let mut remaining = quantity;
+        let mut notional = 0.0;
+        for (price, qty) in self.levels(side) {
+            let take = qty.min(remaining);
+            notional += price as f64 * take as f64;
+            remaining -= take;
+            if remaining == 0 {
+                return Some(notional / quantity as f64);
+            }
+        }
+        None
+    }
+
+    /// Effective spread of taking `quantity` from `side`: `2 * |vwap - mid|`
+    pub fn effective_spread(&self, side: Side, quantity: Quantity) -> Option<f64> {
+        let mid = self.mid_price()?;
+        let vwap = self.vwap(side, quantity)?;
+        Some(2.0 * (vwap - mid).abs())
+    }
+
+    /// Round-trip cost of `quantity` over trading at the mid, in price units:
+    /// `(buy_vwap - mid) + (mid - sell_vwap)`. None if either side cannot
+    /// fill `quantity`.
+    pub fn liquidity_penalty(&self, quantity: Quantity) -> Option<f64> {
+        let mid = self.mid_price()?;
+        let buy = self.vwap(Side::Ask, quantity)?;
+        let sell = self.vwap(Side::Bid, quantity)?;
+        Some((buy - mid) + (mid - sell))
+    }
+
+    /// Touch left on `side` after taking `step_qty` from it, then again after
+    /// each further `step_qty`, up to `steps` times, without touching the
+    /// book. Each point is (quantity taken so far, resulting best price).
+    /// Stops early at the first step that would leave the side empty.
+    pub fn impact_curve(
+        &self,
+        side: Side,
+        step_qty: Quantity,
+        steps: usize,
+    ) -> Vec<(Quantity, Price)> {
+        let mut curve = Vec::new();
+        let mut levels = self.levels(side);
+        let Some((mut touch, mut left)) = levels.next() else {
+            return curve;
+        };
+        if step_qty == 0 {
+            return curve;
+        }
+        let mut taken: Quantity = 0;
+        while curve.len() < steps {
+            let mut need = step_qty;
+            while need >= left {
+                need -= left;
+                let Some((price, qty)) = levels.next() else {
+                    return curve;
+                };
+                (touch, left) = (price, qty);
+            }
+            left -= need;
+            taken += step_qty;
+            curve.push((taken, touch));
+        }
+        curve
+    }
+
+    /// Touch prices weighted by the opposite side's size, so the estimate
+    /// leans towards the thinner side
+    fn weighted_mid(&self) -> Option<f64> {
+        Some(microprice(
+            self.best_level(Side::Bid)?,
+            self.best_level(Side::Ask)?,
+        ))
+    }
+
+    /// Signed size imbalance at the touch,
+    /// `(bid_qty - ask_qty) / (bid_qty + ask_qty)` in [-1, 1]. Positive means
+    /// more size on the bid (upward pressure), negative more on the ask.
+    /// None if either side is empty.
+    pub fn crossing_pressure(&self) -> Option<f64> {
+        let (_, bid_qty) = self.best_level(Side::Bid)?;
+        let (_, ask_qty) = self.best_level(Side::Ask)?;
+        imbalance(bid_qty, ask_qty)
+    }
+
+    /// Inventory-skewed quotes: the mid shifted by
+    /// `-inventory * skew_ticks_per_unit` ticks (down when long, to lean on
+    /// selling), quoted as the nearest multiples of `tick` strictly below and
+    /// above it. None if the mid is unavailable or `tick` is not positive.
+    pub fn skewed_quotes(
+        &self,
+        inventory: i64,
+        skew_ticks_per_unit: f64,
+        tick: Price,
+    ) -> Option<(Price, Price)> {
+        if tick <= 0 {
+            return None;
+        }
+        let tick_f = tick as f64;
+        let center = (self.mid_price()? - inventory as f64 * skew_ticks_per_unit * tick_f) / tick_f;
+        let bid = (center.ceil() - 1.0) as Price * tick;
+        let ask = (center.floor() + 1.0) as Price * tick;
+        Some((bid, ask))
+    }
+
+    /// Quantity at `price` rounded down to a multiple of `precision` for
+    /// display (a `precision` of 0 leaves it as is). None if there is no
+    /// level at `price`.
+    pub fn display_quantity_at(
+        &self,
+        price: Price,
+        side: Side,
+        precision: Quantity,
+    ) -> Option<Quantity> {
+        let qty = self.get_quantity_at(price, side)?;
+        Some(qty.checked_rem(precision).map_or(qty, |rem| qty - rem))
+    }
+
+    /// Dense ladder segment: the quantity at every price from `low` to `high`
+    /// inclusive, ascending, with 0 for empty prices. `out` is cleared first.
+    /// Both bounds must lie within the representable window.
+    pub fn quantities_in_range(
+        &self,
+        side: Side,
+        low: Price,
+        high: Price,
+        out: &mut Vec<Quantity>,
+    ) -> Result<(), OrderBookError> {
+        out.clear();
+        for price in [low, high] {
+            if !self.in_window(price) {
+                return Err(OrderBookError::OutOfRange { price });
+            }
+        }
+        out.extend((low..=high).map(|price| {
+            let (resident, qty) = self.level_at(side, self.slot(price));
+            if resident == price { qty } else { 0 }
+        }));
+        Ok(())
+    }
+
+    /// Move the representable window to `[new_base, new_base + CAP)`.
+    /// Levels inside the new window are remapped to their slots; levels
+    /// falling outside it move to the overflow maps. The book itself does
+    /// not change, so this goes through the raw writers: no hooks, history,
+    /// stats or recording see the move.
+    pub fn recenter(&mut self, new_base: Price) {
+        let this = &*self;
+        let kept: Vec<_> = [Side::Bid, Side::Ask]
+            .into_iter()
+            .flat_map(|side| {
+                this.levels(side)
+                    .map(move |(price, qty)| (side, price, qty, this.reserve(side, price)))
+            })
+            .collect();
+        self.wipe_side(Side::Bid);
+        self.wipe_side(Side::Ask);
+
+        self.base = new_base;
+        self.window_pinned = true;
+        for (side, price, qty, reserve) in kept {
+            match side {
+                Side::Bid => self.write_bid::<false>(price, qty),
+                Side::Ask => self.write_ask::<false>(price, qty),
+            };
+            self.set_reserve(side, price, reserve);
+        }
+        self.window_pinned = false;
+        self.refresh_bbo_checksum();
+    }
+
+    /// Drop every level of `side` beyond the best `n`. The best is unchanged
+    /// unless `n` is 0, which empties the side.
+    pub fn truncate_to_depth(&mut self, side: Side, n: usize) {
+        let excess: Vec<Price> = self.levels(side).skip(n).map(|(price, _)| price).collect();
+        for price in excess {
+            self.set_level(side, price, 0);
+        }
+    }
+
+    /// Drop every level of `side` strictly worse than `price`: bids below it,
+    /// asks above it. Levels at `price` and better, and so the best, are kept.
+    pub fn clear_worse_than(&mut self, side: Side, price: Price) {
+        let worse = |p: Price| match side {
+            Side::Bid => p < price,
+            Side::Ask => p > price,
+        };
+        let excess: Vec<Price> = self
+            .levels(side)
+            .map(|(p, _)| p)
+            .skip_while(|&p| !worse(p))
+            .collect();
+        for p in excess {
+            self.set_level(side, p, 0);
+        }
+    }
+
+    /// Internal storage slot for `price` under the current window, or None if
+    /// the price is outside it
+    pub fn slot_of(&self, price: Price) -> Option<usize> {
+        self.in_window(price).then(|| self.slot(price))
+    }
+
+    /// Slots populated on `side`, best-first
+    pub fn active_slots(&self, side: Side) -> Vec<usize> {
+        let mut slots = Vec::new();
+        let mut next = self.best_slot(side);
+        let mut steps = 0;
+        while let Some(slot) = next {
+            if !self.guard_step(&mut steps) {
+                break;
+            }
+            slots.push(slot);
+            next = self.next_slot(side, slot);
+        }
+        slots
+    }
+
+    /// The occupancy bitmap hierarchy of `side` as (root, L2, L1).
+    ///
+    /// Bit `b` of `l1[w]` is set iff slot `w * 64 + b` is populated (see
+    /// `slot_of`). Bit `b` of `l2[w]` is set iff `l1[w * 64 + b]` is non-zero,
+    /// and bit `w` of `root` is set iff `l2[w]` is non-zero. `l1` has
+    /// `CAP / 64` words and `l2` has `CAP / 4096`; root bits past that are zero.
+    pub fn bitmaps(&self, side: Side) -> (u64, &[u64], &[u64]) {
+        match side {
+            Side::Bid => (self.root_bid, &self.l2_bid[..], &self.l1_bid[..]),
+            Side::Ask => (self.root_ask, &self.l2_ask[..], &self.l1_ask[..]),
+        }
+    }
+
+    /// Hash of the set of live prices on `side`, blind to their quantities.
+    /// Equal fingerprints mean the same levels are (almost certainly) live, so
+    /// it is a cheap gate before a full diff; see `changed_since`. Only
+    /// non-empty L1 words are visited.
+    pub fn bitmap_fingerprint(&self, side: Side) -> u64 {
+        let (root, l2, l1) = self.bitmaps(side);
+        let mut hash = 0;
+        let mut words2 = root;
+        while words2 != 0 {
+            let w2 = words2.trailing_zeros() as usize;
+            let mut words1 = l2[w2];
+            while words1 != 0 {
+                let w1 = (w2 << 6) | words1.trailing_zeros() as usize;
+                // Key each word by the price of its first slot so the hash
+                // follows prices rather than slots
+                let origin = self.base.wrapping_add((w1 << 6) as Price);
+                hash = fx_add(fx_add(hash, origin as u64), l1[w1]);
+                words1 &= words1 - 1;
+            }
+            words2 &= words2 - 1;
+        }
+        let overflow = match side {
+            Side::Bid => &self.bid_overflow,
+            Side::Ask => &self.ask_overflow,
+        };
+        for &price in overflow.keys() {
+            hash = fx_add(hash, price as u64);
+        }
+        hash
+    }
+
+    /// Whether the set of live prices on `side` differs from the one
+    /// `prev_fingerprint` was taken from. Quantity-only changes do not count.
+    pub fn changed_since(&self, side: Side, prev_fingerprint: u64) -> bool {
+        self.bitmap_fingerprint(side) != prev_fingerprint
+    }
+
+    /// Liquidity by distance from the best in ticks: index `i` holds the
+    /// quantity `i` ticks behind the best (0 = best), for `i` in `0..=max_ticks`.
+    /// Levels between ticks are counted at the tick below their distance.
+    pub fn depth_by_tick_distance(&self, side: Side, max_ticks: usize) -> Vec<Quantity> {
+        let mut depth = vec![0; max_ticks + 1];
+        let tick = self.tick();
+        let mut levels = self.levels(side);
+        let Some((best, best_qty)) = levels.next() else {
+            return depth;
+        };
+        depth[0] = best_qty;
+
+        for (price, qty) in levels {
+            let ticks = ((price - best).abs() / tick) as usize;
+            if ticks > max_ticks {
+                break;
+            }
+            depth[ticks] += qty;
+        }
+        depth
+    }
+
+    /// Liquidity by log distance from the mid: `|ln(price / mid)|` over
+    /// `[0, max_log_distance]` is split into `num_buckets` equal buckets, the
+    /// last one closed. Levels further out are left out. Empty if the mid is
+    /// unavailable.
+    pub fn log_depth_profile(
+        &self,
+        side: Side,
+        num_buckets: usize,
+        max_log_distance: f64,
+    ) -> Vec<Quantity> {
+        let Some(mid) = self.mid_price() else {
+            return Vec::new();
+        };
+        let mut profile = vec![0; num_buckets];
+        if num_buckets == 0 {
+            return profile;
+        }
+        for (price, qty) in self.levels(side) {
+            let distance = (price as f64 / mid).ln().abs();
+            if distance > max_log_distance {
+                break;
+            }
+            let bucket = (distance / max_log_distance * num_buckets as f64) as usize;
+            profile[bucket.min(num_buckets - 1)] += qty;
+        }
+        profile
+    }
+
+    /// Signed image of the book on a fixed tick grid around `center`, the
+    /// usual ML input layout. Index `half_levels - 1 - k` holds the price
+    /// `k + 1` ticks below `center` and index `half_levels + k` the price
+    /// `k + 1` ticks above it; `center` itself is not on the grid. Each cell
+    /// is bid quantity minus ask quantity at that price, so bids are positive,
+    /// asks negative and empty ticks zero.
+    pub fn to_grid(&self, center: Price, half_levels: usize) -> Vec<f64> {
+        let tick = self.tick();
+        let below = (1..=half_levels as Price).rev().map(|k| center - k * tick);
+        let above = (1..=half_levels as Price).map(|k| center + k * tick);
+        below
+            .chain(above)
+            .map(|price| {
+                let qty = |side| self.get_quantity_at(price, side).unwrap_or(0) as f64;
+                qty(Side::Bid) - qty(Side::Ask)
+            })
+            .collect()
+    }
+
+    /// Depth curve sampled every `notional_step` of cumulative `price * qty`
+    /// from the best: point `i` is the price at which the walk reaches
+    /// `(i + 1) * notional_step` and the cumulative quantity needed to get
+    /// there, counting a partly used level up to the threshold. At most
+    /// `num_points` points; fewer if the side runs out first. Levels at
+    /// non-positive prices add no notional.
+    pub fn depth_curve_by_notional(
+        &self,
+        side: Side,
+        notional_step: i128,
+        num_points: usize,
+    ) -> Vec<(Price, Quantity)> {
+        let mut points = Vec::new();
+        if notional_step <= 0 {
+            return points;
+        }
+        let (mut notional, mut cumulative) = (0i128, 0 as Quantity);
+        let mut threshold = notional_step;
+        for (price, qty) in self.levels(side) {
+            let level_notional = price.max(0) as i128 * qty as i128;
+            while points.len() < num_points && notional + level_notional >= threshold {
+                let p = price as i128;
+                let needed = (threshold - notional + p - 1) / p;
+                points.push((price, cumulative + needed as Quantity));
+                threshold += notional_step;
+            }
+            if points.len() == num_points {
+                break;
+            }
+            notional += level_notional;
+            cumulative += qty;
+        }
+        points
+    }
+
+    /// The only populated side, or None when both or neither have levels
+    #[inline(always)]
+    pub fn is_one_sided(&self) -> Option<Side> {
+        match (self.best_bid.is_some(), self.best_ask.is_some()) {
+            (true, false) => Some(Side::Bid),
+            (false, true) => Some(Side::Ask),
+            _ => None,
+        }
+    }
+
+    /// Whether the market is exactly one tick wide.
+    /// None if either side is empty or no tick size is configured.
+    pub fn is_one_tick_wide(&self) -> Option<bool> {
+        let tick = self.tick_size?;
+        Some(self.get_spread()? == tick)
+    }
+
+    /// Spread as a fraction of a reference range such as the average daily
+    /// range. None if either side is empty or `daily_range` is not positive.
+    pub fn spread_vs_range(&self, daily_range: Price) -> Option<f64> {
+        if daily_range <= 0 {
+            return None;
+        }
+        Some(self.get_spread()? as f64 / daily_range as f64)
+    }
+
+    /// Smallest price increment the book actually uses: the GCD of the gaps
+    /// between adjacent levels of each side and of the spread. None if the
+    /// book holds fewer than two distinct prices.
+    pub fn infer_tick_size(&self) -> Option<Price> {
+        let mut tick = 0;
+        for side in [Side::Bid, Side::Ask] {
+            let mut prev = None;
+            for (price, _) in self.levels(side) {
+                if let Some(prev) = prev {
+                    tick = gcd(tick, price.abs_diff(prev));
+                }
+                prev = Some(price);
+            }
+        }
+        if let (Some(bid), Some(ask)) = (self.best_bid, self.best_ask) {
+            tick = gcd(tick, ask.abs_diff(bid));
+        }
+        Price::try_from(tick).ok().filter(|&tick| tick > 0)
+    }
+
+    /// Backing depth relative to the touch: cumulative quantity over the top
+    /// `depth` levels divided by the best level's quantity.
+    /// None if the side has fewer than `depth` levels.
+    pub fn resilience(&self, side: Side, depth: usize) -> Option<f64> {
+        let mut levels = self.levels(side);
+        let (_, best_qty) = levels.next()?;
+        let mut total = best_qty;
+        for _ in 1..depth {
+            total += levels.next()?.1;
+        }
+        Some(total as f64 / best_qty as f64)
+    }
+
+    /// Quantity-weighted mean price of `side` and the standard deviation of
+    /// price around it, in one pass. None if the side is empty.
+    pub fn liquidity_moments(&self, side: Side) -> Option<(f64, f64)> {
+        let (mut w, mut sum, mut sum_sq) = (0.0, 0.0, 0.0);
+        for (price, qty) in self.levels(side) {
+            let (p, q) = (price as f64, qty as f64);
+            w += q;
+            sum += p * q;
+            sum_sq += p * p * q;
+        }
+        if w == 0.0 {
+            return None;
+        }
+        let mean = sum / w;
+        let var = (sum_sq / w - mean * mean).max(0.0);
+        Some((mean, var.sqrt()))
+    }
+
+    /// Depth-weighted mid: notional over the top `depth` levels of both sides
+    /// divided by their combined quantity, so the estimate leans towards the
+    /// heavier side. With one side empty this is that side's VWAP over its
+    /// top `depth` levels. None if both sides are empty.
+    pub fn fair_value(&self, depth: usize) -> Option<f64> {
+        let (mut notional, mut total) = (0.0, 0.0);
+        for side in [Side::Bid, Side::Ask] {
+            for (price, qty) in self.levels(side).take(depth) {
+                notional += price as f64 * qty as f64;
+                total += qty as f64;
+            }
+        }
+        (total > 0.0).then(|| notional / total)
+    }
+
+    /// Cumulative quantity over the top `depth` bid levels divided by that
+    /// over the top `depth` ask levels; above 1 means the bids are heavier.
+    /// None if either side is empty or `depth` is 0.
+    pub fn side_depth_ratio(&self, depth: usize) -> Option<f64> {
+        let sum = |side| -> Quantity { self.levels(side).take(depth).map(|(_, qty)| qty).sum() };
+        let (bid, ask) = (sum(Side::Bid), sum(Side::Ask));
+        (bid != 0 && ask != 0).then(|| bid as f64 / ask as f64)
+    }
+
+    /// Widest price gap between adjacent levels of `side`, as
+    /// (better price, worse price, gap). Ties go to the gap nearest the best.
+    /// None if the side has fewer than two levels.
+    pub fn largest_gap(&self, side: Side) -> Option<(Price, Price, Price)> {
+        let mut levels = self.levels(side).map(|(price, _)| price);
+        let mut prev = levels.next()?;
+        let mut widest: Option<(Price, Price, Price)> = None;
+        for price in levels {
+            let gap = (prev - price).abs();
+            if widest.is_none_or(|(_, _, g)| gap > g) {
+                widest = Some((prev, price, gap));
+            }
+            prev = price;
+        }
+        widest
+    }
+
+    /// Run-length encoding of `side`: consecutive levels one tick apart with
+    /// equal quantity collapse into (start, end, quantity), best-first, where
+    /// `start` is the run's price nearest the touch. A gap or a differing
+    /// quantity starts a new run; an isolated level is a run of one.
+    pub fn coalesce_runs(&self, side: Side) -> Vec<(Price, Price, Quantity)> {
+        let tick = self.tick();
+        let mut runs: Vec<(Price, Price, Quantity)> = Vec::new();
+        for (price, qty) in self.levels(side) {
+            match runs.last_mut() {
+                Some((_, end, q)) if *q == qty && (*end - price).abs() == tick => *end = price,
+                _ => runs.push((price, price, qty)),
+            }
+        }
+        runs
+    }
+
+    /// Uniform sample of `min(k, levels)` levels of `side`, best-first,
+    /// deterministic for a given `seed`. The level count comes from the
+    /// bitmap popcount plus the overflow, so a single selection-sampling pass
+    /// over the levels suffices and nothing else is materialised.
+    pub fn sample_levels(&self, side: Side, k: usize, seed: u64) -> Vec<(Price, Quantity)> {
+        let (_, _, l1) = self.bitmaps(side);
+        let overflow = match side {
+            Side::Bid => self.bid_overflow.len(),
+            Side::Ask => self.ask_overflow.len(),
+        };
+        let mut remaining = l1.iter().map(|w| w.count_ones() as u64).sum::<u64>() + overflow as u64;
+        let mut needed = (k as u64).min(remaining);
+        let mut sample = Vec::with_capacity(needed as usize);
+        let mut state = seed;
+        for level in self.levels(side) {
+            if needed == 0 {
+                break;
+            }
+            // Keep this level with probability needed / remaining
+            if splitmix64(&mut state) % remaining < needed {
+                sample.push(level);
+                needed -= 1;
+            }
+            remaining -= 1;
+        }
+        sample
+    }
+
+    /// Content hash of every level, fed in canonical best-first order, so two
+    /// books in the same logical state hash equal whatever their history.
+    /// Uses FxHash: fast, not collision resistant against adversaries.
+    pub fn state_hash(&self) -> u64 {
+        let mut hash = 0;
+        for side in [Side::Bid, Side::Ask] {
+            for (price, qty) in self.levels(side) {
+                hash = fx_add(fx_add(hash, price as u64), qty);
+            }
+            // Side separator so levels cannot shift between sides unnoticed
+            hash = fx_add(hash, u64::MAX);
+        }
+        hash
+    }
+
+    /// CRC-32 of the top `depth` levels of each side, for comparison against
+    /// exchange-published checksums. Bids then asks, best-first, each level
+    /// fed as little-endian price and quantity, each side followed by its
+    /// level count as a little-endian `u32`.
+    pub fn checksum(&self, depth: usize) -> u32 {
+        let mut crc = 0;
+        for side in [Side::Bid, Side::Ask] {
+            let mut count = 0u32;
+            for (price, qty) in self.levels(side).take(depth) {
+                crc = crc32_extend(crc, &price.to_le_bytes());
+                crc = crc32_extend(crc, &qty.to_le_bytes());
+                count += 1;
+            }
+            crc = crc32_extend(crc, &count.to_le_bytes());
+        }
+        crc
+    }
+
+    /// Quantity at the `rank`th distinct level strictly better than
+    /// `reference`, counting outwards from it (1 = nearest better level).
+    /// None if there is no such level.
+    pub fn quantity_at_rank_from(
+        &self,
+        side: Side,
+        reference: Price,
+        rank: usize,
+    ) -> Option<Quantity> {
+        if rank == 0 {
+            return None;
+        }
+        let better = self
+            .levels(side)
+            .take_while(|&(price, _)| match side {
+                Side::Bid => price > reference,
+                Side::Ask => price < reference,
+            })
+            .count();
+        let (_, qty) = self.levels(side).nth(better.checked_sub(rank)?)?;
+        Some(qty)
+    }
+
+    /// Quantity that must be taken from `side` to push its best price
+    /// `ticks` ticks away from the touch: every level closer than that to
+    /// the current best. Emptying the side counts as moving it. None if the
+    /// side is empty.
+    pub fn qty_to_move_bbo(&self, side: Side, ticks: Price) -> Option<Quantity> {
+        let best = self.best_level(side)?.0;
+        let reach = ticks * self.tick();
+        Some(
+            self.levels(side)
+                .take_while(|&(price, _)| (price - best).abs() < reach)
+                .map(|(_, qty)| qty)
+                .sum(),
+        )
+    }
+
+    /// Least-squares slope of cumulative quantity against distance from the
+    /// best price over the top `levels` levels. Positive means liquidity
+    /// thickens away from the touch. None with fewer than two levels.
+    pub fn depth_gradient(&self, side: Side, levels: usize) -> Option<f64> {
+        let best = self.best_level(side)?.0;
+        let (mut n, mut sx, mut sy, mut sxx, mut sxy) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        let mut cumulative = 0;
+        for (price, qty) in self.levels(side).take(levels) {
+            cumulative += qty;
+            let x = (price - best).abs() as f64;
+            let y = cumulative as f64;
+            n += 1.0;
+            sx += x;
+            sy += y;
+            sxx += x * x;
+            sxy += x * y;
+        }
+        if n < 2.0 {
+            return None;
+        }
+        Some((n * sxy - sx * sy) / (n * sxx - sx * sx))
+    }
+
+    /// Whether the spread is at least `min_spread_ticks` ticks wide (one
+    /// price unit per tick when no tick size is set). None if either side is
+    /// empty.
+    pub fn is_profitable_to_quote(&self, min_spread_ticks: Price) -> Option<bool> {
+        Some(self.get_spread()? >= min_spread_ticks * self.tick())
+    }
+
+    /// Screen the touch against a price observed elsewhere.
+    /// Returns the book side to trade against (`Ask` = buy here, `Bid` = sell
+    /// here) with the touch price and size, if the touch is through
+    /// `reference_mid`. When both qualify, the larger `edge * size` wins.
+    pub fn arbitrage_against(&self, reference_mid: Price) -> Option<(Side, Price, Quantity)> {
+        let buy = self
+            .best_level(Side::Ask)
+            .filter(|&(ask, _)| ask < reference_mid)
+            .map(|(ask, qty)| {
+                (
+                    Side::Ask,
+                    ask,
+                    qty,
+                    (reference_mid - ask) as i128 * qty as i128,
+                )
+            });
+        let sell = self
+            .best_level(Side::Bid)
+            .filter(|&(bid, _)| bid > reference_mid)
+            .map(|(bid, qty)| {
+                (
+                    Side::Bid,
+                    bid,
+                    qty,
+                    (bid - reference_mid) as i128 * qty as i128,
+                )
+            });
+
+        match (buy, sell) {
+            (Some(b), Some(s)) => Some(if b.3 >= s.3 { b } else { s }),
+            (b, s) => b.or(s),
+        }
+        .map(|(side, price, qty, _)| (side, price, qty))
+    }
+
+    /// Apply `u` and report whether it touched the top `n` levels of its
+    /// side, judged against the `n`th-best price before the update. When the
+    /// side holds fewer than `n` levels every update counts.
+    pub fn apply_update_topn_aware(&mut self, u: Update, n: usize) -> bool {
+        let (price, side) = match u {
+            Update::Set { price, side, .. }
+            | Update::SetWithReserve { price, side, .. }
+            | Update::Remove { price, side }
+            | Update::Change { price, side, .. } => (price, side),
+        };
+        let affected = n > 0
+            && match self.levels(side).nth(n - 1) {
+                None => true,
+                Some((nth, _)) => match side {
+                    Side::Bid => price >= nth,
+                    Side::Ask => price <= nth,
+                },
+            };
+        self.apply_update(u);
+        affected
+    }
+
+    // =========================================================================
+    // EXECUTION
+    // =========================================================================
+
+    /// Take up to `quantity` from `side` best-first, removing what is
+    /// consumed. `model` decides how much fills at each level; each level is
+    /// offered at most once. Returns the fills as (price, quantity).
+    pub fn execute(
+        &mut self,
+        side: Side,
+        quantity: Quantity,
+        model: &mut dyn FillModel,
+    ) -> Vec<(Price, Quantity)> {
+        self.execute_through(side, quantity, None, model)
+    }
+
+    /// Like `execute`, but stops at levels worse than `limit`
+    fn execute_through(
+        &mut self,
+        side: Side,
+        quantity: Quantity,
+        limit: Option<Price>,
+        model: &mut dyn FillModel,
+    ) -> Vec<(Price, Quantity)> {
+        let mut fills = Vec::new();
+        let mut remaining = quantity;
+        // Levels left partly filled stay ahead of the walk
+        let mut skipped = 0;
+        while remaining > 0 {
+            let Some((price, qty)) = self.levels(side).nth(skipped) else {
+                break;
+            };
+            let through = match side {
+                Side::Bid => limit.is_none_or(|l| price >= l),
+                Side::Ask => limit.is_none_or(|l| price <= l),
+            };
+            if !through {
+                break;
+            }
+            let take = model.consume(price, qty, remaining).min(qty).min(remaining);
+            if take < qty {
+                skipped += 1;
+            }
+            if take == 0 {
+                continue;
+            }
+            self.set_level(side, price, qty - take);
+            fills.push((price, take));
+            #[cfg(feature = "timestamps")]
+            self.record_trade(price, side, take);
+            remaining -= take;
+        }
+        fills
+    }
+
+    /// Apply `u`, first matching a `Set` or `SetWithReserve` against any
+    /// opposite liquidity it locks or crosses. Returns the fills; the
+    /// unfilled remainder rests at the update's price, replacing whatever
+    /// was there.
+    pub fn apply_with_matching(&mut self, u: Update) -> Vec<(Price, Quantity)> {
+        let (price, quantity, side, reserve) = match u {
+            Update::Set {
+                price,
+                quantity,
+                side,
+            } => (price, quantity, side, None),
+            Update::SetWithReserve {
+                price,
+                quantity,
+                reserve,
+                side,
+            } => (price, quantity, side, Some(reserve)),
+            _ => {
+                self.apply_update(u);
+                return Vec::new();
+            }
+        };
+
+        let opposite = match side {
+            Side::Bid => Side::Ask,
+            Side::Ask => Side::Bid,
+        };
+        let fills = self.execute_through(opposite, quantity, Some(price), &mut FullFillModel);
+        let filled: Quantity = fills.iter().map(|&(_, qty)| qty).sum();
+        self.set_level(side, price, quantity - filled);
+        if let Some(reserve) = reserve {
+            self.set_reserve(side, price, reserve);
+        }
+        fills
+    }
+
+    // =========================================================================
+    // DIFF & WIRE FORMAT
+    // =========================================================================
+
+    /// Updates that turn `prev` into `curr`, bids first, each side best-first
+    pub fn diff(prev: &OrderBookImpl, curr: &OrderBookImpl) -> Vec<Update> {
+        let mut updates = Vec::new();
+        for side in [Side::Bid, Side::Ask] {
+            diff_side(side, prev.levels(side), curr.levels(side), &mut updates);
+        }
+        updates
+    }
+
+    /// Image of the book for persisting or shipping elsewhere
+    pub fn to_snapshot(&self) -> Snapshot {
+        Snapshot {
+            base: self.base,
+            bids: self.levels(Side::Bid).collect(),
+            asks: self.levels(Side::Ask).collect(),
+        }
+    }
+
+    /// A fresh book holding exactly the levels of `snap`, laid out in the same
+    /// window. Repeated prices keep the last quantity; zero quantities are
+    /// skipped.
+    pub fn from_snapshot(snap: &Snapshot) -> Self {
+        let mut ob = Self::new();
+        ob.base = snap.base;
+        ob.window_pinned = true;
+        for (side, levels) in [(Side::Bid, &snap.bids), (Side::Ask, &snap.asks)] {
+            for &(price, quantity) in levels {
+                ob.set_level(side, price, quantity);
+            }
+        }
+        ob.window_pinned = false;
+        ob
+    }
+
+    /// Capture the current state for a later `restore`
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            bids: self.levels(Side::Bid).collect(),
+            asks: self.levels(Side::Ask).collect(),
+            last_sequence: self.last_sequence,
+            desynced: self.desynced,
+            provisional_updates: self.provisional_updates,
+        }
+    }
+
+    /// Return to the state captured by `cp`, touching only the levels that
+    /// changed since. The diff is written level by level, bypassing the
+    /// cross policy, since bids go in before asks and may cross on the way.
+    pub fn restore(&mut self, cp: &Checkpoint) {
+        let mut updates = Vec::new();
+        diff_side(
+            Side::Bid,
+            self.levels(Side::Bid),
+            cp.bids.iter().copied(),
+            &mut updates,
+        );
+        diff_side(
+            Side::Ask,
+            self.levels(Side::Ask),
+            cp.asks.iter().copied(),
+            &mut updates,
+        );
+        for update in updates {
+            match update {
+                Update::Set {
+                    price,
+                    quantity,
+                    side,
+                } => self.set_level(side, price, quantity),
+                Update::Remove { price, side } => self.set_level(side, price, 0),
+                _ => unreachable!("diff_side only emits sets and removes"),
+            }
+        }
+        self.last_sequence = cp.last_sequence;
+        self.desynced = cp.desynced;
+        self.provisional_updates = cp.provisional_updates;
+    }
+
+    /// Encode `diff(prev, curr)` onto `buf`.
+    ///
+    /// Each record is an op byte (bit 0: set/remove, bit 1: ask side), the
+    /// zigzag varint delta from the previous record's price (0 for the first),
+    /// and for sets a varint quantity.
+    pub fn encode_diff(prev: &OrderBookImpl, curr: &OrderBookImpl, buf: &mut Vec<u8>) {
+        let mut last = 0;
+        for update in Self::diff(prev, curr) {
+            put_update(buf, &mut last, &update);
+        }
+    }
+
+    /// Decode a stream produced by `encode_diff`
+    pub fn decode_diff(buf: &[u8]) -> Result<Vec<Update>, DecodeError> {
+        let mut updates = Vec::new();
+        let mut pos = 0;
+        let mut last: Price = 0;
+        while pos < buf.len() {
+            updates.push(get_update(buf, &mut pos, &mut last)?);
+        }
+        Ok(updates)
+    }
+
+    /// Apply a stream of fixed-width records (see `PACKED_RECORD_LEN`)
+    /// straight from `bytes`, without building `Update`s in between. Op 0 sets
+    /// and op 1 removes (its quantity is ignored); side 0 is bid, 1 is ask.
+    /// Returns the number of records applied. A length that is not a whole
+    /// number of records is rejected up front; on a malformed record the
+    /// records before it stay applied.
+    pub fn apply_packed(&mut self, bytes: &[u8]) -> Result<usize, PackedError> {
+        if !bytes.len().is_multiple_of(PACKED_RECORD_LEN) {
+            return Err(PackedError::Truncated { len: bytes.len() });
+        }
+        for (record, rec) in bytes.chunks_exact(PACKED_RECORD_LEN).enumerate() {
+            let side = match rec[1] {
+                0 => Side::Bid,
+                1 => Side::Ask,
+                side => return Err(PackedError::InvalidSide { record, side }),
+            };
+            let price = Price::from_le_bytes(rec[2..10].try_into().unwrap());
+            let quantity = i64::from_le_bytes(rec[10..18].try_into().unwrap());
+            let update = match rec[0] {
+                OP_SET => Update::Set {
+                    price,
+                    quantity: Quantity::try_from(quantity)
+                        .map_err(|_| PackedError::NegativeQuantity { record, quantity })?,
+                    side,
+                },
+                OP_REMOVE => Update::Remove { price, side },
+                op => return Err(PackedError::InvalidOp { record, op }),
+            };
+            self.apply_update(update);
+        }
+        Ok(bytes.len() / PACKED_RECORD_LEN)
+    }
+
+    /// Round-trip quote for `quantity`: the VWAP to buy it from the asks and
+    /// to sell it into the bids (both rounded to the nearest price unit) and
+    /// the unrounded difference between them. None if either side is too thin.
+    pub fn effective_spread_for_size(&self, quantity: Quantity) -> Option<(Price, Price, f64)> {
+        let buy = self.vwap(Side::Ask, quantity)?;
+        let sell = self.vwap(Side::Bid, quantity)?;
+        Some((buy.round() as Price, sell.round() as Price, buy - sell))
+    }
+
+    /// Quantity available on `side` within `max_slippage_ticks` ticks of its
+    /// best price (inclusive), i.e. how much can be taken without the fill
+    /// price moving further than that
+    pub fn fillable_within_slippage(&self, side: Side, max_slippage_ticks: Price) -> Quantity {
+        let Some((best, _)) = self.best_level(side) else {
+            return 0;
+        };
+        let reach = max_slippage_ticks * self.tick();
+        self.levels(side)
+            .take_while(|&(price, _)| (price - best).abs() <= reach)
+            .map(|(_, qty)| qty)
+            .sum()
+    }
+
+    /// Child-order sizes for taking `total_qty` from `side` at a participation
+    /// rate: best-first, `participation` (clamped to `[0, 1]`) of each level's
+    /// size, rounded down, until the slices add up to `total_qty`. The last
+    /// slice is trimmed to fit; levels whose share rounds to 0 are skipped.
+    /// May fall short if the book is too thin.
+    pub fn pov_slices(
+        &self,
+        side: Side,
+        total_qty: Quantity,
+        participation: f64,
+    ) -> Vec<(Price, Quantity)> {
+        let participation = participation.clamp(0.0, 1.0);
+        let mut slices = Vec::new();
+        let mut remaining = total_qty;
+        for (price, qty) in self.levels(side) {
+            if remaining == 0 {
+                break;
+            }
+            let slice = ((qty as f64 * participation) as Quantity).min(remaining);
+            if slice > 0 {
+                slices.push((price, slice));
+                remaining -= slice;
+            }
+        }
+        slices
+    }
+
+    /// Fraction of `quantity` that `side` could fill right now, in `[0, 1]`.
+    /// 0.0 for an empty side; a zero request on a non-empty side is 1.0.
+    pub fn fill_ratio(&self, side: Side, quantity: Quantity) -> f64 {
+        let available = self.get_total_quantity(side);
+        if available == 0 {
+            return 0.0;
+        }
+        if quantity == 0 {
+            return 1.0;
+        }
+        available.min(quantity) as f64 / quantity as f64
+    }
+
+    /// Multiply every level on `side` by `factor`, rounding down.
+    /// Levels that round to zero are removed.
+    pub fn scale_quantities(&mut self, side: Side, factor: f64) {
+        let mut steps = 0;
+        let mut next = self.best_slot(side);
+        while let Some(slot) = next {
+            if !self.guard_step(&mut steps) {
+                break;
+            }
+            next = self.next_slot(side, slot);
+            let (price, qty) = self.level_at(side, slot);
+            debug_assert!(qty != 0, "slot {slot} is marked active but empty");
+            self.set_level(side, price, (qty as f64 * factor) as Quantity);
+        }
+        let overflow = match side {
+            Side::Bid => &self.bid_overflow,
+            Side::Ask => &self.ask_overflow,
+        };
+        let levels: Vec<_> = overflow.iter().map(|(&p, &q)| (p, q)).collect();
+        for (price, qty) in levels {
+            self.set_level(side, price, (qty as f64 * factor) as Quantity);
+        }
+    }
+}
 
 impl OrderBook for OrderBookImpl {
     fn new() -> Self {
-        panic!("Todo")
+        OrderBookImpl {
+            bid_qty: boxed_array(0),
+            ask_qty: boxed_array(0),
+            bid_prices: boxed_array(0),
+            ask_prices: boxed_array(0),
+            bid_reserve: boxed_array(0),
+            ask_reserve: boxed_array(0),
+            l1_bid: boxed_array(0),
+            l1_ask: boxed_array(0),
+            l2_bid: [0; L2_WORDS],
+            l2_ask: [0; L2_WORDS],
+            root_bid: 0,
+            root_ask: 0,
+            bid_overflow: BTreeMap::new(),
+            ask_overflow: BTreeMap::new(),
+            bid_overflow_reserve: BTreeMap::new(),
+            ask_overflow_reserve: BTreeMap::new(),
+            best_bid: None,
+            best_ask: None,
+            total_bid_qty: 0,
+            total_ask_qty: 0,
+            bbo_checksum: bbo_checksum_of(None, None),
+            traversal_limit: DEFAULT_TRAVERSAL_LIMIT,
+            base: 0,
+            window_pinned: false,
+            tick_size: None,
+            cross_policy: CrossPolicy::Allow,
+            cross_interventions: 0,
+            last_sequence: None,
+            desynced: false,
+            provisional_updates: 0,
+            #[cfg(feature = "stats")]
+            crossed_event_count: 0,
+            #[cfg(feature = "stats")]
+            session_high_bid: None,
+            #[cfg(feature = "stats")]
+            session_low_ask: None,
+            #[cfg(feature = "stats")]
+            last_bbo_change_ts: 0,
+            #[cfg(feature = "stats")]
+            max_best_recompute_steps: 0,
+            clock: 0,
+            top_cache: None,
+            recording: None,
+            touch_hook: None,
+            #[cfg(feature = "mid-history")]
+            mid_history: Box::new(MidHistory {
+                mids: [(0, 0.0); MID_HISTORY_LEN],
+                head: 0,
+                len: 0,
+            }),
+            #[cfg(feature = "timestamps")]
+            bid_update_times: HashMap::new(),
+            #[cfg(feature = "timestamps")]
+            ask_update_times: HashMap::new(),
+            #[cfg(feature = "timestamps")]
+            bid_trades: HashMap::new(),
+            #[cfg(feature = "timestamps")]
+            ask_trades: HashMap::new(),
+        }
+    }
+
+    /// Wipes both sides through their bitmaps and resets everything else
+    /// field by field, keeping every heap allocation
+    fn clear(&mut self) {
+        self.wipe_side(Side::Bid);
+        self.wipe_side(Side::Ask);
+
+        // Listing every field makes a new one fail to compile here until it
+        // is reset
+        let OrderBookImpl {
+            bid_qty: _,
+            ask_qty: _,
+            bid_prices: _,
+            ask_prices: _,
+            bid_reserve: _,
+            ask_reserve: _,
+            l1_bid: _,
+            l1_ask: _,
+            l2_bid: _,
+            l2_ask: _,
+            root_bid: _,
+            root_ask: _,
+            bid_overflow: _,
+            ask_overflow: _,
+            bid_overflow_reserve: _,
+            ask_overflow_reserve: _,
+            best_bid: _,
+            best_ask: _,
+            total_bid_qty: _,
+            total_ask_qty: _,
+            bbo_checksum,
+            traversal_limit,
+            base,
+            window_pinned,
+            tick_size,
+            cross_policy,
+            cross_interventions,
+            last_sequence,
+            desynced,
+            provisional_updates,
+            #[cfg(feature = "stats")]
+            crossed_event_count,
+            #[cfg(feature = "stats")]
+            session_high_bid,
+            #[cfg(feature = "stats")]
+            session_low_ask,
+            #[cfg(feature = "stats")]
+            last_bbo_change_ts,
+            #[cfg(feature = "stats")]
+            max_best_recompute_steps,
+            clock,
+            top_cache,
+            recording,
+            touch_hook,
+            #[cfg(feature = "mid-history")]
+            mid_history,
+            #[cfg(feature = "timestamps")]
+            bid_update_times,
+            #[cfg(feature = "timestamps")]
+            ask_update_times,
+            #[cfg(feature = "timestamps")]
+            bid_trades,
+            #[cfg(feature = "timestamps")]
+            ask_trades,
+        } = self;
+        *bbo_checksum = bbo_checksum_of(None, None);
+        *traversal_limit = DEFAULT_TRAVERSAL_LIMIT;
+        *base = 0;
+        *window_pinned = false;
+        *tick_size = None;
+        *cross_policy = CrossPolicy::Allow;
+        *cross_interventions = 0;
+        *last_sequence = None;
+        *desynced = false;
+        *provisional_updates = 0;
+        #[cfg(feature = "stats")]
+        {
+            *crossed_event_count = 0;
+            *session_high_bid = None;
+            *session_low_ask = None;
+            *last_bbo_change_ts = 0;
+            *max_best_recompute_steps = 0;
+        }
+        *clock = 0;
+        *top_cache = None;
+        *recording = None;
+        *touch_hook = None;
+        #[cfg(feature = "mid-history")]
+        {
+            mid_history.head = 0;
+            mid_history.len = 0;
+        }
+        #[cfg(feature = "timestamps")]
+        for times in [bid_update_times, ask_update_times] {
+            times.clear();
+        }
+        #[cfg(feature = "timestamps")]
+        for trades in [bid_trades, ask_trades] {
+            trades.clear();
+        }
+    }
+
+    /// Wipes the side through its bitmaps unless per-update bookkeeping
+    /// (stats, mid history, update times, top cache, recording, the touch
+    /// hook) needs to see each removal, in which case levels are removed one
+    /// by one
+    fn clear_side(&mut self, side: Side) {
+        if cfg!(any(
+            feature = "stats",
+            feature = "mid-history",
+            feature = "timestamps"
+        )) || self.top_cache.is_some()
+            || self.recording.is_some()
+            || self.touch_hook.is_some()
+        {
+            self.remove_all(side);
+            return;
+        }
+        self.wipe_side(side);
+        self.refresh_bbo_checksum();
+    }
+
+    #[inline(always)]
+    fn apply_update(&mut self, update: Update) {
+        if self.cross_policy != CrossPolicy::Allow && !self.enforce_cross_policy(&update, None) {
+            return;
+        }
+        self.write_update(update);
+    }
+
+    /// Writes the whole packet with the best-price recompute deferred: a
+    /// removed best only marks its side stale, and each stale side is
+    /// rescanned once at the end. Per-update bookkeeping that needs the
+    /// intermediate bests (stats, mid history, top cache, recording, the
+    /// touch hook, a cross policy) falls back to applying updates one by one.
+    fn apply_updates(&mut self, updates: &[Update]) {
+        if cfg!(any(feature = "stats", feature = "mid-history"))
+            || self.top_cache.is_some()
+            || self.recording.is_some()
+            || self.touch_hook.is_some()
+            || self.cross_policy != CrossPolicy::Allow
+        {
+            for update in updates {
+                self.apply_update(update.clone());
+            }
+            return;
+        }
+
+        let (mut stale_bid, mut stale_ask) = (false, false);
+        for update in updates {
+            let (side, price, quantity) = match *update {
+                Update::Set {
+                    price,
+                    quantity,
+                    side,
+                }
+                | Update::SetWithReserve {
+                    price,
+                    quantity,
+                    side,
+                    ..
+                } => (side, price, quantity),
+                Update::Remove { price, side } => (side, price, 0),
+                Update::Change { price, delta, side } => (
+                    side,
+                    price,
+                    self.quantity(side, price).saturating_add_signed(delta),
+                ),
+            };
+            // Overflow writes and window anchoring read the best
+            if (stale_bid || stale_ask) && !self.in_window(price) {
+                self.settle_best(&mut stale_bid, &mut stale_ask);
+            }
+            match side {
+                Side::Bid => stale_bid |= self.write_bid::<true>(price, quantity),
+                Side::Ask => stale_ask |= self.write_ask::<true>(price, quantity),
+            }
+            if let Update::SetWithReserve { reserve, .. } = *update {
+                self.set_reserve(side, price, reserve);
+            }
+            #[cfg(feature = "timestamps")]
+            self.record_update_time(side, price, quantity);
+        }
+        self.settle_best(&mut stale_bid, &mut stale_ask);
+        self.refresh_bbo_checksum();
+    }
+
+    #[inline(always)]
+    fn get_spread(&self) -> Option<Price> {
+        match (self.best_bid, self.best_ask) {
+            (Some(bid), Some(ask)) => Some(ask - bid),
+            _ => None,
+        }
+    }
+
+    #[inline(always)]
+    fn get_best_bid(&self) -> Option<Price> {
+        self.best_bid
+    }
+
+    #[inline(always)]
+    fn get_best_ask(&self) -> Option<Price> {
+        self.best_ask
+    }
+
+    #[inline(always)]
+    fn get_quantity_at(&self, price: Price, side: Side) -> Option<Quantity> {
+        let qty = self.quantity(side, price);
+        (qty != 0).then_some(qty)
+    }
+
+    fn get_top_levels(&self, side: Side, n: usize) -> Vec<(Price, Quantity)> {
+        let mut levels = Vec::with_capacity(n.min(CAP));
+        levels.extend(self.levels(side).take(n));
+        levels
+    }
+
+    fn get_top_levels_into(&self, side: Side, out: &mut [(Price, Quantity)]) -> usize {
+        let mut written = 0;
+        // `out` first, so the walk stops without visiting a level past it
+        for (entry, level) in out.iter_mut().zip(self.levels(side)) {
+            *entry = level;
+            written += 1;
+        }
+        written
+    }
+
+    fn iter_levels(&self, side: Side) -> impl Iterator<Item = (Price, Quantity)> + '_ {
+        self.levels(side)
+    }
+
+    fn get_total_quantity(&self, side: Side) -> Quantity {
+        match side {
+            Side::Bid => self.total_bid_qty,
+            Side::Ask => self.total_ask_qty,
+        }
+    }
+
+    fn get_quantity_up_to(&self, limit: Price, side: Side) -> Quantity {
+        self.levels(side)
+            .take_while(|&(price, _)| match side {
+                Side::Bid => price >= limit,
+                Side::Ask => price <= limit,
+            })
+            .map(|(_, qty)| qty)
+            .sum()
+    }
+
+    fn get_sweep_cost(&self, qty: Quantity, side: Side) -> Option<(Price, i128)> {
+        sweep_cost(self.levels(side), qty)
+    }
+
+    fn get_mid_price(&self) -> Option<f64> {
+        self.mid_price()
+    }
+
+    fn get_microprice(&self) -> Option<f64> {
+        self.weighted_mid()
+    }
+
+    /// The touch sizes for `depth == 1`, otherwise a bitmap walk of each side
+    fn get_imbalance(&self, depth: usize) -> Option<f64> {
+        if depth == 1 {
+            return self.crossing_pressure();
+        }
+        if self.best_bid.is_none() || self.best_ask.is_none() {
+            return None;
+        }
+        let sum = |side| self.levels(side).take(depth).map(|(_, qty)| qty).sum();
+        imbalance(sum(Side::Bid), sum(Side::Ask))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::naive::NaiveOrderBook;
+
+    fn book(bids: &[(Price, Quantity)], asks: &[(Price, Quantity)]) -> OrderBookImpl {
+        let mut ob = OrderBookImpl::new();
+        for &(price, quantity) in bids {
+            ob.apply_update(Update::Set {
+                price,
+                quantity,
+                side: Side::Bid,
+            });
+        }
+        for &(price, quantity) in asks {
+            ob.apply_update(Update::Set {
+                price,
+                quantity,
+                side: Side::Ask,
+            });
+        }
+        ob
+    }
+
+    fn all_levels(ob: &OrderBookImpl) -> [Vec<(Price, Quantity)>; 2] {
+        [
+            ob.get_top_levels(Side::Bid, usize::MAX),
+            ob.get_top_levels(Side::Ask, usize::MAX),
+        ]
+    }
+
+    #[test]
+    fn test_next_slot_crosses_words() {
+        let ob = book(
+            &[(10, 1), (5000, 1), (40000, 1)],
+            &[(10, 1), (5000, 1), (40000, 1)],
+        );
+
+        assert_eq!(ob.find_next_bid(40000), Some(5000));
+        assert_eq!(ob.find_next_bid(5000), Some(10));
+        assert_eq!(ob.find_next_bid(10), None);
+        assert_eq!(ob.find_next_ask(10), Some(5000));
+        assert_eq!(ob.find_next_ask(5000), Some(40000));
+        assert_eq!(ob.find_next_ask(40000), None);
+    }
+
+    #[test]
+    fn test_classify_bbo_transition() {
+        let base = book(&[(10000, 100)], &[(10050, 100)]);
+        let cases = [
+            (book(&[(10000, 100)], &[(10050, 100)]), BboEvent::NoChange),
+            (
+                book(&[(10010, 100)], &[(10050, 100)]),
+                BboEvent::BidImproved,
+            ),
+            (book(&[(9990, 100)], &[(10050, 100)]), BboEvent::BidWorsened),
+            (
+                book(&[(10000, 100)], &[(10040, 100)]),
+                BboEvent::AskImproved,
+            ),
+            (
+                book(&[(10000, 100)], &[(10060, 100)]),
+                BboEvent::AskWorsened,
+            ),
+            (book(&[(9990, 100)], &[(10060, 100)]), BboEvent::Widened),
+            (book(&[(10010, 100)], &[(10040, 100)]), BboEvent::Tightened),
+            (book(&[(10010, 100)], &[(10060, 100)]), BboEvent::ShiftedUp),
+            (book(&[(9990, 100)], &[(10040, 100)]), BboEvent::ShiftedDown),
+            (book(&[], &[(10050, 100)]), BboEvent::BidWorsened),
+        ];
+
+        for (curr, expected) in cases {
+            assert_eq!(
+                OrderBookImpl::classify_bbo_transition(&base, &curr),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_scale_quantities() {
+        let mut ob = book(&[(10000, 100), (9990, 31), (9980, 1)], &[(10050, 80)]);
+
+        ob.scale_quantities(Side::Bid, 0.5);
+
+        assert_eq!(ob.get_quantity_at(10000, Side::Bid), Some(50));
+        assert_eq!(ob.get_quantity_at(9990, Side::Bid), Some(15));
+        assert_eq!(ob.get_quantity_at(9980, Side::Bid), None);
+        assert_eq!(ob.get_total_quantity(Side::Bid), 65);
+        assert_eq!(
+            ob.get_top_levels(Side::Bid, 10),
+            vec![(10000, 50), (9990, 15)]
+        );
+        assert_eq!(ob.get_quantity_at(10050, Side::Ask), Some(80));
+
+        ob.scale_quantities(Side::Bid, 0.01);
+        assert_eq!(ob.get_best_bid(), None);
+        assert_eq!(ob.get_total_quantity(Side::Bid), 0);
+    }
+
+    #[test]
+    fn test_bbo_key() {
+        let a = book(&[(10000, 100), (9990, 50)], &[(10050, 80)]);
+        let b = book(&[(10000, 7)], &[(10050, 3), (10100, 9)]);
+        let c = book(&[(10000, 100)], &[(10040, 80)]);
+        let d = book(&[(10050, 100)], &[(10000, 80)]);
+
+        assert_eq!(a.bbo_key(), b.bbo_key());
+        assert_ne!(a.bbo_key(), c.bbo_key());
+        assert_ne!(a.bbo_key(), d.bbo_key());
+        assert_ne!(a.bbo_key(), book(&[(10000, 100)], &[]).bbo_key());
+        assert_eq!(OrderBookImpl::new().bbo_key(), u64::MAX);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "marked active but empty")]
+    fn test_guard_trips_on_phantom_slot() {
+        let mut ob = book(&[(10000, 100), (9990, 50)], &[]);
+        let slot = ob.slot(9995);
+        set_bit(&mut ob.root_bid, &mut ob.l2_bid, &mut ob.l1_bid, slot);
+
+        ob.scale_quantities(Side::Bid, 0.5);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "traversal exceeded 5 steps")]
+    fn test_guard_trips_on_traversal_limit() {
+        let bids: Vec<_> = (0..6).map(|i| (10000 - i * 10, 100)).collect();
+        let mut ob = book(&bids, &[]);
+        ob.set_traversal_limit(5);
+
+        ob.get_top_levels(Side::Bid, 6);
+    }
+
+    #[test]
+    fn test_effective_spread() {
+        let ob = book(&[(10000, 100), (9990, 100)], &[(10050, 50), (10100, 100)]);
+
+        // Buying 100 takes 50@10050 + 50@10100: vwap 10075, mid 10025
+        assert_eq!(ob.vwap(Side::Ask, 100), Some(10075.0));
+        assert_eq!(ob.effective_spread(Side::Ask, 100), Some(100.0));
+        // Selling 150 takes 100@10000 + 50@9990: vwap 9996.67
+        let expected = 2.0 * (10025.0 - (100.0 * 10000.0 + 50.0 * 9990.0) / 150.0);
+        assert!((ob.effective_spread(Side::Bid, 150).unwrap() - expected).abs() < 1e-9);
+
+        assert_eq!(ob.effective_spread(Side::Ask, 151), None);
+        assert_eq!(
+            book(&[], &[(10050, 50)]).effective_spread(Side::Ask, 10),
+            None
+        );
+    }
+
+    #[test]
+    fn test_price_level_struct() {
+        let level = PriceLevel::from((10000, 100));
+        assert_eq!(
+            level,
+            PriceLevel {
+                price: 10000,
+                quantity: 100
+            }
+        );
+        let tuple: (Price, Quantity) = level.into();
+        assert_eq!(tuple, (10000, 100));
+
+        let ob = book(&[(10000, 100), (9990, 50), (9900, 10)], &[(10050, 80)]);
+        for side in [Side::Bid, Side::Ask] {
+            let structs: Vec<(Price, Quantity)> = ob
+                .get_top_levels_struct(side, 2)
+                .into_iter()
+                .map(Into::into)
+                .collect();
+            assert_eq!(structs, ob.get_top_levels(side, 2));
+        }
+        assert_eq!(
+            ob.get_nth_level_struct(Side::Bid, 1),
+            Some(PriceLevel {
+                price: 9990,
+                quantity: 50
+            })
+        );
+        assert_eq!(ob.get_nth_level(Side::Bid, 3), None);
+    }
+
+    #[test]
+    fn test_arbitrage_against() {
+        let ob = book(&[(10000, 100)], &[(10050, 80)]);
+
+        assert_eq!(ob.arbitrage_against(10100), Some((Side::Ask, 10050, 80)));
+        assert_eq!(ob.arbitrage_against(9900), Some((Side::Bid, 10000, 100)));
+        assert_eq!(ob.arbitrage_against(10025), None);
+
+        // Crossed book: selling 100 * 40 beats buying 80 * 10
+        let crossed = book(&[(10100, 100)], &[(10050, 80)]);
+        assert_eq!(
+            crossed.arbitrage_against(10060),
+            Some((Side::Bid, 10100, 100))
+        );
+    }
+
+    #[test]
+    fn test_validate_update() {
+        let mut ob = book(&[(10000, 100)], &[]);
+        let before = ob.get_top_levels(Side::Bid, 10);
+
+        let remove = Update::Remove {
+            price: 9990,
+            side: Side::Bid,
+        };
+        assert_eq!(
+            ob.validate_update(&remove),
+            Err(OrderBookError::LevelNotFound {
+                price: 9990,
+                side: Side::Bid
+            })
+        );
+        let set = Update::Set {
+            price: 9990,
+            quantity: 50,
+            side: Side::Bid,
+        };
+        assert_eq!(ob.validate_update(&set), Ok(()));
+        assert_eq!(ob.get_top_levels(Side::Bid, 10), before);
+
+        let alias = Update::Set {
+            price: 10000 + CAP as Price,
+            quantity: 50,
+            side: Side::Bid,
+        };
+        assert_eq!(ob.validate_update(&alias), Ok(()));
+        assert_eq!(
+            ob.try_apply_update(remove),
+            Err(OrderBookError::LevelNotFound {
+                price: 9990,
+                side: Side::Bid
+            })
+        );
+        assert_eq!(ob.try_apply_update(set), Ok(()));
+        assert_eq!(ob.get_quantity_at(9990, Side::Bid), Some(50));
+    }
+
+    #[test]
+    fn test_quantities_in_range() {
+        let ob = book(&[(10000, 100), (10003, 30), (9990, 5)], &[(10001, 80)]);
+        let mut out = vec![42];
+
+        ob.quantities_in_range(Side::Bid, 9999, 10004, &mut out)
+            .unwrap();
+        assert_eq!(out, vec![0, 100, 0, 0, 30, 0]);
+
+        assert_eq!(
+            ob.quantities_in_range(Side::Bid, 9999, CAP as Price, &mut out),
+            Err(OrderBookError::OutOfRange {
+                price: CAP as Price
+            })
+        );
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_recenter() {
+        let mut ob = book(
+            &[(100, 10), (600, 20), (40000, 30)],
+            &[(40100, 5), (65500, 15)],
+        );
+
+        ob.recenter(500);
+
+        // 100 now lies below the window and moves to overflow
+        assert_eq!(
+            ob.get_top_levels(Side::Bid, 10),
+            vec![(40000, 30), (600, 20), (100, 10)]
+        );
+        assert_eq!(ob.get_quantity_at(100, Side::Bid), Some(10));
+        assert_eq!(ob.slot_of(100), None);
+        assert_eq!(ob.get_total_quantity(Side::Bid), 60);
+        assert_eq!(
+            ob.get_top_levels(Side::Ask, 10),
+            vec![(40100, 5), (65500, 15)]
+        );
+
+        // 66000 gets a slot after the move rather than an overflow entry
+        ob.try_apply_update(Update::Set {
+            price: 66000,
+            quantity: 1,
+            side: Side::Ask,
+        })
+        .unwrap();
+        assert_eq!(ob.get_top_levels(Side::Ask, 10).last(), Some(&(66000, 1)));
+        assert!(ob.slot_of(66000).is_some());
+        assert_eq!(ob.slot_of(499), None);
+        assert!(ob.self_check().is_healthy());
+    }
+
+    #[test]
+    fn test_recenter_away_from_every_level() {
+        let mut ob = book(&[(10000, 100), (9990, 50)], &[(10050, 80)]);
+        let levels = all_levels(&ob);
+
+        ob.recenter(1_000_000);
+        assert_eq!(all_levels(&ob), levels);
+        assert_eq!(ob.slot_of(1_000_000), Some(0));
+        assert_eq!(ob.slot_of(10000), None);
+        assert!(ob.self_check().is_healthy());
+    }
+
+    #[test]
+    fn test_recenter_is_not_an_update() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let mut ob = OrderBookImpl::new();
+        ob.set_clock(1_000);
+        ob.apply_update(Update::Set {
+            price: 100_000,
+            quantity: 100,
+            side: Side::Bid,
+        });
+        ob.apply_update(Update::SetWithReserve {
+            price: 100_050,
+            quantity: 80,
+            reserve: 400,
+            side: Side::Ask,
+        });
+        ob.apply_update(Update::Set {
+            price: 99_990,
+            quantity: 50,
+            side: Side::Bid,
+        });
+        let touches = Arc::new(AtomicUsize::new(0));
+        let sink = Arc::clone(&touches);
+        ob.on_touch_size_change(Box::new(move |_, _, _| {
+            sink.fetch_add(1, Ordering::Relaxed);
+        }));
+        ob.start_recording();
+        #[cfg(feature = "mid-history")]
+        let volatility = ob.mid_volatility();
+        let levels = all_levels(&ob);
+        let checksum = ob.bbo_checksum();
+
+        ob.set_clock(2_000);
+        ob.recenter(99_000);
+
+        assert_eq!(all_levels(&ob), levels);
+        assert_eq!(ob.bbo_checksum(), checksum);
+        assert_eq!(ob.get_reserve_at(100_050, Side::Ask), Some(400));
+        assert!(ob.slot_of(99_000).is_some());
+        assert!(ob.self_check().is_healthy());
+        assert_eq!(touches.load(Ordering::Relaxed), 0);
+        assert!(ob.stop_recording().updates.is_empty());
+        #[cfg(feature = "mid-history")]
+        assert_eq!(ob.mid_volatility(), volatility);
+        #[cfg(feature = "stats")]
+        assert_eq!(ob.bbo_dwell(2_000), 1_000);
+    }
+
+    #[test]
+    fn test_depth_by_tick_distance() {
+        let mut ob = book(
+            &[(10000, 100), (9975, 50), (9925, 30), (9800, 9)],
+            &[(10025, 7)],
+        );
+        ob.set_tick_size(25);
+
+        assert_eq!(
+            ob.depth_by_tick_distance(Side::Bid, 4),
+            vec![100, 50, 0, 30, 0]
+        );
+        assert_eq!(ob.depth_by_tick_distance(Side::Ask, 2), vec![7, 0, 0]);
+        assert_eq!(
+            OrderBookImpl::new().depth_by_tick_distance(Side::Bid, 1),
+            vec![0, 0]
+        );
+    }
+
+    #[test]
+    fn test_is_one_tick_wide() {
+        let mut ob = book(&[(10000, 100)], &[(10025, 80)]);
+        assert_eq!(ob.is_one_tick_wide(), None);
+
+        ob.set_tick_size(25);
+        assert_eq!(ob.is_one_tick_wide(), Some(true));
+
+        ob.apply_update(Update::Remove {
+            price: 10025,
+            side: Side::Ask,
+        });
+        assert_eq!(ob.is_one_tick_wide(), None);
+        ob.apply_update(Update::Set {
+            price: 10050,
+            quantity: 80,
+            side: Side::Ask,
+        });
+        assert_eq!(ob.is_one_tick_wide(), Some(false));
+    }
+
+    #[test]
+    fn test_diff_wire_round_trip() {
+        let mut a = book(
+            &[(10000, 100), (9990, 50), (9900, 5)],
+            &[(10050, 80), (10100, 9)],
+        );
+        let b = book(
+            &[(10010, 7), (9990, 60), (9900, 5)],
+            &[(10100, 9), (12000, 1)],
+        );
+
+        let mut buf = Vec::new();
+        OrderBookImpl::encode_diff(&a, &b, &mut buf);
+        let updates = OrderBookImpl::decode_diff(&buf).unwrap();
+        assert_eq!(updates.len(), 5);
+        for update in updates {
+            a.apply_update(update);
+        }
+        assert_eq!(all_levels(&a), all_levels(&b));
+        assert_eq!(
+            a.get_total_quantity(Side::Bid),
+            b.get_total_quantity(Side::Bid)
+        );
+
+        assert_eq!(
+            OrderBookImpl::decode_diff(&buf[..buf.len() - 1]),
+            Err(DecodeError::Truncated)
+        );
+        assert_eq!(
+            OrderBookImpl::decode_diff(&[0x80]),
+            Err(DecodeError::InvalidOp(0x80))
+        );
+    }
+
+    #[test]
+    fn test_varint_rejects_overlong_values() {
+        let mut buf = Vec::new();
+        put_varint(&mut buf, u64::MAX);
+        assert_eq!(buf.len(), 10);
+        assert_eq!(get_varint(&buf, &mut 0), Ok(u64::MAX));
+
+        // A 10th byte above 1 would need bits past 64
+        let mut overlong = buf.clone();
+        overlong[9] = 0x02;
+        assert_eq!(get_varint(&overlong, &mut 0), Err(DecodeError::Overflow));
+        overlong[9] = 0x81;
+        overlong.push(0x00);
+        assert_eq!(get_varint(&overlong, &mut 0), Err(DecodeError::Overflow));
+    }
+
+    #[test]
+    fn test_resilience() {
+        let ob = book(&[(10000, 10), (9990, 500), (9980, 490)], &[(10050, 80)]);
+
+        assert_eq!(ob.resilience(Side::Bid, 3), Some(100.0));
+        assert_eq!(ob.resilience(Side::Bid, 1), Some(1.0));
+        assert_eq!(ob.resilience(Side::Bid, 4), None);
+        assert_eq!(ob.resilience(Side::Ask, 2), None);
+    }
+
+    #[test]
+    fn test_apply_snapshot_recovers_from_gap() {
+        let mut ob = OrderBookImpl::new();
+        let set = |price, quantity, side| Update::Set {
+            price,
+            quantity,
+            side,
+        };
+        ob.apply_sequenced(1, set(10000, 100, Side::Bid));
+        ob.apply_sequenced(2, set(10050, 80, Side::Ask));
+        ob.apply_sequenced(4, set(9990, 50, Side::Bid));
+        ob.apply_sequenced(5, set(9980, 50, Side::Bid));
+        assert!(ob.is_desynced());
+        assert_eq!(ob.last_sequence(), Some(2));
+        assert_eq!(ob.get_quantity_at(9990, Side::Bid), None);
+
+        ob.apply_snapshot(ExchangeSnapshot {
+            seq: 5,
+            bids: vec![(10010, 30), (9990, 50)],
+            asks: vec![(10040, 20)],
+        });
+        assert!(!ob.is_desynced());
+        assert_eq!(ob.last_sequence(), Some(5));
+        assert_eq!(
+            all_levels(&ob),
+            [vec![(10010, 30), (9990, 50)], vec![(10040, 20)]]
+        );
+        assert_eq!(ob.get_total_quantity(Side::Bid), 80);
+
+        ob.apply_sequenced(6, set(10040, 0, Side::Ask));
+        assert_eq!(ob.get_best_ask(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn test_crossed_event_count() {
+        let mut ob = book(&[(10000, 100)], &[(10050, 80)]);
+        let bid = |price| Update::Set {
+            price,
+            quantity: 10,
+            side: Side::Bid,
+        };
+
+        ob.apply_update(bid(10060)); // crosses
+        ob.apply_update(bid(10070)); // stays crossed
+        ob.apply_update(Update::Remove {
+            price: 10070,
+            side: Side::Bid,
+        });
+        ob.apply_update(Update::Remove {
+            price: 10060,
+            side: Side::Bid,
+        }); // uncrossed
+        ob.apply_update(bid(10055)); // recrosses
+
+        assert_eq!(ob.crossed_event_count(), 2);
+    }
+
+    #[test]
+    fn test_liquidity_moments() {
+        let ob = book(&[(10010, 50), (10000, 100), (9990, 50)], &[(10050, 80)]);
+
+        let (mean, std) = ob.liquidity_moments(Side::Bid).unwrap();
+        assert!((mean - 10000.0).abs() < 1e-9);
+        assert!((std - 50.0f64.sqrt()).abs() < 1e-6);
+        assert_eq!(ob.liquidity_moments(Side::Ask), Some((10050.0, 0.0)));
+        assert_eq!(OrderBookImpl::new().liquidity_moments(Side::Bid), None);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_to_json() {
+        let ob = book(&[(10000, 100), (9990, 50)], &[(10050, 80)]);
+
+        let json = ob.to_json(1);
+        assert_eq!(
+            json,
+            r#"{"bid":[{"px":10000,"qty":100}],"ask":[{"px":10050,"qty":80}],"spread":50,"mid":10025.0}"#
+        );
+        let parsed: BookSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, ob.snapshot(1));
+
+        assert_eq!(
+            OrderBookImpl::new().to_json(5),
+            r#"{"bid":[],"ask":[],"spread":null,"mid":null}"#
+        );
+    }
+
+    #[test]
+    fn test_largest_gap() {
+        let ob = book(
+            &[(10000, 1), (9990, 1), (9900, 1)],
+            &[(10050, 1), (10060, 1)],
+        );
+
+        assert_eq!(ob.largest_gap(Side::Bid), Some((9990, 9900, 90)));
+        assert_eq!(ob.largest_gap(Side::Ask), Some((10050, 10060, 10)));
+        assert_eq!(book(&[(10000, 1)], &[]).largest_gap(Side::Bid), None);
+    }
+
+    #[test]
+    fn test_apply_with_matching() {
+        let mut ob = book(&[(10000, 100)], &[(10050, 50), (10060, 60), (10100, 100)]);
+
+        let fills = ob.apply_with_matching(Update::Set {
+            price: 10070,
+            quantity: 150,
+            side: Side::Bid,
+        });
+
+        assert_eq!(fills, vec![(10050, 50), (10060, 60)]);
+        assert_eq!(ob.get_best_bid(), Some(10070));
+        assert_eq!(ob.get_quantity_at(10070, Side::Bid), Some(40));
+        assert_eq!(ob.get_best_ask(), Some(10100));
+        assert_eq!(ob.get_total_quantity(Side::Ask), 100);
+
+        // Non-crossing sets rest untouched
+        assert!(
+            ob.apply_with_matching(Update::Set {
+                price: 10090,
+                quantity: 5,
+                side: Side::Ask,
+            })
+            .is_empty()
+        );
+        assert_eq!(ob.get_best_ask(), Some(10090));
+    }
+
+    #[test]
+    fn test_apply_with_matching_full_fill_replaces_level() {
+        // Locked book: a stale bid sits at the ask's price
+        let mut ob = book(&[(10000, 100)], &[(10050, 50)]);
+        ob.set_level(Side::Bid, 10050, 20);
+
+        let fills = ob.apply_with_matching(Update::Set {
+            price: 10050,
+            quantity: 50,
+            side: Side::Bid,
+        });
+
+        assert_eq!(fills, vec![(10050, 50)]);
+        assert_eq!(ob.get_quantity_at(10050, Side::Bid), None);
+        assert_eq!(ob.get_best_bid(), Some(10000));
+        assert_eq!(ob.get_best_ask(), None);
+    }
+
+    #[test]
+    fn test_apply_with_matching_reserve() {
+        let mut ob = book(&[(10000, 100)], &[(10050, 50), (10060, 60)]);
+
+        let fills = ob.apply_with_matching(Update::SetWithReserve {
+            price: 10050,
+            quantity: 80,
+            reserve: 500,
+            side: Side::Bid,
+        });
+
+        assert_eq!(fills, vec![(10050, 50)]);
+        assert_eq!(ob.get_quantity_at(10050, Side::Bid), Some(30));
+        assert_eq!(ob.get_reserve_at(10050, Side::Bid), Some(500));
+        assert_eq!(ob.get_best_ask(), Some(10060));
+    }
+
+    #[test]
+    fn test_execute() {
+        let mut ob = book(&[(10000, 100), (9990, 50)], &[]);
+
+        assert_eq!(
+            ob.execute(Side::Bid, 120, &mut FullFillModel),
+            vec![(10000, 100), (9990, 20)]
+        );
+        assert_eq!(ob.get_top_levels(Side::Bid, 10), vec![(9990, 30)]);
+        assert_eq!(
+            ob.execute(Side::Bid, 100, &mut FullFillModel),
+            vec![(9990, 30)]
+        );
+        assert_eq!(ob.get_total_quantity(Side::Bid), 0);
+    }
+
+    #[test]
+    fn test_state_hash() {
+        let a = book(&[(10000, 100), (9990, 50)], &[(10050, 80)]);
+        let mut b = book(&[(9990, 50), (9000, 1)], &[(10060, 3), (10050, 80)]);
+        b.apply_update(Update::Set {
+            price: 10000,
+            quantity: 100,
+            side: Side::Bid,
+        });
+        b.apply_update(Update::Remove {
+            price: 9000,
+            side: Side::Bid,
+        });
+        b.apply_update(Update::Set {
+            price: 10060,
+            quantity: 0,
+            side: Side::Ask,
+        });
+        assert_eq!(a.state_hash(), b.state_hash());
+
+        b.apply_update(Update::Set {
+            price: 9990,
+            quantity: 51,
+            side: Side::Bid,
+        });
+        assert_ne!(a.state_hash(), b.state_hash());
+        assert_ne!(
+            book(&[(10000, 1)], &[]).state_hash(),
+            book(&[], &[(10000, 1)]).state_hash()
+        );
+    }
+
+    #[test]
+    fn test_top_cache_matches_top_levels() {
+        let mut ob = OrderBookImpl::with_top_cache(10);
+        let mut rng = 0x2545_f491_4f6c_dd1d_u64;
+        for _ in 0..20_000 {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            let side = if rng & 1 == 0 { Side::Bid } else { Side::Ask };
+            let price = 10000 + ((rng >> 8) % 40) as Price * 5;
+            let quantity = if (rng >> 32).is_multiple_of(3) {
+                0
+            } else {
+                (rng >> 40) % 100
+            };
+            ob.apply_update(Update::Set {
+                price,
+                quantity,
+                side,
+            });
+
+            for side in [Side::Bid, Side::Ask] {
+                assert_eq!(ob.cached_top(side), ob.get_top_levels(side, 10).as_slice());
+            }
+        }
+        assert!(OrderBookImpl::new().cached_top(Side::Bid).is_empty());
+    }
+
+    #[test]
+    fn test_quantity_at_rank_from() {
+        let ob = book(
+            &[(10030, 1), (10020, 2), (10010, 3), (10000, 4), (9990, 5)],
+            &[(10050, 6), (10060, 7), (10070, 8)],
+        );
+
+        assert_eq!(ob.quantity_at_rank_from(Side::Bid, 10000, 1), Some(3));
+        assert_eq!(ob.quantity_at_rank_from(Side::Bid, 10000, 3), Some(1));
+        assert_eq!(ob.quantity_at_rank_from(Side::Bid, 10000, 4), None);
+        assert_eq!(ob.quantity_at_rank_from(Side::Bid, 10005, 1), Some(3));
+        assert_eq!(ob.quantity_at_rank_from(Side::Ask, 10070, 2), Some(6));
+        assert_eq!(ob.quantity_at_rank_from(Side::Ask, 10070, 0), None);
+
+        // Overflow levels rank like any other, from any reference
+        let far = CAP as Price * 3;
+        let ob = book(
+            &[(10000, 4), (9990, 5)],
+            &[(10050, 6), (far, 7), (far + 10, 8)],
+        );
+        assert_eq!(ob.quantity_at_rank_from(Side::Ask, far + 20, 1), Some(8));
+        assert_eq!(ob.quantity_at_rank_from(Side::Ask, far + 20, 3), Some(6));
+        assert_eq!(ob.quantity_at_rank_from(Side::Ask, far + 20, 4), None);
+        assert_eq!(ob.quantity_at_rank_from(Side::Bid, -far, 2), Some(4));
+    }
+
+    #[test]
+    fn test_dedup_slots() {
+        let mut ob = book(&[(10000, 100), (10005, 50), (9990, 7)], &[(10050, 80)]);
+        assert_eq!(ob.dedup_slots(), 0);
+
+        // Slot 10000 claims to hold 10005, which already has its own level
+        let slot = ob.slot(10000);
+        ob.bid_prices[slot] = 10005;
+        assert_eq!(ob.dedup_slots(), 1);
+
+        assert_eq!(ob.get_quantity_at(10000, Side::Bid), None);
+        assert_eq!(ob.get_quantity_at(10005, Side::Bid), Some(100));
+        assert_eq!(ob.get_total_quantity(Side::Bid), 107);
+        assert_eq!(
+            ob.get_top_levels(Side::Bid, 10),
+            vec![(10005, 100), (9990, 7)]
+        );
+        assert_eq!(ob.dedup_slots(), 0);
+    }
+
+    #[test]
+    fn test_depth_gradient() {
+        let ob = book(
+            &[(10000, 10), (9990, 20), (9980, 30), (9970, 40)],
+            &[(10050, 5)],
+        );
+
+        // Cumulative 10, 30, 60 at distances 0, 10, 20
+        assert!((ob.depth_gradient(Side::Bid, 3).unwrap() - 2.5).abs() < 1e-9);
+        assert!(ob.depth_gradient(Side::Bid, 10).unwrap() > 0.0);
+        assert_eq!(ob.depth_gradient(Side::Ask, 5), None);
+        assert_eq!(ob.depth_gradient(Side::Bid, 1), None);
+    }
+
+    #[test]
+    fn test_best_with_fallback() {
+        let ob = book(&[(10000, 1), (9990, 1), (9980, 1)], &[(10050, 1)]);
+
+        assert_eq!(
+            ob.best_with_fallback(Side::Bid, 2),
+            (Some(10000), Some(9980))
+        );
+        assert_eq!(
+            ob.best_with_fallback(Side::Bid, 0),
+            (Some(10000), Some(10000))
+        );
+        assert_eq!(ob.best_with_fallback(Side::Ask, 1), (Some(10050), None));
+        assert_eq!(ob.best_with_fallback(Side::Bid, 3), (Some(10000), None));
+    }
+
+    #[test]
+    fn test_apply_update_topn_aware() {
+        let mut ob = book(
+            &[(10000, 1), (9990, 1), (9980, 1), (9970, 1)],
+            &[(10050, 1)],
+        );
+        let bid = |price| Update::Set {
+            price,
+            quantity: 5,
+            side: Side::Bid,
+        };
+
+        assert!(!ob.apply_update_topn_aware(bid(9970), 2));
+        assert!(!ob.apply_update_topn_aware(bid(9985), 2));
+        assert!(ob.apply_update_topn_aware(bid(10000), 2));
+        assert!(ob.apply_update_topn_aware(bid(9990), 2));
+        assert!(ob.apply_update_topn_aware(
+            Update::Remove {
+                price: 10000,
+                side: Side::Bid
+            },
+            1
+        ));
+        assert!(ob.apply_update_topn_aware(
+            Update::Set {
+                price: 10100,
+                quantity: 1,
+                side: Side::Ask
+            },
+            2
+        ));
+    }
+
+    #[test]
+    fn test_checkpoint_restore() {
+        let mut ob = book(&[(10000, 100), (9990, 50)], &[(10050, 80), (10100, 9)]);
+        ob.apply_sequenced(
+            7,
+            Update::Remove {
+                price: 10100,
+                side: Side::Ask,
+            },
+        );
+        let cp = ob.checkpoint();
+        let levels = all_levels(&ob);
+
+        ob.apply_update(Update::Set {
+            price: 10010,
+            quantity: 5,
+            side: Side::Bid,
+        });
+        ob.execute(Side::Ask, 80, &mut FullFillModel);
+        ob.apply_sequenced(
+            9,
+            Update::Remove {
+                price: 9990,
+                side: Side::Bid,
+            },
+        );
+        ob.restore(&cp);
+
+        assert_eq!(all_levels(&ob), levels);
+        assert_eq!(ob.get_best_bid(), Some(10000));
+        assert_eq!(ob.get_best_ask(), Some(10050));
+        assert_eq!(ob.get_spread(), Some(50));
+        assert_eq!(ob.get_total_quantity(Side::Bid), 150);
+        assert_eq!(ob.get_total_quantity(Side::Ask), 80);
+        assert_eq!(ob.last_sequence(), Some(7));
+        assert!(!ob.is_desynced());
+        assert_eq!(ob.checkpoint(), cp);
+    }
+
+    #[test]
+    fn test_restore_ignores_cross_policy() {
+        let mut ob = book(&[(10000, 100), (9990, 50)], &[(10050, 80)]);
+        let cp = ob.checkpoint();
+        let levels = all_levels(&ob);
+
+        // The market moves down, so the checkpoint's bids sit above the asks
+        ob.set_cross_policy(CrossPolicy::Reject);
+        for price in [10000, 9990] {
+            ob.apply_update(Update::Remove {
+                price,
+                side: Side::Bid,
+            });
+        }
+        ob.apply_update(Update::Set {
+            price: 9950,
+            quantity: 30,
+            side: Side::Ask,
+        });
+        ob.apply_update(Update::Set {
+            price: 9940,
+            quantity: 20,
+            side: Side::Bid,
+        });
+        assert_eq!(ob.cross_interventions(), 0);
+
+        ob.restore(&cp);
+        assert_eq!(all_levels(&ob), levels);
+        assert_eq!(ob.cross_interventions(), 0);
+
+        ob.set_cross_policy(CrossPolicy::Resolve);
+        ob.apply_update(Update::Set {
+            price: 9950,
+            quantity: 30,
+            side: Side::Ask,
+        });
+        ob.restore(&cp);
+        assert_eq!(all_levels(&ob), levels);
+    }
+
+    #[test]
+    fn test_effective_spread_for_size() {
+        let ob = book(&[(10000, 60), (9980, 100)], &[(10050, 50), (10100, 100)]);
+
+        // Buy 100: 50@10050 + 50@10100 = 10075; sell 100: 60@10000 + 40@9980 = 9992
+        assert_eq!(ob.effective_spread_for_size(100), Some((10075, 9992, 83.0)));
+        assert_eq!(ob.effective_spread_for_size(151), None);
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn test_session_extremes() {
+        let mut ob = book(&[(10000, 100)], &[(10050, 80)]);
+        ob.apply_update(Update::Set {
+            price: 10020,
+            quantity: 5,
+            side: Side::Bid,
+        });
+        ob.apply_update(Update::Remove {
+            price: 10020,
+            side: Side::Bid,
+        });
+        ob.execute(Side::Ask, 80, &mut FullFillModel);
+        ob.apply_update(Update::Set {
+            price: 10060,
+            quantity: 5,
+            side: Side::Ask,
+        });
+
+        assert_eq!(ob.get_best_bid(), Some(10000));
+        assert_eq!(ob.session_extremes(), (Some(10020), Some(10050)));
+
+        ob.reset_session();
+        assert_eq!(ob.session_extremes(), (None, None));
+        ob.apply_update(Update::Set {
+            price: 9990,
+            quantity: 5,
+            side: Side::Bid,
+        });
+        assert_eq!(ob.session_extremes().0, Some(10000));
+    }
+
+    #[test]
+    fn test_fillable_within_slippage() {
+        let mut ob = book(
+            &[(10000, 100), (9900, 50)],
+            &[(10050, 80), (10100, 120), (10150, 7)],
+        );
+
+        assert_eq!(ob.fillable_within_slippage(Side::Ask, 50), 200);
+        assert_eq!(ob.fillable_within_slippage(Side::Ask, 49), 80);
+        assert_eq!(ob.fillable_within_slippage(Side::Bid, 99), 100);
+        ob.set_tick_size(50);
+        assert_eq!(ob.fillable_within_slippage(Side::Ask, 2), 207);
+        assert_eq!(
+            OrderBookImpl::new().fillable_within_slippage(Side::Bid, 10),
+            0
+        );
+    }
+
+    #[test]
+    fn test_ring_snapshot() {
+        let ob = book(&[(10000, 100), (9990, 50), (9980, 7)], &[(10050, 80)]);
+
+        let snap = ob.ring_snapshot::<5>();
+        assert_eq!(snap.bid_count, 3);
+        assert_eq!(snap.ask_count, 1);
+        assert_eq!(
+            snap.bids[..3],
+            [
+                PriceLevel::from((10000, 100)),
+                PriceLevel::from((9990, 50)),
+                PriceLevel::from((9980, 7)),
+            ]
+        );
+        assert_eq!(snap.bids[3], PriceLevel::default());
+        assert_eq!(snap.asks[0], PriceLevel::from((10050, 80)));
+
+        let shallow = ob.ring_snapshot::<2>();
+        assert_eq!(shallow.bid_count, 2);
+        assert_eq!(shallow.bids[1], PriceLevel::from((9990, 50)));
+    }
+
+    #[test]
+    #[cfg(feature = "mid-history")]
+    fn test_mid_volatility() {
+        let mut ob = book(&[(10000, 100)], &[(10050, 80)]);
+        assert_eq!(ob.mid_volatility(), Some(0.0));
+
+        for (bid, ask) in [(10010, 10060), (9990, 10040), (10020, 10070)] {
+            ob.apply_update(Update::Set {
+                price: bid,
+                quantity: 1,
+                side: Side::Bid,
+            });
+            ob.apply_update(Update::Set {
+                price: ask,
+                quantity: 1,
+                side: Side::Ask,
+            });
+        }
+        assert!(ob.mid_volatility().unwrap() > 0.0);
+
+        let mut flat = book(&[(10000, 100)], &[(10050, 80)]);
+        for qty in 1..10 {
+            flat.apply_update(Update::Set {
+                price: 10000,
+                quantity: qty,
+                side: Side::Bid,
+            });
+        }
+        assert_eq!(flat.mid_volatility(), Some(0.0));
+        assert_eq!(OrderBookImpl::new().mid_volatility(), None);
+    }
+
+    #[test]
+    fn test_apply_batch_transactional() {
+        let mut ob = book(&[(10000, 100), (9990, 50)], &[(10050, 80)]);
+        let before = all_levels(&ob);
+        let batch = [
+            Update::Set {
+                price: 10010,
+                quantity: 5,
+                side: Side::Bid,
+            },
+            Update::Remove {
+                price: 10000,
+                side: Side::Bid,
+            },
+            Update::Set {
+                price: 10050,
+                quantity: 1,
+                side: Side::Ask,
+            },
+            Update::Remove {
+                price: 10060,
+                side: Side::Ask,
+            },
+            Update::Set {
+                price: 10040,
+                quantity: 1,
+                side: Side::Ask,
+            },
+        ];
+
+        assert_eq!(
+            ob.apply_batch_transactional(&batch),
+            Err((
+                3,
+                OrderBookError::LevelNotFound {
+                    price: 10060,
+                    side: Side::Ask
+                }
+            ))
+        );
+        assert_eq!(all_levels(&ob), before);
+        assert_eq!(ob.get_best_bid(), Some(10000));
+        assert_eq!(ob.get_total_quantity(Side::Bid), 150);
+        assert_eq!(ob.get_total_quantity(Side::Ask), 80);
+
+        assert_eq!(ob.apply_batch_transactional(&batch[..3]), Ok(()));
+        assert_eq!(ob.get_best_bid(), Some(10010));
+    }
+
+    #[test]
+    fn test_apply_batch_transactional_rolls_back_resolved_levels() {
+        let mut ob = book(&[(10000, 100), (9990, 50)], &[(10010, 40), (10020, 60)]);
+        ob.set_cross_policy(CrossPolicy::Resolve);
+        let before = all_levels(&ob);
+        let batch = [
+            // Clears both asks at or below 10020
+            Update::Set {
+                price: 10020,
+                quantity: 10,
+                side: Side::Bid,
+            },
+            Update::Remove {
+                price: 9000,
+                side: Side::Bid,
+            },
+        ];
+
+        assert_eq!(
+            ob.apply_batch_transactional(&batch),
+            Err((
+                1,
+                OrderBookError::LevelNotFound {
+                    price: 9000,
+                    side: Side::Bid
+                }
+            ))
+        );
+        assert_eq!(all_levels(&ob), before);
+        assert_eq!(ob.get_best_ask(), Some(10010));
+        assert_eq!(ob.cross_interventions(), 0);
+        assert!(ob.self_check().is_healthy());
+
+        assert_eq!(ob.apply_batch_transactional(&batch[..1]), Ok(()));
+        assert_eq!(ob.get_best_ask(), None);
+        assert_eq!(ob.cross_interventions(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn test_bbo_dwell() {
+        let mut ob = OrderBookImpl::new();
+        ob.set_clock(100);
+        ob.apply_update(Update::Set {
+            price: 10000,
+            quantity: 100,
+            side: Side::Bid,
+        });
+        assert_eq!(ob.bbo_dwell(100), 0);
+
+        ob.set_clock(150);
+        ob.apply_update(Update::Set {
+            price: 9990,
+            quantity: 5,
+            side: Side::Bid,
+        });
+        ob.apply_update(Update::Set {
+            price: 10000,
+            quantity: 7,
+            side: Side::Bid,
+        });
+        assert_eq!(ob.bbo_dwell(150), 50);
+        assert_eq!(ob.bbo_dwell(400), 300);
+
+        ob.set_clock(500);
+        ob.apply_update(Update::Set {
+            price: 10050,
+            quantity: 1,
+            side: Side::Ask,
+        });
+        assert_eq!(ob.bbo_dwell(520), 20);
+    }
+
+    #[test]
+    fn test_fill_ratio() {
+        let ob = book(&[(10000, 100), (9990, 50)], &[]);
+
+        assert_eq!(ob.fill_ratio(Side::Bid, 300), 0.5);
+        assert_eq!(ob.fill_ratio(Side::Bid, 150), 1.0);
+        assert_eq!(ob.fill_ratio(Side::Bid, 0), 1.0);
+        assert_eq!(ob.fill_ratio(Side::Ask, 10), 0.0);
+    }
+
+    #[test]
+    fn test_is_profitable_to_quote() {
+        let mut ob = book(&[(10000, 100)], &[(10025, 80)]);
+        ob.set_tick_size(25);
+
+        assert_eq!(ob.is_profitable_to_quote(2), Some(false));
+        assert_eq!(ob.is_profitable_to_quote(1), Some(true));
+        assert_eq!(book(&[(10000, 1)], &[]).is_profitable_to_quote(1), None);
+    }
+
+    #[test]
+    #[cfg(feature = "bumpalo")]
+    fn test_snapshot_in_arena() {
+        let arena = bumpalo::Bump::new();
+        let mut ob = book(&[(10000, 100), (9990, 50)], &[(10050, 80)]);
+
+        let (bids_a, asks_a) = ob.snapshot_in(&arena, 5);
+        ob.apply_update(Update::Set {
+            price: 10010,
+            quantity: 1,
+            side: Side::Bid,
+        });
+        let (bids_b, asks_b) = ob.snapshot_in(&arena, 2);
+
+        assert_eq!(bids_a, ob_levels(&[(10000, 100), (9990, 50)]));
+        assert_eq!(asks_a, ob_levels(&[(10050, 80)]));
+        assert_eq!(bids_b, ob_levels(&[(10010, 1), (10000, 100)]));
+        assert_eq!(asks_b, asks_a);
+        assert_eq!(bids_a.len(), 2);
+
+        fn ob_levels(levels: &[(Price, Quantity)]) -> Vec<PriceLevel> {
+            levels.iter().copied().map(PriceLevel::from).collect()
+        }
+    }
+
+    #[test]
+    fn test_fair_value() {
+        let symmetric = book(&[(10000, 100), (9990, 50)], &[(10010, 100), (10020, 50)]);
+        assert_eq!(symmetric.fair_value(5), symmetric.mid_price());
+
+        // Heavy bid pulls the estimate below the 10005 mid
+        let lopsided = book(&[(10000, 300)], &[(10010, 100)]);
+        assert_eq!(lopsided.fair_value(5), Some(10002.5));
+
+        let one_sided = book(&[(10000, 100), (9990, 100)], &[]);
+        assert_eq!(one_sided.fair_value(1), Some(10000.0));
+        assert_eq!(one_sided.fair_value(2), Some(9995.0));
+        assert_eq!(OrderBookImpl::new().fair_value(5), None);
+    }
+
+    #[test]
+    fn test_coalesce_runs() {
+        let mut ob = book(
+            &[(10000, 5), (9999, 5), (9998, 5), (9997, 7), (9995, 7)],
+            &[(10010, 3), (10011, 3)],
+        );
+
+        assert_eq!(
+            ob.coalesce_runs(Side::Bid),
+            vec![(10000, 9998, 5), (9997, 9997, 7), (9995, 9995, 7)]
+        );
+        assert_eq!(ob.coalesce_runs(Side::Ask), vec![(10010, 10011, 3)]);
+
+        // Adjacency follows the tick size
+        ob.set_tick_size(2);
+        assert_eq!(
+            ob.coalesce_runs(Side::Bid),
+            vec![
+                (10000, 10000, 5),
+                (9999, 9999, 5),
+                (9998, 9998, 5),
+                (9997, 9995, 7)
+            ]
+        );
     }
 
-    fn apply_update(&mut self, update: Update) {
-        panic!("Todo")
+    #[test]
+    fn test_slot_of() {
+        let mut ob = OrderBookImpl::new();
+
+        assert_eq!(ob.slot_of(10000), Some(10000 & MASK));
+        assert_eq!(ob.slot_of(70000), None);
+        assert_eq!(ob.slot_of(-1), None);
+
+        ob.recenter(60000);
+        assert_eq!(ob.slot_of(70000), Some(10000));
+        assert_eq!(ob.slot_of(10000), None);
     }
 
-    fn get_spread(&self) -> Option<Price> {
-        panic!("Todo")
+    #[test]
+    fn test_qty_to_move_bbo() {
+        let mut ob = book(
+            &[(10000, 100), (9999, 40), (9990, 60)],
+            &[(10050, 80), (10100, 120)],
+        );
+
+        assert_eq!(ob.qty_to_move_bbo(Side::Ask, 50), Some(80));
+        assert_eq!(ob.qty_to_move_bbo(Side::Ask, 51), Some(200));
+        assert_eq!(ob.qty_to_move_bbo(Side::Bid, 5), Some(140));
+        assert_eq!(ob.qty_to_move_bbo(Side::Bid, 0), Some(0));
+
+        ob.set_tick_size(5);
+        assert_eq!(ob.qty_to_move_bbo(Side::Ask, 10), Some(80));
+        assert_eq!(book(&[], &[]).qty_to_move_bbo(Side::Ask, 1), None);
     }
 
-    fn get_best_bid(&self) -> Option<Price> {
-        panic!("Todo")
+    #[test]
+    fn test_self_check() {
+        let healthy = book(&[(10000, 100), (9990, 50)], &[(10050, 80)]);
+        let report = healthy.self_check();
+        assert!(report.is_healthy());
+        assert_eq!(report.checks.len(), 5);
+
+        let mut phantom = book(&[(10000, 100), (9990, 50)], &[(10050, 80)]);
+        let slot = phantom.slot(9995);
+        set_bit(
+            &mut phantom.root_bid,
+            &mut phantom.l2_bid,
+            &mut phantom.l1_bid,
+            slot,
+        );
+        let failed: Vec<_> = phantom.self_check().failures().map(|c| c.name).collect();
+        assert_eq!(failed, ["no_empty_active_slots"]);
+
+        let mut drifted = book(&[(10000, 100)], &[(10050, 80)]);
+        drifted.total_ask_qty += 1;
+        drifted.best_bid = Some(9990);
+        let report = drifted.self_check();
+        let failed: Vec<_> = report.failures().collect();
+        assert_eq!(failed.len(), 2);
+        assert_eq!(failed[0].name, "total_quantity");
+        assert_eq!(
+            failed[0].details,
+            ["Ask: cached total 81, levels sum to 80"]
+        );
+        assert_eq!(failed[1].name, "best_price");
     }
 
-    fn get_best_ask(&self) -> Option<Price> {
-        panic!("Todo")
+    #[test]
+    fn test_apply_ladder() {
+        let mut ob = book(
+            &[(10000, 100), (9990, 50), (9980, 40), (9900, 10)],
+            &[(10050, 80)],
+        );
+
+        ob.apply_ladder(Side::Bid, &[(10010, 5), (10000, 60), (9980, 40)]);
+
+        let [bids, asks] = all_levels(&ob);
+        assert_eq!(bids, [(10010, 5), (10000, 60), (9980, 40), (9900, 10)]);
+        assert_eq!(asks, [(10050, 80)]);
+        assert_eq!(ob.get_best_bid(), Some(10010));
+        assert_eq!(ob.get_total_quantity(Side::Bid), 115);
+
+        ob.apply_ladder(Side::Bid, &[]);
+        assert_eq!(all_levels(&ob)[0].len(), 4);
     }
 
-    fn get_quantity_at(&self, price: Price, side: Side) -> Option<Quantity> {
-        panic!("Todo")
+    #[test]
+    fn test_liquidity_penalty() {
+        let ob = book(&[(10000, 50), (9990, 100)], &[(10020, 50), (10040, 100)]);
+
+        // Buy 100: 50@10020 + 50@10040 = 10030; sell 100: 50@10000 + 50@9990 = 9995
+        assert_eq!(ob.liquidity_penalty(100), Some(35.0));
+        assert_eq!(ob.liquidity_penalty(50), Some(20.0));
+        assert_eq!(ob.liquidity_penalty(151), None);
+        assert_eq!(book(&[(10000, 50)], &[]).liquidity_penalty(10), None);
     }
 
-    fn get_top_levels(&self, side: Side, n: usize) -> Vec<(Price, Quantity)> {
-        panic!("Todo")
+    #[test]
+    fn test_checksum() {
+        assert_eq!(crc32_extend(0, b"123456789"), 0xCBF4_3926);
+        assert_eq!(
+            crc32_extend(crc32_extend(0, b"1234"), b"56789"),
+            0xCBF4_3926
+        );
+
+        let a = book(&[(10000, 100), (9990, 50)], &[(10050, 80)]);
+        let b = book(&[(10000, 100)], &[(9990, 50), (10050, 80)]);
+        assert_ne!(a.checksum(10), b.checksum(10));
+        assert_eq!(
+            a.checksum(1),
+            book(&[(10000, 100)], &[(10050, 80)]).checksum(1)
+        );
+        assert_ne!(a.checksum(1), a.checksum(2));
     }
 
-    fn get_total_quantity(&self, side: Side) -> Quantity {
-        panic!("Todo")
+    #[test]
+    fn test_find_first_divergence() {
+        let updates: Vec<(u64, Update)> = (1..=20)
+            .map(|seq| {
+                let side = if seq % 2 == 0 { Side::Bid } else { Side::Ask };
+                let price = match side {
+                    Side::Bid => 10000 - (seq as Price % 5) * 10,
+                    Side::Ask => 10050 + (seq as Price % 5) * 10,
+                };
+                let update = Update::Set {
+                    price,
+                    quantity: seq * 10,
+                    side,
+                };
+                (seq, update)
+            })
+            .collect();
+
+        let mut reference = OrderBookImpl::new();
+        let mut checkpoints = vec![(0, reference.checksum(10))];
+        for (seq, update) in &updates {
+            reference.apply_update(update.clone());
+            if seq % 5 == 0 {
+                checkpoints.push((*seq, reference.checksum(10)));
+            }
+        }
+
+        assert_eq!(
+            OrderBookImpl::new().find_first_divergence(&updates, &checkpoints, 10),
+            None
+        );
+
+        let mut corrupted = updates.clone();
+        corrupted[11].1 = Update::Remove {
+            price: 9980,
+            side: Side::Bid,
+        };
+        assert_eq!(
+            OrderBookImpl::new().find_first_divergence(&corrupted, &checkpoints, 10),
+            Some(15)
+        );
+    }
+
+    #[test]
+    fn test_bitmaps_match_active_slots() {
+        let ob = book(&[(10000, 1), (9990, 1), (70, 1)], &[(10050, 1), (65535, 1)]);
+
+        for side in [Side::Bid, Side::Ask] {
+            let (root, l2, l1) = ob.bitmaps(side);
+            let mut rebuilt = Vec::new();
+            for w2 in (0..64).filter(|w2| root >> w2 & 1 != 0) {
+                for b2 in (0..64).filter(|b2| l2[w2] >> b2 & 1 != 0) {
+                    let w1 = w2 * 64 + b2;
+                    rebuilt.extend(
+                        (0..64)
+                            .filter(|b| l1[w1] >> b & 1 != 0)
+                            .map(|b| w1 * 64 + b),
+                    );
+                }
+            }
+
+            let mut active = ob.active_slots(side);
+            active.sort_unstable();
+            assert_eq!(rebuilt, active);
+        }
+        assert_eq!(ob.active_slots(Side::Bid), [10000, 9990, 70]);
+        assert_eq!(ob.active_slots(Side::Ask), [10050, 65535]);
+    }
+
+    #[test]
+    #[cfg(feature = "mid-history")]
+    fn test_twap_mid() {
+        let mut ob = OrderBookImpl::new();
+        assert_eq!(ob.twap_mid(1_000, 0), None);
+
+        ob.set_clock(0);
+        ob.apply_update(Update::Set {
+            price: 9000,
+            quantity: 1,
+            side: Side::Bid,
+        });
+        ob.apply_update(Update::Set {
+            price: 9100,
+            quantity: 1,
+            side: Side::Ask,
+        });
+        // Mid 9050 holds until 900, then 10050 for the last 100
+        ob.set_clock(900);
+        ob.apply_update(Update::Set {
+            price: 10100,
+            quantity: 1,
+            side: Side::Ask,
+        });
+        ob.apply_update(Update::Set {
+            price: 10000,
+            quantity: 1,
+            side: Side::Bid,
+        });
+        ob.apply_update(Update::Remove {
+            price: 9100,
+            side: Side::Ask,
+        });
+
+        assert_eq!(ob.twap_mid(1_000, 1_000), Some(9150.0));
+        // Only the second half of the window: 400 at 9050, 100 at 10050
+        assert_eq!(
+            ob.twap_mid(500, 1_000),
+            Some((400.0 * 9050.0 + 100.0 * 10050.0) / 500.0)
+        );
+        assert_eq!(ob.twap_mid(0, 1_000), Some(10050.0));
+    }
+
+    #[test]
+    fn test_order_flow_imbalance() {
+        let prev = book(&[(10000, 100)], &[(10050, 80)]);
+        let ofi = |curr: &OrderBookImpl| OrderBookImpl::order_flow_imbalance(&prev, curr);
+
+        // Same prices: size changes, bid +20, ask -30
+        assert_eq!(ofi(&book(&[(10000, 120)], &[(10050, 50)])), 20.0 + 30.0);
+        // Bid up: new bid size; ask down: new ask size counts against
+        assert_eq!(ofi(&book(&[(10010, 40)], &[(10040, 10)])), 40.0 - 10.0);
+        // Bid down: old bid size lost; ask up: old ask size removed
+        assert_eq!(ofi(&book(&[(9990, 500)], &[(10060, 500)])), -100.0 + 80.0);
+        // Side vanishing / appearing
+        assert_eq!(ofi(&book(&[], &[(10050, 80)])), -100.0);
+        assert_eq!(
+            OrderBookImpl::order_flow_imbalance(&OrderBookImpl::new(), &prev),
+            100.0 - 80.0
+        );
+        assert_eq!(ofi(&prev), 0.0);
+    }
+
+    #[test]
+    fn test_log_depth_profile() {
+        // Mid 10000; ln(9900 / 10000) = -0.01005, ln(9000 / 10000) = -0.105
+        let ob = book(
+            &[(9999, 10), (9990, 20), (9900, 30), (9000, 40), (5000, 50)],
+            &[(10001, 5)],
+        );
+
+        assert_eq!(ob.log_depth_profile(Side::Bid, 4, 0.2), [60, 0, 40, 0]);
+        assert_eq!(ob.log_depth_profile(Side::Bid, 2, 0.01), [30, 0]);
+        assert_eq!(ob.log_depth_profile(Side::Ask, 3, 0.1), [5, 0, 0]);
+        assert!(
+            book(&[(9999, 10)], &[])
+                .log_depth_profile(Side::Bid, 4, 0.2)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "timestamps")]
+    fn test_update_rate_at() {
+        let mut ob = OrderBookImpl::new();
+        ob.set_clock(500_000);
+        ob.apply_update(Update::Set {
+            price: 9990,
+            quantity: 50,
+            side: Side::Bid,
+        });
+        for i in 0..10 {
+            ob.set_clock(1_000_000 + i * 1_000);
+            ob.apply_update(Update::Set {
+                price: 10000,
+                quantity: 100 + i,
+                side: Side::Bid,
+            });
+        }
+        let now = 1_010_000;
+
+        // 10 updates and 1 update within the last millisecond
+        assert_eq!(
+            ob.update_rate_at(10000, Side::Bid, 1_000_000, now),
+            10_000.0
+        );
+        assert_eq!(ob.update_rate_at(9990, Side::Bid, 1_000_000, now), 1_000.0);
+        assert_eq!(ob.update_rate_at(9990, Side::Bid, 100_000, now), 0.0);
+        assert_eq!(ob.update_rate_at(10000, Side::Ask, 1_000_000, now), 0.0);
+    }
+
+    #[test]
+    #[cfg(feature = "timestamps")]
+    fn test_update_history_follows_live_levels() {
+        let mut ob = book(&[(10000, 100)], &[(10050, 80)]);
+        ob.set_clock(1_000);
+        ob.apply_update(Update::Set {
+            price: 10000,
+            quantity: 90,
+            side: Side::Bid,
+        });
+        ob.record_trade(10050, Side::Ask, 10);
+        ob.record_trade(10060, Side::Ask, 10);
+
+        // A market walking up leaves no history behind
+        for i in 0..1_000 {
+            let price = 20_000 + i * 10;
+            ob.apply_update(Update::Set {
+                price,
+                quantity: 5,
+                side: Side::Ask,
+            });
+            ob.apply_updates(&[Update::Remove {
+                price,
+                side: Side::Ask,
+            }]);
+        }
+        assert_eq!(ob.ask_update_times.len(), 1);
+        assert_eq!(ob.ask_trades.len(), 1);
+
+        // Moving the window keeps it
+        ob.recenter(5_000);
+        assert_eq!(ob.update_rate_at(10000, Side::Bid, 1_000, 1_000), 1e6);
+        assert_eq!(ob.last_update_at(10000, Side::Bid), Some(1_000));
+
+        ob.execute(Side::Ask, 80, &mut FullFillModel);
+        assert_eq!(ob.last_update_at(10050, Side::Ask), None);
+        assert_eq!(ob.estimated_time_to_fill(10050, Side::Ask, 1), None);
+        assert!(ob.ask_update_times.is_empty() && ob.ask_trades.is_empty());
+    }
+
+    #[test]
+    fn test_to_grid() {
+        let mut ob = book(&[(10000, 100), (9998, 50)], &[(10002, 80), (10004, 10)]);
+
+        assert_eq!(ob.to_grid(10001, 3), [50.0, 0.0, 100.0, -80.0, 0.0, -10.0]);
+        assert_eq!(ob.to_grid(10001, 1), [100.0, -80.0]);
+
+        ob.set_tick_size(2);
+        assert_eq!(ob.to_grid(10000, 2), [0.0, 50.0, -80.0, -10.0]);
+        assert!(ob.to_grid(10000, 0).is_empty());
+    }
+
+    #[test]
+    fn test_crossing_pressure() {
+        assert_eq!(
+            book(&[(10000, 300)], &[(10050, 100)]).crossing_pressure(),
+            Some(0.5)
+        );
+        assert_eq!(
+            book(&[(10000, 100)], &[(10050, 300)]).crossing_pressure(),
+            Some(-0.5)
+        );
+        assert_eq!(
+            book(&[(10000, 100)], &[(10050, 100)]).crossing_pressure(),
+            Some(0.0)
+        );
+        assert_eq!(book(&[(10000, 100)], &[]).crossing_pressure(), None);
+    }
+
+    #[test]
+    fn test_sample_levels() {
+        let bids: Vec<_> = (0..100)
+            .map(|i| (10000 - i * 10, i as Quantity + 1))
+            .collect();
+        let ob = book(&bids, &[(10050, 1)]);
+
+        let sample = ob.sample_levels(Side::Bid, 10, 42);
+        assert_eq!(sample.len(), 10);
+        assert_eq!(sample, ob.sample_levels(Side::Bid, 10, 42));
+        assert_ne!(sample, ob.sample_levels(Side::Bid, 10, 43));
+        assert!(sample.windows(2).all(|w| w[0].0 > w[1].0));
+        assert!(sample.iter().all(|level| bids.contains(level)));
+
+        assert_eq!(ob.sample_levels(Side::Ask, 10, 42), [(10050, 1)]);
+        assert_eq!(ob.sample_levels(Side::Bid, 1000, 7).len(), 100);
+        assert!(ob.sample_levels(Side::Bid, 0, 7).is_empty());
+    }
+
+    #[test]
+    fn test_sample_levels_overflow() {
+        let far = 10000 + 10 * CAP as Price;
+
+        let asks: Vec<_> = (0..4).map(|i| (far + i * 10, 1)).collect();
+        let mut ob = book(&[(10000, 1)], &[]);
+        for &(price, qty) in &asks {
+            ob.set_level(Side::Ask, price, qty);
+        }
+        ob.set_level(Side::Bid, 10000, 0);
+        assert_eq!(ob.sample_levels(Side::Ask, 4, 42), asks);
+
+        // Three bids far below the window plus one inside it
+        let mut ob = book(&[(far, 1)], &[]);
+        for i in 1..4 {
+            ob.set_level(Side::Bid, 10000 + i * 10, 1);
+        }
+        assert_eq!(ob.sample_levels(Side::Bid, 4, 42).len(), 4);
+        assert_eq!(ob.sample_levels(Side::Bid, 2, 42).len(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn test_max_best_recompute_steps() {
+        // 10000 and 10001 share an L1 word; 9000 is in another L1 word of the
+        // same L2 word; 100 is under a different root bit
+        let mut ob = book(&[(10001, 1), (10000, 1), (9000, 1), (100, 1)], &[]);
+        assert_eq!(ob.max_best_recompute_steps(), 0);
+
+        let remove = |ob: &mut OrderBookImpl, price| {
+            ob.apply_update(Update::Remove {
+                price,
+                side: Side::Bid,
+            })
+        };
+        remove(&mut ob, 9000);
+        assert_eq!(ob.max_best_recompute_steps(), 0);
+        remove(&mut ob, 10001);
+        assert_eq!(ob.max_best_recompute_steps(), 1);
+        ob.apply_update(Update::Set {
+            price: 9000,
+            quantity: 1,
+            side: Side::Bid,
+        });
+        remove(&mut ob, 10000);
+        assert_eq!(ob.max_best_recompute_steps(), 2);
+        remove(&mut ob, 9000);
+        assert_eq!(ob.max_best_recompute_steps(), 3);
+
+        ob.reset_session();
+        assert_eq!(ob.max_best_recompute_steps(), 0);
+    }
+
+    #[test]
+    fn test_apply_packed() {
+        fn record(op: u8, side: u8, price: i64, qty: i64) -> Vec<u8> {
+            let mut rec = vec![op, side];
+            rec.extend(price.to_le_bytes());
+            rec.extend(qty.to_le_bytes());
+            rec
+        }
+        let mut bytes = record(0, 0, 10000, 100);
+        bytes.extend(record(0, 1, 10050, 80));
+        bytes.extend(record(1, 0, 10000, 0));
+
+        let mut ob = book(&[(9990, 50)], &[]);
+        assert_eq!(ob.apply_packed(&bytes), Ok(3));
+        assert_eq!(all_levels(&ob), [vec![(9990, 50)], vec![(10050, 80)]]);
+
+        assert_eq!(
+            ob.apply_packed(&bytes[..20]),
+            Err(PackedError::Truncated { len: 20 })
+        );
+        assert_eq!(all_levels(&ob)[0], [(9990, 50)]);
+
+        let mut bad = record(0, 0, 9980, 5);
+        bad.extend(record(0, 2, 9970, 5));
+        assert_eq!(
+            ob.apply_packed(&bad),
+            Err(PackedError::InvalidSide { record: 1, side: 2 })
+        );
+        assert_eq!(ob.get_best_bid(), Some(9990));
+        assert_eq!(ob.get_quantity_at(9980, Side::Bid), Some(5));
+        assert_eq!(
+            ob.apply_packed(&record(7, 0, 9980, 5)),
+            Err(PackedError::InvalidOp { record: 0, op: 7 })
+        );
+        assert_eq!(
+            ob.apply_packed(&record(0, 0, 9980, -5)),
+            Err(PackedError::NegativeQuantity {
+                record: 0,
+                quantity: -5
+            })
+        );
+    }
+
+    #[test]
+    fn test_skewed_quotes() {
+        let ob = book(&[(10000, 100)], &[(10050, 80)]);
+
+        // Mid 10025
+        assert_eq!(ob.skewed_quotes(0, 0.5, 5), Some((10020, 10030)));
+        assert_eq!(ob.skewed_quotes(0, 0.5, 10), Some((10020, 10030)));
+        // Long 4 units at half a tick each: centre 10015
+        assert_eq!(ob.skewed_quotes(4, 0.5, 5), Some((10010, 10020)));
+        // Short skews upwards
+        assert_eq!(ob.skewed_quotes(-4, 0.5, 5), Some((10030, 10040)));
+
+        assert_eq!(ob.skewed_quotes(0, 0.5, 0), None);
+        assert_eq!(book(&[(10000, 100)], &[]).skewed_quotes(0, 0.5, 5), None);
+    }
+
+    #[test]
+    fn test_find_phantom_slots() {
+        let mut ob = book(&[(10000, 100), (9990, 50)], &[(10050, 80)]);
+        ob.apply_update(Update::Remove {
+            price: 9990,
+            side: Side::Bid,
+        });
+        assert!(ob.find_phantom_slots(Side::Bid).is_empty());
+        assert!(ob.find_phantom_slots(Side::Ask).is_empty());
+
+        // Bit set without writing the quantity: slot 9990 still holds its stale price
+        let slot = ob.slot(9990);
+        set_bit(&mut ob.root_bid, &mut ob.l2_bid, &mut ob.l1_bid, slot);
+        assert_eq!(ob.find_phantom_slots(Side::Bid), [slot]);
+        assert!(ob.find_phantom_slots(Side::Ask).is_empty());
+    }
+
+    #[test]
+    fn test_depth_curve_by_notional() {
+        let ob = book(&[(100, 10), (90, 10), (80, 50)], &[(110, 10), (120, 100)]);
+
+        // Bid notional: 1000 at 100, 900 at 90, 4000 at 80
+        assert_eq!(
+            ob.depth_curve_by_notional(Side::Bid, 1000, 10),
+            [(100, 10), (80, 22), (80, 34), (80, 47), (80, 59)]
+        );
+        assert_eq!(
+            ob.depth_curve_by_notional(Side::Bid, 1000, 2),
+            [(100, 10), (80, 22)]
+        );
+
+        let asks = ob.depth_curve_by_notional(Side::Ask, 500, 20);
+        assert_eq!(asks.len(), 20);
+        assert!(asks.windows(2).all(|w| w[0].0 <= w[1].0 && w[0].1 < w[1].1));
+        assert!(ob.depth_curve_by_notional(Side::Ask, 0, 5).is_empty());
+    }
+
+    #[test]
+    fn test_decompose_mid_change() {
+        let prev = book(&[(10000, 100)], &[(10050, 100)]);
+
+        // Whole touch up 10 with sizes unchanged: all price
+        let shifted = book(&[(10010, 100)], &[(10060, 100)]);
+        assert_eq!(
+            OrderBookImpl::decompose_mid_change(&prev, &shifted),
+            Some((10.0, 0.0))
+        );
+
+        // Same prices, bid size triples: weighted mid 10037.5, all size
+        let rebalanced = book(&[(10000, 300)], &[(10050, 100)]);
+        assert_eq!(
+            OrderBookImpl::decompose_mid_change(&prev, &rebalanced),
+            Some((0.0, 12.5))
+        );
+
+        // Both at once: mid +10, weighted mid 10047.5 - 10025 = 22.5
+        let both = book(&[(10010, 300)], &[(10060, 100)]);
+        assert_eq!(
+            OrderBookImpl::decompose_mid_change(&prev, &both),
+            Some((10.0, 12.5))
+        );
+
+        let one_sided = book(&[(10000, 100)], &[]);
+        assert_eq!(OrderBookImpl::decompose_mid_change(&prev, &one_sided), None);
+    }
+
+    #[test]
+    fn test_apply_provisional() {
+        let mut ob = OrderBookImpl::new();
+        ob.apply_sequenced(
+            1,
+            Update::Set {
+                price: 10000,
+                quantity: 100,
+                side: Side::Bid,
+            },
+        );
+        ob.apply_sequenced(
+            3,
+            Update::Set {
+                price: 10050,
+                quantity: 80,
+                side: Side::Ask,
+            },
+        );
+        assert!(ob.is_desynced());
+        assert!(!ob.is_provisional());
+
+        ob.apply_provisional(Update::Set {
+            price: 10050,
+            quantity: 80,
+            side: Side::Ask,
+        });
+        ob.apply_provisional(Update::Set {
+            price: 10010,
+            quantity: 5,
+            side: Side::Bid,
+        });
+        assert!(ob.is_provisional());
+        assert_eq!(ob.provisional_update_count(), 2);
+        assert_eq!(ob.get_spread(), Some(40));
+
+        ob.apply_snapshot(ExchangeSnapshot {
+            seq: 4,
+            bids: vec![(10000, 100)],
+            asks: vec![(10050, 80)],
+        });
+        assert!(!ob.is_provisional());
+        assert_eq!(ob.provisional_update_count(), 0);
+        assert_eq!(ob.get_spread(), Some(50));
+    }
+
+    #[test]
+    fn test_realized_spread() {
+        let prev = book(&[(10000, 100)], &[(10050, 80)]);
+        let up = book(&[(10030, 100)], &[(10070, 80)]);
+        let rs = |curr, side, price| OrderBookImpl::realized_spread(&prev, curr, side, price);
+
+        // Bought at 10000, mid moved up to 10050
+        assert_eq!(rs(&up, Side::Bid, 10000), Some(100.0));
+        // Sold at 10050 ahead of the same move: adverse selection
+        assert_eq!(rs(&up, Side::Ask, 10050), Some(0.0));
+        assert_eq!(rs(&prev, Side::Ask, 10050), Some(50.0));
+        assert_eq!(rs(&book(&[], &[(10070, 80)]), Side::Bid, 10000), None);
+    }
+
+    #[test]
+    fn test_truncate_to_depth() {
+        let bids: Vec<_> = (0..10)
+            .map(|i| (10000 - i * 10, 10 + i as Quantity))
+            .collect();
+        let mut ob = book(&bids, &[(10050, 80)]);
+
+        ob.truncate_to_depth(Side::Bid, 3);
+
+        let [bids, asks] = all_levels(&ob);
+        assert_eq!(bids, [(10000, 10), (9990, 11), (9980, 12)]);
+        assert_eq!(asks, [(10050, 80)]);
+        assert_eq!(ob.get_total_quantity(Side::Bid), 33);
+        assert_eq!(ob.get_best_bid(), Some(10000));
+
+        ob.truncate_to_depth(Side::Ask, 0);
+        assert_eq!(ob.get_best_ask(), None);
+        assert_eq!(ob.get_total_quantity(Side::Ask), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "timestamps")]
+    fn test_age_histogram() {
+        let mut ob = OrderBookImpl::new();
+        for (ts, price, qty) in [(1_000, 10000, 100), (8_000, 9990, 50), (9_500, 9980, 7)] {
+            ob.set_clock(ts);
+            ob.apply_update(Update::Set {
+                price,
+                quantity: qty,
+                side: Side::Bid,
+            });
+        }
+        assert_eq!(ob.last_update_at(9990, Side::Bid), Some(8_000));
+        assert_eq!(ob.last_update_at(9990, Side::Ask), None);
+
+        // Ages 9000, 2000 and 500 at now = 10000
+        assert_eq!(
+            ob.age_histogram(Side::Bid, 10_000, 1_000, 4),
+            [7, 0, 50, 100]
+        );
+        assert_eq!(ob.age_histogram(Side::Bid, 10_000, 5_000, 2), [57, 100]);
+        assert_eq!(ob.age_histogram(Side::Ask, 10_000, 1_000, 2), [0, 0]);
+    }
+
+    #[test]
+    fn test_is_one_sided() {
+        assert_eq!(book(&[(10000, 1)], &[(10050, 1)]).is_one_sided(), None);
+        assert_eq!(book(&[(10000, 1)], &[]).is_one_sided(), Some(Side::Bid));
+        assert_eq!(book(&[], &[(10050, 1)]).is_one_sided(), Some(Side::Ask));
+        assert_eq!(book(&[], &[]).is_one_sided(), None);
+
+        // The ask lives only in overflow
+        let mut ob = book(&[(100_000, 1)], &[]);
+        ob.set_level(Side::Ask, 100_000 + 10 * CAP as Price, 1);
+        assert_eq!(ob.is_one_sided(), None);
+        ob.set_level(Side::Bid, 100_000, 0);
+        assert_eq!(ob.is_one_sided(), Some(Side::Ask));
+    }
+
+    #[test]
+    fn test_bbo_checksum() {
+        let mut a = book(&[(10000, 100), (9990, 50)], &[(10050, 80)]);
+        let b = book(&[(10000, 100)], &[(10050, 80), (10100, 7)]);
+        assert_eq!(a.bbo_checksum(), b.bbo_checksum());
+        assert_eq!(
+            OrderBookImpl::new().bbo_checksum(),
+            book(&[], &[]).bbo_checksum()
+        );
+
+        let before = a.bbo_checksum();
+        a.apply_update(Update::Set {
+            price: 10000,
+            quantity: 101,
+            side: Side::Bid,
+        });
+        assert_ne!(a.bbo_checksum(), before);
+        a.apply_update(Update::Set {
+            price: 10000,
+            quantity: 100,
+            side: Side::Bid,
+        });
+        assert_eq!(a.bbo_checksum(), before);
+
+        // Removing the touch lands on the next level
+        a.apply_update(Update::Remove {
+            price: 10000,
+            side: Side::Bid,
+        });
+        assert_eq!(
+            a.bbo_checksum(),
+            book(&[(9990, 50)], &[(10050, 80)]).bbo_checksum()
+        );
+        // Changes behind the touch are ignored
+        a.apply_update(Update::Set {
+            price: 9000,
+            quantity: 1,
+            side: Side::Bid,
+        });
+        assert_eq!(
+            a.bbo_checksum(),
+            book(&[(9990, 50)], &[(10050, 80)]).bbo_checksum()
+        );
+    }
+
+    #[test]
+    fn test_display_quantity_at() {
+        let ob = book(&[(10000, 157)], &[(10050, 9)]);
+
+        assert_eq!(ob.display_quantity_at(10000, Side::Bid, 10), Some(150));
+        assert_eq!(ob.display_quantity_at(10000, Side::Bid, 0), Some(157));
+        assert_eq!(ob.display_quantity_at(10050, Side::Ask, 10), Some(0));
+        assert_eq!(ob.display_quantity_at(9990, Side::Bid, 10), None);
+    }
+
+    #[test]
+    #[cfg(feature = "timestamps")]
+    fn test_estimated_time_to_fill() {
+        let mut ob = book(&[(10000, 500)], &[(10050, 80)]);
+        assert_eq!(ob.estimated_time_to_fill(10000, Side::Bid, 100), None);
+
+        // 100 sold into the bid every 1000 units of clock: rate 0.1
+        for t in 0..5 {
+            ob.set_clock(t * 1_000);
+            ob.execute(Side::Bid, 100, &mut FullFillModel);
+            ob.apply_update(Update::Set {
+                price: 10000,
+                quantity: 500,
+                side: Side::Bid,
+            });
+        }
+        ob.set_clock(5_000);
+
+        // 500 trades over 5000: (500 ahead + 100 mine) / 0.1
+        assert_eq!(
+            ob.estimated_time_to_fill(10000, Side::Bid, 100),
+            Some(6_000.0)
+        );
+        assert_eq!(ob.estimated_time_to_fill(10050, Side::Ask, 100), None);
+
+        ob.set_clock(5_000);
+        ob.record_trade(10050, Side::Ask, 40);
+        assert_eq!(ob.estimated_time_to_fill(10050, Side::Ask, 100), None);
+        ob.set_clock(5_400);
+        assert_eq!(ob.estimated_time_to_fill(10050, Side::Ask, 0), Some(800.0));
+    }
+
+    #[test]
+    fn test_best_removal_across_word_boundary() {
+        // 10048 is bit 0 of its L1 word, 10047 bit 63 of the previous one
+        let mut ob = book(
+            &[(10048, 1), (10047, 2), (10040, 3)],
+            &[(10047, 4), (10048, 5), (10050, 6)],
+        );
+
+        ob.apply_update(Update::Remove {
+            price: 10048,
+            side: Side::Bid,
+        });
+        assert_eq!(ob.get_best_bid(), Some(10047));
+        ob.apply_update(Update::Remove {
+            price: 10047,
+            side: Side::Bid,
+        });
+        assert_eq!(ob.get_best_bid(), Some(10040));
+
+        ob.apply_update(Update::Remove {
+            price: 10047,
+            side: Side::Ask,
+        });
+        assert_eq!(ob.get_best_ask(), Some(10048));
+        ob.apply_update(Update::Remove {
+            price: 10048,
+            side: Side::Ask,
+        });
+        assert_eq!(ob.get_best_ask(), Some(10050));
+        assert!(ob.self_check().is_healthy());
+
+        ob.apply_update(Update::Remove {
+            price: 10040,
+            side: Side::Bid,
+        });
+        assert_eq!(ob.get_best_bid(), None);
+    }
+
+    #[test]
+    fn test_scenario_record_replay() {
+        let mut ob = book(&[(10000, 100)], &[(10050, 80)]);
+        ob.start_recording();
+        for i in 0..150 {
+            ob.apply_update(Update::Set {
+                price: 9900 + i % 90,
+                quantity: i as Quantity + 1,
+                side: Side::Bid,
+            });
+        }
+        ob.execute(Side::Bid, 250, &mut FullFillModel);
+        let scenario = ob.stop_recording();
+
+        assert_eq!(scenario.initial.len(), 2);
+        assert_eq!(scenario.checkpoints.len(), 4);
+        assert_eq!(OrderBookImpl::replay_scenario(&scenario), Ok(()));
+        assert!(ob.stop_recording().updates.is_empty());
+
+        let mut buf = Vec::new();
+        scenario.encode(&mut buf);
+        assert_eq!(Scenario::decode(&buf), Ok(scenario.clone()));
+
+        let mut tampered = scenario.clone();
+        tampered.updates[70] = Update::Set {
+            price: 9900,
+            quantity: 7,
+            side: Side::Ask,
+        };
+        let mismatch = OrderBookImpl::replay_scenario(&tampered).unwrap_err();
+        assert_eq!(mismatch.applied, 128);
+        assert_eq!(mismatch.expected, scenario.checkpoints[2].1);
+    }
+
+    #[test]
+    fn test_colliding_prices_tracked_independently() {
+        let alias = 10000 + CAP as Price;
+        let mut ob = book(&[(10000, 100), (alias, 7)], &[(alias + 10, 5)]);
+
+        assert_eq!(ob.get_quantity_at(10000, Side::Bid), Some(100));
+        assert_eq!(ob.get_quantity_at(alias, Side::Bid), Some(7));
+        assert_eq!(ob.get_quantity_at(10000 - CAP as Price, Side::Bid), None);
+        assert_eq!(ob.get_quantity_at(10010, Side::Ask), None);
+        assert_eq!(ob.get_best_bid(), Some(alias));
+        assert_eq!(ob.get_total_quantity(Side::Bid), 107);
+        assert_eq!(
+            ob.get_top_levels(Side::Bid, 10),
+            vec![(alias, 7), (10000, 100)]
+        );
+
+        ob.apply_update(Update::Remove {
+            price: alias,
+            side: Side::Bid,
+        });
+        assert_eq!(ob.get_quantity_at(10000, Side::Bid), Some(100));
+        assert_eq!(ob.get_quantity_at(alias, Side::Bid), None);
+        assert_eq!(ob.get_best_bid(), Some(10000));
+        assert_eq!(ob.get_total_quantity(Side::Bid), 100);
+
+        ob.apply_update(Update::Set {
+            price: alias,
+            quantity: 3,
+            side: Side::Bid,
+        });
+        ob.apply_update(Update::Remove {
+            price: 10000,
+            side: Side::Bid,
+        });
+        assert_eq!(ob.get_quantity_at(alias, Side::Bid), Some(3));
+        assert_eq!(ob.get_quantity_at(10000, Side::Bid), None);
+        assert_eq!(ob.get_top_levels(Side::Bid, 10), vec![(alias, 3)]);
+        assert!(ob.self_check().is_healthy());
+    }
+
+    #[test]
+    fn test_empty_book_anchors_window() {
+        let mut ob = book(&[(100_000, 10)], &[(100_050, 20)]);
+        assert!(ob.slot_of(100_000).is_some());
+        assert!(ob.slot_of(100_050).is_some());
+
+        // Far below the window, and worse than every in-window bid
+        ob.apply_update(Update::Set {
+            price: 10,
+            quantity: 1,
+            side: Side::Bid,
+        });
+        assert_eq!(ob.slot_of(10), None);
+        assert_eq!(
+            ob.get_top_levels(Side::Bid, 10),
+            vec![(100_000, 10), (10, 1)]
+        );
+        ob.apply_update(Update::Remove {
+            price: 100_000,
+            side: Side::Bid,
+        });
+        assert_eq!(ob.get_best_bid(), Some(10));
+        assert_eq!(ob.get_spread(), Some(100_040));
+        assert!(ob.self_check().is_healthy());
+    }
+
+    #[test]
+    fn test_side_depth_ratio() {
+        let ob = book(
+            &[(10000, 300), (9999, 200), (9998, 1000)],
+            &[(10001, 100), (10002, 150), (10003, 5)],
+        );
+
+        assert_eq!(ob.side_depth_ratio(1), Some(3.0));
+        assert_eq!(ob.side_depth_ratio(2), Some(2.0));
+        assert!(ob.side_depth_ratio(10).unwrap() > 1.0);
+        assert_eq!(ob.side_depth_ratio(0), None);
+        assert_eq!(book(&[(10000, 300)], &[]).side_depth_ratio(5), None);
+    }
+
+    #[test]
+    fn test_top_levels_across_words() {
+        // Same L1 word, next L1 word, other L2 words, both ends of the window
+        let bids = [
+            (65535, 1),
+            (60000, 2),
+            (4097, 3),
+            (4095, 4),
+            (130, 5),
+            (64, 6),
+            (63, 7),
+            (0, 8),
+        ];
+        let ob = book(&bids, &[]);
+
+        assert_eq!(ob.get_top_levels(Side::Bid, 3), bids[..3]);
+        assert_eq!(ob.get_top_levels(Side::Bid, 100), bids);
+        let mut asks = bids;
+        asks.reverse();
+        let ob = book(&[], &asks);
+        assert_eq!(ob.get_top_levels(Side::Ask, 5), asks[..5]);
+        assert_eq!(ob.get_top_levels(Side::Ask, 100), asks);
+        assert!(ob.get_top_levels(Side::Bid, 10).is_empty());
+        assert!(ob.get_top_levels(Side::Ask, 0).is_empty());
+    }
+
+    #[test]
+    fn test_top_levels_fewer_than_n() {
+        let ob = book(&[(10000, 100), (5000, 20)], &[(30000, 7)]);
+
+        assert_eq!(
+            ob.get_top_levels(Side::Bid, 10),
+            vec![(10000, 100), (5000, 20)]
+        );
+        assert_eq!(ob.get_top_levels(Side::Ask, 10), vec![(30000, 7)]);
+    }
+
+    #[test]
+    fn test_clear_worse_than() {
+        let mut ob = book(
+            &[(10000, 100), (9950, 50), (9949, 30), (9900, 20)],
+            &[(10010, 40), (10020, 60), (10030, 70)],
+        );
+
+        ob.clear_worse_than(Side::Bid, 9950);
+        assert_eq!(
+            ob.get_top_levels(Side::Bid, 10),
+            vec![(10000, 100), (9950, 50)]
+        );
+        assert_eq!(ob.get_total_quantity(Side::Bid), 150);
+        assert_eq!(ob.get_best_bid(), Some(10000));
+
+        ob.clear_worse_than(Side::Ask, 10015);
+        assert_eq!(ob.get_top_levels(Side::Ask, 10), vec![(10010, 40)]);
+        assert_eq!(ob.get_total_quantity(Side::Ask), 40);
+        assert!(ob.self_check().is_healthy());
+    }
+
+    #[test]
+    fn test_infer_tick_size() {
+        let ob = book(
+            &[(10000, 1), (9975, 1), (9900, 1)],
+            &[(10025, 1), (10100, 1)],
+        );
+        assert_eq!(ob.infer_tick_size(), Some(25));
+
+        let ob = book(&[(10000, 1), (9970, 1)], &[(10045, 1), (10090, 1)]);
+        assert_eq!(ob.infer_tick_size(), Some(15));
+
+        // Only the spread to go on
+        let ob = book(&[(10000, 1)], &[(10006, 1)]);
+        assert_eq!(ob.infer_tick_size(), Some(6));
+
+        assert_eq!(book(&[(10000, 1)], &[]).infer_tick_size(), None);
+        assert_eq!(book(&[(10000, 1)], &[(10000, 1)]).infer_tick_size(), None);
+    }
+
+    #[test]
+    fn test_change_update() {
+        let mut ob = book(&[(9990, 50)], &[(10010, 40)]);
+        let change = |price, delta, side| Update::Change { price, delta, side };
+
+        ob.apply_update(change(10000, 30, Side::Bid));
+        assert_eq!(ob.get_quantity_at(10000, Side::Bid), Some(30));
+        assert_eq!(ob.get_best_bid(), Some(10000));
+        assert_eq!(ob.get_total_quantity(Side::Bid), 80);
+
+        ob.apply_update(change(10000, -10, Side::Bid));
+        assert_eq!(ob.get_quantity_at(10000, Side::Bid), Some(20));
+        assert_eq!(ob.get_total_quantity(Side::Bid), 70);
+
+        // Down to exactly zero at the touch
+        ob.apply_update(change(10000, -20, Side::Bid));
+        assert_eq!(ob.get_quantity_at(10000, Side::Bid), None);
+        assert_eq!(ob.get_best_bid(), Some(9990));
+        assert_eq!(ob.get_total_quantity(Side::Bid), 50);
+
+        // Past zero saturates into a removal
+        ob.apply_update(change(10010, -1000, Side::Ask));
+        assert_eq!(ob.get_best_ask(), None);
+        assert_eq!(ob.get_total_quantity(Side::Ask), 0);
+        assert_eq!(
+            ob.validate_update(&change(10010, -1, Side::Ask)),
+            Err(OrderBookError::LevelNotFound {
+                price: 10010,
+                side: Side::Ask
+            })
+        );
+        assert_eq!(ob.validate_update(&change(10010, 5, Side::Ask)), Ok(()));
+        assert!(ob.self_check().is_healthy());
+
+        let mut buf = Vec::new();
+        let mut last = 0;
+        put_update(&mut buf, &mut last, &change(10010, -7, Side::Ask));
+        let (mut pos, mut last) = (0, 0);
+        assert_eq!(
+            get_update(&buf, &mut pos, &mut last),
+            Ok(change(10010, -7, Side::Ask))
+        );
+        assert_eq!(
+            get_update(&[OP_CHANGE | OP_REMOVE], &mut 0, &mut 0),
+            Err(DecodeError::InvalidOp(OP_CHANGE | OP_REMOVE))
+        );
+    }
+
+    #[test]
+    fn test_execute_with_fill_model() {
+        /// Fills half of whatever rests at each level
+        struct HalfFill;
+
+        impl FillModel for HalfFill {
+            fn consume(&mut self, _: Price, available: Quantity, requested: Quantity) -> Quantity {
+                (available / 2).min(requested)
+            }
+        }
+
+        let mut ob = book(&[(10000, 100), (9990, 60), (9980, 40)], &[]);
+
+        assert_eq!(
+            ob.execute(Side::Bid, 75, &mut HalfFill),
+            vec![(10000, 50), (9990, 25)]
+        );
+        assert_eq!(
+            ob.get_top_levels(Side::Bid, 10),
+            vec![(10000, 50), (9990, 35), (9980, 40)]
+        );
+        assert_eq!(ob.get_total_quantity(Side::Bid), 125);
+
+        // Running out of levels leaves the order partly unfilled
+        assert_eq!(
+            ob.execute(Side::Bid, 1000, &mut HalfFill),
+            vec![(10000, 25), (9990, 17), (9980, 20)]
+        );
+        assert_eq!(ob.get_total_quantity(Side::Bid), 63);
+    }
+
+    #[test]
+    fn test_apply_updates_matches_sequential() {
+        let mut rng = 11;
+        let mut batched = book(&[(10000, 100)], &[(10010, 100)]);
+        let mut sequential = book(&[(10000, 100)], &[(10010, 100)]);
+
+        for _ in 0..200 {
+            let mut packet = Vec::new();
+            for _ in 0..1 + splitmix64(&mut rng) % 300 {
+                let roll = splitmix64(&mut rng);
+                let side = if roll & 1 == 0 { Side::Bid } else { Side::Ask };
+                let price = match side {
+                    Side::Bid => 9900 + (roll >> 8) as Price % 110,
+                    Side::Ask => 10000 + (roll >> 8) as Price % 110,
+                };
+                // 30% remove the current best, as of the sequential book
+                let best = match side {
+                    Side::Bid => sequential.get_best_bid(),
+                    Side::Ask => sequential.get_best_ask(),
+                };
+                let update = match (roll >> 4) % 10 {
+                    0..3 => Update::Remove {
+                        price: best.unwrap_or(price),
+                        side,
+                    },
+                    3 => Update::Change {
+                        price,
+                        delta: (roll >> 40) as i64 % 100 - 50,
+                        side,
+                    },
+                    // Occasionally outside the window
+                    4 if roll >> 60 == 0 => Update::Set {
+                        price: price + CAP as Price,
+                        quantity: 1,
+                        side,
+                    },
+                    _ => Update::Set {
+                        price,
+                        quantity: (roll >> 32) % 50,
+                        side,
+                    },
+                };
+                sequential.apply_update(update.clone());
+                packet.push(update);
+            }
+            batched.apply_updates(&packet);
+
+            assert_eq!(all_levels(&batched), all_levels(&sequential));
+            assert_eq!(batched.get_best_bid(), sequential.get_best_bid());
+            assert_eq!(batched.get_best_ask(), sequential.get_best_ask());
+            for side in [Side::Bid, Side::Ask] {
+                assert_eq!(
+                    batched.get_total_quantity(side),
+                    sequential.get_total_quantity(side)
+                );
+                assert_eq!(batched.bitmaps(side), sequential.bitmaps(side));
+            }
+            assert_eq!(batched.bbo_checksum(), sequential.bbo_checksum());
+            assert_eq!(batched.slot_of(10000), sequential.slot_of(10000));
+        }
+        assert!(batched.self_check().is_healthy());
+    }
+
+    #[test]
+    fn test_verify_and_repair_totals() {
+        let mut ob = book(&[(10000, 100), (9990, 50)], &[(10010, 40)]);
+        assert!(!ob.verify_and_repair_totals());
+
+        ob.total_bid_qty += 7;
+        assert!(ob.verify_and_repair_totals());
+        assert_eq!(ob.get_total_quantity(Side::Bid), 150);
+        assert_eq!(ob.get_total_quantity(Side::Ask), 40);
+        assert!(!ob.verify_and_repair_totals());
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut rng = 3;
+        let mut ob = OrderBookImpl::new();
+        for _ in 0..4000 {
+            let roll = splitmix64(&mut rng);
+            let (side, price) = if roll & 1 == 0 {
+                (Side::Bid, 50_000 - (roll >> 8) as Price % 30_000)
+            } else {
+                (Side::Ask, 50_001 + (roll >> 8) as Price % 30_000)
+            };
+            ob.apply_update(Update::Set {
+                price,
+                quantity: 1 + (roll >> 40) % 1000,
+                side,
+            });
+        }
+        ob.apply_update(Update::Set {
+            price: 200_000,
+            quantity: 9,
+            side: Side::Ask,
+        });
+
+        let bytes = ob.to_snapshot().to_bytes();
+        let snap = Snapshot::from_bytes(&bytes).unwrap();
+        assert_eq!(snap, ob.to_snapshot());
+        let restored = OrderBookImpl::from_snapshot(&snap);
+
+        for side in [Side::Bid, Side::Ask] {
+            assert_eq!(
+                restored.get_top_levels(side, usize::MAX),
+                ob.get_top_levels(side, usize::MAX)
+            );
+            assert_eq!(
+                restored.get_total_quantity(side),
+                ob.get_total_quantity(side)
+            );
+            assert_eq!(restored.bitmaps(side), ob.bitmaps(side));
+        }
+        assert_eq!(restored.get_best_bid(), ob.get_best_bid());
+        assert_eq!(restored.get_best_ask(), ob.get_best_ask());
+        assert_eq!(restored.bbo_checksum(), ob.bbo_checksum());
+        assert!(restored.self_check().is_healthy());
+    }
+
+    #[test]
+    fn test_snapshot_round_trip_keeps_window() {
+        // The best bid, listed first, lies above the window
+        let mut ob = book(&[(10000, 100), (9990, 50)], &[(10050, 80)]);
+        let far = 10000 + 2 * CAP as Price;
+        ob.apply_update(Update::Set {
+            price: far,
+            quantity: 7,
+            side: Side::Bid,
+        });
+        let snap = ob.to_snapshot();
+        assert_eq!(snap.bids[0], (far, 7));
+
+        let restored = OrderBookImpl::from_snapshot(&snap);
+        assert_eq!(restored.to_snapshot(), snap);
+        for price in [far, 10000, 10050] {
+            assert_eq!(restored.slot_of(price), ob.slot_of(price));
+        }
+        for side in [Side::Bid, Side::Ask] {
+            assert_eq!(restored.bitmaps(side), ob.bitmaps(side));
+        }
+        assert!(restored.self_check().is_healthy());
+    }
+
+    #[test]
+    fn test_restored_book_accepts_updates() {
+        let ob = book(&[(10000, 100), (9990, 50)], &[(10010, 40)]);
+        let mut restored = OrderBookImpl::from_snapshot(&ob.to_snapshot());
+
+        restored.apply_update(Update::Remove {
+            price: 10000,
+            side: Side::Bid,
+        });
+        restored.apply_update(Update::Set {
+            price: 10005,
+            quantity: 20,
+            side: Side::Ask,
+        });
+        assert_eq!(restored.get_best_bid(), Some(9990));
+        assert_eq!(restored.get_best_ask(), Some(10005));
+        assert_eq!(restored.get_total_quantity(Side::Bid), 50);
+        assert_eq!(restored.get_total_quantity(Side::Ask), 60);
+    }
+
+    #[test]
+    fn test_snapshot_rejects_malformed_bytes() {
+        let snap = Snapshot {
+            base: 0,
+            bids: vec![(10000, 100), (9990, 50)],
+            asks: vec![(10010, 40)],
+        };
+        let bytes = snap.to_bytes();
+
+        for len in 0..bytes.len() {
+            assert_eq!(
+                Snapshot::from_bytes(&bytes[..len]),
+                Err(DecodeError::Truncated)
+            );
+        }
+        let mut bad = bytes.clone();
+        bad[0] = b'X';
+        assert_eq!(Snapshot::from_bytes(&bad), Err(DecodeError::BadMagic));
+        bad = bytes.clone();
+        bad[SNAPSHOT_MAGIC.len()] = SNAPSHOT_VERSION + 1;
+        assert_eq!(
+            Snapshot::from_bytes(&bad),
+            Err(DecodeError::UnsupportedVersion(SNAPSHOT_VERSION + 1))
+        );
+
+        let duplicate = Snapshot {
+            bids: vec![(10000, 100), (10000, 50)],
+            ..snap
+        };
+        assert_eq!(
+            Snapshot::from_bytes(&duplicate.to_bytes()),
+            Err(DecodeError::UnorderedLevel { price: 10000 })
+        );
+    }
+
+    #[test]
+    fn test_on_touch_size_change() {
+        use std::sync::{Arc, Mutex};
+
+        let mut ob = book(&[(10000, 100), (9990, 50)], &[(10010, 40)]);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&seen);
+        ob.on_touch_size_change(Box::new(move |side, price, qty| {
+            sink.lock().unwrap().push((side, price, qty));
+        }));
+        let set = |price, quantity, side| Update::Set {
+            price,
+            quantity,
+            side,
+        };
+
+        ob.apply_update(set(10000, 120, Side::Bid));
+        ob.apply_update(set(9990, 10, Side::Bid));
+        ob.apply_update(set(10000, 120, Side::Bid));
+        ob.apply_update(set(10005, 5, Side::Bid));
+        ob.execute(Side::Ask, 15, &mut FullFillModel);
+        ob.apply_update(set(10010, 0, Side::Ask));
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![(Side::Bid, 10000, 120), (Side::Ask, 10010, 25)]
+        );
+    }
+
+    #[test]
+    fn test_quantity_up_to_and_sweep_cost() {
+        // Far apart so the walk has to skip whole L1/L2 words
+        let ob = book(
+            &[(10000, 100), (9990, 50), (2000, 30)],
+            &[(10010, 40), (10020, 60), (30000, 10)],
+        );
+
+        assert_eq!(ob.get_quantity_up_to(10020, Side::Ask), 100);
+        assert_eq!(ob.get_quantity_up_to(10015, Side::Ask), 40);
+        assert_eq!(ob.get_quantity_up_to(40000, Side::Ask), 110);
+        assert_eq!(ob.get_quantity_up_to(9990, Side::Bid), 150);
+        assert_eq!(ob.get_quantity_up_to(10005, Side::Ask), 0);
+        assert_eq!(ob.get_quantity_up_to(10001, Side::Bid), 0);
+
+        assert_eq!(ob.get_sweep_cost(0, Side::Ask), Some((10010, 0)));
+        assert_eq!(
+            ob.get_sweep_cost(70, Side::Ask),
+            Some((10020, 40 * 10010 + 30 * 10020))
+        );
+        assert_eq!(
+            ob.get_sweep_cost(180, Side::Bid),
+            Some((2000, 100 * 10000 + 50 * 9990 + 30 * 2000))
+        );
+        assert_eq!(ob.get_sweep_cost(111, Side::Ask), None);
+        assert_eq!(book(&[], &[]).get_sweep_cost(0, Side::Bid), None);
+    }
+
+    #[test]
+    fn test_pov_slices() {
+        let ob = book(
+            &[],
+            &[(10010, 100), (10020, 250), (10030, 50), (10040, 400)],
+        );
+
+        assert_eq!(
+            ob.pov_slices(Side::Ask, 75, 0.2),
+            vec![(10010, 20), (10020, 50), (10030, 5)]
+        );
+        // Trimmed on the level that completes the total
+        assert_eq!(
+            ob.pov_slices(Side::Ask, 100, 0.2),
+            vec![(10010, 20), (10020, 50), (10030, 10), (10040, 20)]
+        );
+        // Thin book: every level contributes its share and no more
+        assert_eq!(ob.pov_slices(Side::Ask, 10_000, 0.2).len(), 4);
+        assert!(ob.pov_slices(Side::Bid, 10, 0.2).is_empty());
+        assert!(ob.pov_slices(Side::Ask, 0, 0.2).is_empty());
+    }
+
+    #[test]
+    fn test_cross_policy_resolve() {
+        let mut ob = book(
+            &[(10000, 100)],
+            &[(10010, 40), (10020, 60), (10030, 70), (10040, 5)],
+        );
+        ob.set_cross_policy(CrossPolicy::Resolve);
+
+        ob.apply_update(Update::Set {
+            price: 10030,
+            quantity: 25,
+            side: Side::Bid,
+        });
+        assert!(!ob.is_crossed());
+        assert_eq!(ob.get_best_bid(), Some(10030));
+        assert_eq!(ob.get_best_ask(), Some(10040));
+        assert_eq!(ob.get_total_quantity(Side::Ask), 5);
+        assert_eq!(ob.get_total_quantity(Side::Bid), 125);
+        assert_eq!(ob.cross_interventions(), 1);
+
+        // Passive updates and removals are never touched
+        ob.apply_update(Update::Set {
+            price: 10050,
+            quantity: 10,
+            side: Side::Ask,
+        });
+        ob.apply_update(Update::Remove {
+            price: 10040,
+            side: Side::Ask,
+        });
+        assert_eq!(ob.cross_interventions(), 1);
+        assert!(ob.self_check().is_healthy());
+    }
+
+    #[test]
+    fn test_cross_policy_on_locking_update() {
+        let lock = Update::Set {
+            price: 10010,
+            quantity: 30,
+            side: Side::Bid,
+        };
+        let mut outcomes = Vec::new();
+        for policy in [
+            CrossPolicy::Allow,
+            CrossPolicy::Reject,
+            CrossPolicy::Resolve,
+        ] {
+            let mut ob = book(&[(10000, 100)], &[(10010, 40), (10020, 60)]);
+            ob.set_cross_policy(policy);
+            ob.apply_update(lock.clone());
+            assert!(!ob.is_crossed());
+            assert!(ob.self_check().is_healthy());
+            outcomes.push((
+                ob.is_locked(),
+                ob.get_best_bid(),
+                ob.get_best_ask(),
+                ob.get_total_quantity(Side::Bid),
+                ob.get_total_quantity(Side::Ask),
+                ob.cross_interventions(),
+            ));
+        }
+
+        assert_eq!(
+            outcomes,
+            vec![
+                (true, Some(10010), Some(10010), 130, 100, 0),
+                (false, Some(10000), Some(10010), 100, 100, 1),
+                (false, Some(10010), Some(10020), 130, 60, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bitmap_fingerprint_tracks_level_set() {
+        let mut ob = book(&[(10000, 100), (9990, 50)], &[(10010, 40)]);
+        let bids = ob.bitmap_fingerprint(Side::Bid);
+        let asks = ob.bitmap_fingerprint(Side::Ask);
+
+        // Quantity-only changes keep the fingerprint
+        ob.set_level(Side::Bid, 10000, 7);
+        ob.set_level(Side::Ask, 10010, 400);
+        assert!(!ob.changed_since(Side::Bid, bids));
+        assert!(!ob.changed_since(Side::Ask, asks));
+
+        ob.set_level(Side::Bid, 9980, 10);
+        assert!(ob.changed_since(Side::Bid, bids));
+        assert!(!ob.changed_since(Side::Ask, asks));
+        ob.set_level(Side::Bid, 9980, 0);
+        assert!(!ob.changed_since(Side::Bid, bids));
+
+        ob.set_level(Side::Bid, 9990, 0);
+        assert!(ob.changed_since(Side::Bid, bids));
+        ob.set_level(Side::Bid, 9990, 1);
+        assert!(!ob.changed_since(Side::Bid, bids));
+
+        // Levels outside the window count too
+        ob.set_level(Side::Ask, 10010 + CAP as Price, 5);
+        assert!(ob.changed_since(Side::Ask, asks));
+    }
+
+    #[test]
+    fn test_impact_curve_worsens() {
+        let ob = book(
+            &[(10000, 100)],
+            &[(10010, 30), (10020, 50), (10030, 20), (10050, 100)],
+        );
+
+        let curve = ob.impact_curve(Side::Ask, 25, 100);
+        assert_eq!(
+            curve,
+            vec![
+                (25, 10010),
+                (50, 10020),
+                (75, 10020),
+                (100, 10050),
+                (125, 10050),
+                (150, 10050),
+                (175, 10050),
+            ]
+        );
+        assert!(
+            curve
+                .windows(2)
+                .all(|w| w[0].1 <= w[1].1 && w[0].0 < w[1].0)
+        );
+
+        // Bids worsen downwards; the step that would empty the side stops it
+        assert_eq!(ob.impact_curve(Side::Bid, 60, 5), vec![(60, 10000)]);
+        let ob = book(&[(10000, 40), (9990, 40), (9970, 40)], &[]);
+        assert_eq!(
+            ob.impact_curve(Side::Bid, 30, 10),
+            vec![(30, 10000), (60, 9990), (90, 9970)]
+        );
+        assert_eq!(ob.impact_curve(Side::Bid, 30, 2).len(), 2);
+        assert!(ob.impact_curve(Side::Bid, 0, 10).is_empty());
+        assert!(ob.impact_curve(Side::Ask, 1, 10).is_empty());
+    }
+
+    #[test]
+    fn test_reserve_tracked_apart_from_displayed() {
+        let mut ob = OrderBookImpl::new();
+        ob.apply_update(Update::SetWithReserve {
+            price: 10000,
+            quantity: 100,
+            reserve: 900,
+            side: Side::Bid,
+        });
+        ob.apply_update(Update::Set {
+            price: 9990,
+            quantity: 50,
+            side: Side::Bid,
+        });
+        ob.apply_update(Update::SetWithReserve {
+            price: 10010,
+            quantity: 30,
+            reserve: 70,
+            side: Side::Ask,
+        });
+
+        assert_eq!(ob.get_quantity_at(10000, Side::Bid), Some(100));
+        assert_eq!(ob.get_reserve_at(10000, Side::Bid), Some(900));
+        assert_eq!(ob.get_reserve_at(9990, Side::Bid), Some(0));
+        assert_eq!(ob.get_reserve_at(9980, Side::Bid), None);
+        assert_eq!(ob.get_total_quantity(Side::Bid), 150);
+        assert_eq!(ob.total_reserve(Side::Bid), 900);
+        assert_eq!(ob.total_reserve(Side::Ask), 70);
+        assert_eq!(ob.get_best_bid(), Some(10000));
+
+        // Displayed-only updates keep the reserve; removal drops it
+        ob.apply_update(Update::Set {
+            price: 10000,
+            quantity: 40,
+            side: Side::Bid,
+        });
+        assert_eq!(ob.get_reserve_at(10000, Side::Bid), Some(900));
+        ob.apply_update(Update::Remove {
+            price: 10000,
+            side: Side::Bid,
+        });
+        assert_eq!(ob.get_best_bid(), Some(9990));
+        ob.apply_update(Update::Set {
+            price: 10000,
+            quantity: 5,
+            side: Side::Bid,
+        });
+        assert_eq!(ob.get_reserve_at(10000, Side::Bid), Some(0));
+
+        // A reserve without displayed quantity is not a level
+        ob.apply_update(Update::SetWithReserve {
+            price: 10020,
+            quantity: 0,
+            reserve: 500,
+            side: Side::Ask,
+        });
+        assert_eq!(ob.get_reserve_at(10020, Side::Ask), None);
+        assert_eq!(ob.total_reserve(Side::Ask), 70);
+    }
+
+    #[test]
+    fn test_reserve_survives_recenter_and_overflow() {
+        let mut ob = book(&[(10000, 100)], &[(10010, 30)]);
+        // Outside the initial window, inside the one starting at 10000
+        let far = 10000 + 60_000;
+        for (price, reserve) in [(10010, 7), (far, 11)] {
+            ob.apply_update(Update::SetWithReserve {
+                price,
+                quantity: 30,
+                reserve,
+                side: Side::Ask,
+            });
+        }
+        assert_eq!(ob.slot_of(far), None);
+        assert_eq!(ob.get_reserve_at(far, Side::Ask), Some(11));
+        assert_eq!(ob.total_reserve(Side::Ask), 18);
+
+        ob.recenter(10000);
+        assert!(ob.slot_of(far).is_some());
+        assert_eq!(ob.get_reserve_at(10010, Side::Ask), Some(7));
+        assert_eq!(ob.get_reserve_at(far, Side::Ask), Some(11));
+        assert_eq!(ob.total_reserve(Side::Ask), 18);
+
+        // A level recreated after a removal starts without a reserve
+        let beyond = 10000 + CAP as Price + 5;
+        for price in [far, beyond] {
+            ob.apply_update(Update::SetWithReserve {
+                price,
+                quantity: 30,
+                reserve: 3,
+                side: Side::Ask,
+            });
+            ob.apply_update(Update::Remove {
+                price,
+                side: Side::Ask,
+            });
+            ob.apply_update(Update::Set {
+                price,
+                quantity: 1,
+                side: Side::Ask,
+            });
+            assert_eq!(ob.get_reserve_at(price, Side::Ask), Some(0));
+        }
+        assert_eq!(ob.slot_of(beyond), None);
+        assert_eq!(ob.total_reserve(Side::Ask), 7);
+
+        let update = Update::SetWithReserve {
+            price: 9990,
+            quantity: 20,
+            reserve: 300,
+            side: Side::Bid,
+        };
+        let mut buf = Vec::new();
+        put_update(&mut buf, &mut 0, &update);
+        assert_eq!(OrderBookImpl::decode_diff(&buf), Ok(vec![update]));
+        for op in [OP_RESERVE | OP_REMOVE, OP_RESERVE | OP_CHANGE] {
+            assert_eq!(
+                get_update(&[op], &mut 0, &mut 0),
+                Err(DecodeError::InvalidOp(op))
+            );
+        }
+    }
+
+    #[test]
+    fn test_top_of_book_derived_quantities() {
+        let ob = book(
+            &[(10000, 300), (9990, 100), (9980, 600)],
+            &[(10010, 100), (10020, 500)],
+        );
+        let naive = {
+            let mut naive = NaiveOrderBook::new();
+            for side in [Side::Bid, Side::Ask] {
+                for (price, quantity) in ob.get_top_levels(side, usize::MAX) {
+                    naive.apply_update(Update::Set {
+                        price,
+                        quantity,
+                        side,
+                    });
+                }
+            }
+            naive
+        };
+
+        assert_eq!(ob.get_mid_price(), Some(10005.0));
+        // Heavier bid pulls the microprice towards the ask
+        assert_eq!(ob.get_microprice(), Some(10007.5));
+        assert_eq!(ob.get_imbalance(1), Some(0.5));
+        assert_eq!(ob.get_imbalance(2), Some(-0.2));
+        assert_eq!(ob.get_imbalance(3), Some(0.25));
+        assert_eq!(ob.get_imbalance(100), Some(0.25));
+        assert_eq!(ob.get_imbalance(0), None);
+        for depth in [0, 1, 2, 3, 100] {
+            assert_eq!(ob.get_imbalance(depth), naive.get_imbalance(depth));
+        }
+        assert_eq!(ob.get_microprice(), naive.get_microprice());
+        assert_eq!(ob.get_mid_price(), naive.get_mid_price());
+
+        // Odd spreads keep the half tick
+        let ob = book(&[(10000, 1)], &[(10001, 1)]);
+        assert_eq!(ob.get_mid_price(), Some(10000.5));
+
+        for one_sided in [book(&[(10000, 100)], &[]), book(&[], &[(10010, 100)])] {
+            assert_eq!(one_sided.get_mid_price(), None);
+            assert_eq!(one_sided.get_microprice(), None);
+            assert_eq!(one_sided.get_imbalance(1), None);
+            assert_eq!(one_sided.get_imbalance(5), None);
+        }
+    }
+
+    #[test]
+    fn test_derived_quantities_with_empty_touch() {
+        /// Book reporting both touch prices with nothing resting at them
+        struct ZeroTouch;
+        impl OrderBook for ZeroTouch {
+            fn new() -> Self {
+                ZeroTouch
+            }
+            fn apply_update(&mut self, _: Update) {}
+            fn get_spread(&self) -> Option<Price> {
+                Some(10)
+            }
+            fn get_best_bid(&self) -> Option<Price> {
+                Some(10000)
+            }
+            fn get_best_ask(&self) -> Option<Price> {
+                Some(10010)
+            }
+            fn get_quantity_at(&self, _: Price, _: Side) -> Option<Quantity> {
+                Some(0)
+            }
+            fn get_top_levels(&self, side: Side, n: usize) -> Vec<(Price, Quantity)> {
+                let price = match side {
+                    Side::Bid => 10000,
+                    Side::Ask => 10010,
+                };
+                vec![(price, 0); n.min(1)]
+            }
+            fn get_total_quantity(&self, _: Side) -> Quantity {
+                0
+            }
+        }
+
+        assert_eq!(ZeroTouch.get_microprice(), Some(10005.0));
+        assert_eq!(ZeroTouch.get_imbalance(1), None);
+    }
+
+    #[test]
+    fn test_spread_vs_range() {
+        let ob = book(&[(10000, 100)], &[(10050, 100)]);
+        assert_eq!(ob.spread_vs_range(500), Some(0.1));
+        assert_eq!(ob.spread_vs_range(25), Some(2.0));
+        assert_eq!(ob.spread_vs_range(0), None);
+        assert_eq!(ob.spread_vs_range(-500), None);
+        assert_eq!(book(&[(10000, 100)], &[]).spread_vs_range(500), None);
+    }
+
+    #[test]
+    fn test_top_levels_into_buffer() {
+        let far = 10000 - CAP as Price;
+        let ob = book(&[(10000, 100), (9990, 50), (far, 5)], &[]);
+
+        let mut out = [(-1, 0); 5];
+        assert_eq!(ob.get_top_levels_into(Side::Bid, &mut out), 3);
+        assert_eq!(out, [(10000, 100), (9990, 50), (far, 5), (-1, 0), (-1, 0)]);
+
+        let mut short = [(-1, 0); 2];
+        assert_eq!(ob.get_top_levels_into(Side::Bid, &mut short), 2);
+        assert_eq!(short, [(10000, 100), (9990, 50)]);
+        assert_eq!(ob.get_top_levels_into(Side::Bid, &mut []), 0);
+        assert_eq!(ob.get_top_levels_into(Side::Ask, &mut out[..1]), 0);
+        assert_eq!(out[0], (10000, 100));
+
+        assert_eq!(
+            ob.iter_levels(Side::Bid).collect::<Vec<_>>(),
+            ob.get_top_levels(Side::Bid, usize::MAX)
+        );
+        assert_eq!(ob.iter_levels(Side::Ask).next(), None);
+        assert!(ob.self_check().is_healthy());
+    }
+
+    #[test]
+    fn test_apply_batch_reporting_nets_out() {
+        let mut ob = book(&[(10000, 100), (9990, 50)], &[(10010, 40), (10020, 60)]);
+        let set = |price, quantity, side| Update::Set {
+            price,
+            quantity,
+            side,
+        };
+
+        // The best bid moves up, then away, and is restored
+        let delta = ob.apply_batch_reporting(&[
+            set(10005, 10, Side::Bid),
+            set(10000, 0, Side::Bid),
+            set(10005, 0, Side::Bid),
+            set(10000, 70, Side::Bid),
+        ]);
+        assert!(!delta.changed());
+        assert_eq!(delta.event(), BboEvent::NoChange);
+        assert_eq!(ob.get_quantity_at(10000, Side::Bid), Some(70));
+
+        let delta = ob.apply_batch_reporting(&[
+            Update::Remove {
+                price: 10010,
+                side: Side::Ask,
+            },
+            set(10005, 10, Side::Bid),
+        ]);
+        assert!(delta.changed());
+        assert_eq!(
+            delta,
+            BboDelta {
+                bid_before: Some(10000),
+                ask_before: Some(10010),
+                bid_after: Some(10005),
+                ask_after: Some(10020),
+            }
+        );
+        assert_eq!(delta.event(), BboEvent::ShiftedUp);
+        assert!(!ob.apply_batch_reporting(&[]).changed());
+    }
+
+    /// Updates on both sides around 10000, with reserves and prices a window
+    /// or two away
+    fn clear_stream(seed: u64) -> Vec<Update> {
+        let mut rng = seed;
+        (0..3_000)
+            .map(|_| {
+                let roll = splitmix64(&mut rng);
+                let side = if roll & 1 == 0 { Side::Bid } else { Side::Ask };
+                let far = CAP as Price * ((roll >> 8) % 8).saturating_sub(5) as Price;
+                let offset = ((roll >> 16) % 200) as Price + far;
+                let price = match side {
+                    Side::Bid => 9_990 - offset,
+                    Side::Ask => 10_010 + offset,
+                };
+                let quantity = (roll >> 32) % 100;
+                match (roll >> 24) % 4 {
+                    0 => Update::Remove { price, side },
+                    1 => Update::SetWithReserve {
+                        price,
+                        quantity,
+                        reserve: quantity * 2,
+                        side,
+                    },
+                    _ => Update::Set {
+                        price,
+                        quantity,
+                        side,
+                    },
+                }
+            })
+            .collect()
+    }
+
+    fn assert_same_book(a: &OrderBookImpl, b: &OrderBookImpl) {
+        for side in [Side::Bid, Side::Ask] {
+            assert_eq!(
+                a.get_top_levels(side, usize::MAX),
+                b.get_top_levels(side, usize::MAX)
+            );
+            assert_eq!(a.get_total_quantity(side), b.get_total_quantity(side));
+            assert_eq!(a.total_reserve(side), b.total_reserve(side));
+            assert_eq!(a.bitmaps(side), b.bitmaps(side));
+        }
+        assert_eq!(
+            (a.get_best_bid(), a.get_best_ask()),
+            (b.get_best_bid(), b.get_best_ask())
+        );
+        assert_eq!(a.bbo_checksum(), b.bbo_checksum());
+        assert_eq!(a.tick_size(), b.tick_size());
+        assert_eq!(a.cross_policy(), b.cross_policy());
+        assert_eq!(a.cross_interventions(), b.cross_interventions());
+        for price in [0, 9_990, 10_010, 10_010 + CAP as Price] {
+            assert_eq!(a.slot_of(price), b.slot_of(price));
+        }
+        assert!(a.self_check().is_healthy());
+    }
+
+    #[test]
+    fn test_clear_matches_fresh_book() {
+        let mut reused = OrderBookImpl::new();
+        reused.set_cross_policy(CrossPolicy::Reject);
+        reused.set_tick_size(5);
+        reused.set_traversal_limit(3);
+        reused.apply_updates(&clear_stream(1));
+        reused.apply_update(Update::Set {
+            price: 10_000,
+            quantity: 10,
+            side: Side::Ask,
+        });
+
+        reused.clear();
+        let fresh = OrderBookImpl::new();
+        assert_same_book(&reused, &fresh);
+        assert_eq!(reused.get_best_bid(), None);
+        assert_eq!(reused.get_total_quantity(Side::Ask), 0);
+
+        let mut fresh = fresh;
+        for update in clear_stream(2) {
+            reused.apply_update(update.clone());
+            fresh.apply_update(update);
+        }
+        assert_same_book(&reused, &fresh);
+    }
+
+    #[test]
+    fn test_clear_side_leaves_other_side() {
+        let mut ob = OrderBookImpl::new();
+        ob.apply_updates(&clear_stream(3));
+        let asks = ob.get_top_levels(Side::Ask, usize::MAX);
+        let ask_total = ob.get_total_quantity(Side::Ask);
+        assert!(ob.get_best_bid().is_some());
+
+        ob.clear_side(Side::Bid);
+        assert_eq!(ob.get_best_bid(), None);
+        assert_eq!(ob.get_total_quantity(Side::Bid), 0);
+        assert_eq!(ob.total_reserve(Side::Bid), 0);
+        assert_eq!(ob.bitmaps(Side::Bid).0, 0);
+        assert_eq!(ob.get_top_levels(Side::Ask, usize::MAX), asks);
+        assert_eq!(ob.get_total_quantity(Side::Ask), ask_total);
+        let best_ask = ob
+            .get_best_ask()
+            .map(|p| (p, ob.get_quantity_at(p, Side::Ask).unwrap()));
+        assert_eq!(ob.bbo_checksum(), bbo_checksum_of(None, best_ask));
+        assert!(ob.self_check().is_healthy());
+
+        // The wiped side takes updates like a fresh one
+        ob.apply_update(Update::Set {
+            price: 9_000,
+            quantity: 5,
+            side: Side::Bid,
+        });
+        assert_eq!(ob.get_top_levels(Side::Bid, usize::MAX), [(9_000, 5)]);
+        assert_eq!(ob.get_quantity_at(9_990, Side::Bid), None);
     }
 }
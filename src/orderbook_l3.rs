@@ -0,0 +1,497 @@
+// ============================================================================
+// ORDER-LEVEL (L3) BOOK
+// ============================================================================
+// Tracks individual resting orders by id for ITCH-style feeds and keeps an
+// `OrderBookImpl` of per-price aggregates in step with them, so the usual
+// level queries (best prices, top levels, totals) work on `book()`. Every
+// order message becomes at most one `Set` on the aggregate book. Each level
+// also keeps its orders in arrival order, for queue position queries.
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::interfaces::{OrderBook, Price, Quantity, Side, Update};
+use crate::orderbook::OrderBookImpl;
+
+/// Exchange-assigned order identifier
+pub type OrderId = u64;
+
+/// Caller-assigned tag for whoever placed an order, e.g. a simulated strategy
+pub type OwnerId = u32;
+
+/// A live resting order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Order {
+    pub price: Price,
+    pub quantity: Quantity,
+    pub side: Side,
+    /// `None` for orders from the feed, which carry no owner
+    pub owner: Option<OwnerId>,
+}
+
+/// Reasons an order message cannot be applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderError {
+    /// The id is not resting in the book
+    UnknownOrder { id: OrderId },
+    /// An add reuses the id of a live order
+    DuplicateOrder { id: OrderId },
+}
+
+impl std::fmt::Display for OrderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OrderError::UnknownOrder { id } => write!(f, "no live order with id {id}"),
+            OrderError::DuplicateOrder { id } => write!(f, "order id {id} is already live"),
+        }
+    }
+}
+
+impl std::error::Error for OrderError {}
+
+pub struct OrderBookL3 {
+    book: OrderBookImpl,
+    orders: HashMap<OrderId, Order>,
+    /// Live order ids per level, oldest first
+    bid_queues: HashMap<Price, VecDeque<OrderId>>,
+    ask_queues: HashMap<Price, VecDeque<OrderId>>,
+}
+
+impl Default for OrderBookL3 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OrderBookL3 {
+    pub fn new() -> Self {
+        Self {
+            book: OrderBookImpl::new(),
+            orders: HashMap::new(),
+            bid_queues: HashMap::new(),
+            ask_queues: HashMap::new(),
+        }
+    }
+
+    /// The per-price aggregate of every live order
+    pub fn book(&self) -> &OrderBookImpl {
+        &self.book
+    }
+
+    pub fn order(&self, id: OrderId) -> Option<Order> {
+        self.orders.get(&id).copied()
+    }
+
+    pub fn order_count(&self) -> usize {
+        self.orders.len()
+    }
+
+    /// Quantity that has to rest ahead of a new order at `price` for it to
+    /// sit at `target_rank` in the level's queue, 0 being the front: the
+    /// total of the `target_rank` oldest orders there. `None` if the level is
+    /// empty or holds fewer than `target_rank` orders.
+    pub fn qty_to_reach_rank(
+        &self,
+        price: Price,
+        side: Side,
+        target_rank: usize,
+    ) -> Option<Quantity> {
+        let queue = self.queues(side).get(&price)?;
+        if target_rank > queue.len() {
+            return None;
+        }
+        Some(
+            queue
+                .iter()
+                .take(target_rank)
+                .map(|id| self.orders[id].quantity)
+                .sum(),
+        )
+    }
+
+    /// Number of orders and quantity queued ahead of `order_id` at `price`.
+    /// `None` if the order is not resting at that level.
+    pub fn queue_position(
+        &self,
+        price: Price,
+        side: Side,
+        order_id: OrderId,
+    ) -> Option<(usize, Quantity)> {
+        let queue = self.queues(side).get(&price)?;
+        let ahead = queue.iter().position(|&id| id == order_id)?;
+        let qty = queue
+            .iter()
+            .take(ahead)
+            .map(|id| self.orders[id].quantity)
+            .sum();
+        Some((ahead, qty))
+    }
+
+    /// Rest a new order. A zero quantity is accepted and ignored.
+    pub fn add_order(
+        &mut self,
+        id: OrderId,
+        price: Price,
+        quantity: Quantity,
+        side: Side,
+    ) -> Result<(), OrderError> {
+        self.insert_order(id, price, quantity, side, None)
+    }
+
+    /// Rest a new order tagged with `owner`, otherwise as `add_order`
+    pub fn add_owned_order(
+        &mut self,
+        id: OrderId,
+        price: Price,
+        quantity: Quantity,
+        side: Side,
+        owner: OwnerId,
+    ) -> Result<(), OrderError> {
+        self.insert_order(id, price, quantity, side, Some(owner))
+    }
+
+    /// Best bid level counting only quantity not owned by `owner`, with that
+    /// quantity. Levels made up entirely of `owner`'s orders are skipped.
+    pub fn best_bid_excluding(&self, owner: OwnerId) -> Option<(Price, Quantity)> {
+        let mut own: HashMap<Price, Quantity> = HashMap::new();
+        for order in self.orders.values() {
+            if order.side == Side::Bid && order.owner == Some(owner) {
+                *own.entry(order.price).or_default() += order.quantity;
+            }
+        }
+        self.book
+            .get_top_levels(Side::Bid, usize::MAX)
+            .into_iter()
+            .find_map(|(price, level)| {
+                let own = own.get(&price).copied().unwrap_or(0);
+                (level > own).then_some((price, level - own))
+            })
+    }
+
+    fn insert_order(
+        &mut self,
+        id: OrderId,
+        price: Price,
+        quantity: Quantity,
+        side: Side,
+        owner: Option<OwnerId>,
+    ) -> Result<(), OrderError> {
+        if self.orders.contains_key(&id) {
+            return Err(OrderError::DuplicateOrder { id });
+        }
+        if quantity == 0 {
+            return Ok(());
+        }
+        self.orders.insert(
+            id,
+            Order {
+                price,
+                quantity,
+                side,
+                owner,
+            },
+        );
+        self.queues_mut(side)
+            .entry(price)
+            .or_default()
+            .push_back(id);
+        self.adjust_level(side, price, 0, quantity);
+        Ok(())
+    }
+
+    /// Remove a live order. Unknown ids are rejected and leave the book as is.
+    pub fn cancel_order(&mut self, id: OrderId) -> Result<Order, OrderError> {
+        let order = self
+            .orders
+            .remove(&id)
+            .ok_or(OrderError::UnknownOrder { id })?;
+        self.dequeue(order.side, order.price, id);
+        self.adjust_level(order.side, order.price, order.quantity, 0);
+        Ok(order)
+    }
+
+    /// Replace the quantity of a live order, keeping its price. A decrease
+    /// keeps its place in the queue; an increase sends it to the back, as on
+    /// ITCH-style venues. A new quantity of 0 cancels it.
+    pub fn modify_order(&mut self, id: OrderId, new_qty: Quantity) -> Result<(), OrderError> {
+        if new_qty == 0 {
+            return self.cancel_order(id).map(|_| ());
+        }
+        let order = self
+            .orders
+            .get_mut(&id)
+            .ok_or(OrderError::UnknownOrder { id })?;
+        let (side, price, old_qty) = (order.side, order.price, order.quantity);
+        order.quantity = new_qty;
+        if new_qty > old_qty {
+            self.dequeue(side, price, id);
+            self.queues_mut(side)
+                .entry(price)
+                .or_default()
+                .push_back(id);
+        }
+        self.adjust_level(side, price, old_qty, new_qty);
+        Ok(())
+    }
+
+    /// Fill `filled_qty` of a live order, removing it once nothing is left.
+    /// Fills beyond the resting quantity are capped at it. Returns the
+    /// quantity still resting.
+    pub fn execute_order(
+        &mut self,
+        id: OrderId,
+        filled_qty: Quantity,
+    ) -> Result<Quantity, OrderError> {
+        let order = self.order(id).ok_or(OrderError::UnknownOrder { id })?;
+        let remaining = order.quantity.saturating_sub(filled_qty);
+        self.modify_order(id, remaining)?;
+        Ok(remaining)
+    }
+
+    fn queues(&self, side: Side) -> &HashMap<Price, VecDeque<OrderId>> {
+        match side {
+            Side::Bid => &self.bid_queues,
+            Side::Ask => &self.ask_queues,
+        }
+    }
+
+    fn queues_mut(&mut self, side: Side) -> &mut HashMap<Price, VecDeque<OrderId>> {
+        match side {
+            Side::Bid => &mut self.bid_queues,
+            Side::Ask => &mut self.ask_queues,
+        }
+    }
+
+    /// Take `id` out of its level's queue, dropping the queue once empty.
+    /// Fills remove from the front, so the scan is usually short.
+    fn dequeue(&mut self, side: Side, price: Price, id: OrderId) {
+        let queues = self.queues_mut(side);
+        let Some(queue) = queues.get_mut(&price) else {
+            debug_assert!(false, "order {id} has no queue at {price}");
+            return;
+        };
+        if let Some(pos) = queue.iter().position(|&queued| queued == id) {
+            queue.remove(pos);
+        }
+        if queue.is_empty() {
+            queues.remove(&price);
+        }
+    }
+
+    /// Move the aggregate at `price` from including `old_qty` of one order to
+    /// including `new_qty`; the level goes away when it reaches zero
+    fn adjust_level(&mut self, side: Side, price: Price, old_qty: Quantity, new_qty: Quantity) {
+        let level = self.book.get_quantity_at(price, side).unwrap_or(0);
+        debug_assert!(level >= old_qty, "level {price} holds less than its orders");
+        self.book.apply_update(Update::Set {
+            price,
+            quantity: level - old_qty + new_qty,
+            side,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orderbook::splitmix64;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_orders_aggregate_into_levels() {
+        let mut ob = OrderBookL3::new();
+        ob.add_order(1, 10000, 100, Side::Bid).unwrap();
+        ob.add_order(2, 10000, 50, Side::Bid).unwrap();
+        ob.add_order(3, 9990, 70, Side::Bid).unwrap();
+        ob.add_order(4, 10010, 30, Side::Ask).unwrap();
+
+        assert_eq!(
+            ob.add_order(1, 10005, 5, Side::Bid),
+            Err(OrderError::DuplicateOrder { id: 1 })
+        );
+        assert_eq!(
+            ob.book().get_top_levels(Side::Bid, 10),
+            vec![(10000, 150), (9990, 70)]
+        );
+
+        assert_eq!(ob.execute_order(1, 40), Ok(60));
+        assert_eq!(ob.book().get_quantity_at(10000, Side::Bid), Some(110));
+        assert_eq!(ob.execute_order(1, 500), Ok(0));
+        assert_eq!(ob.order(1), None);
+        assert_eq!(ob.book().get_quantity_at(10000, Side::Bid), Some(50));
+        assert_eq!(ob.book().get_total_quantity(Side::Bid), 120);
+    }
+
+    #[test]
+    fn test_cancel_unknown_order_is_rejected() {
+        let mut ob = OrderBookL3::new();
+        ob.add_order(1, 10000, 100, Side::Bid).unwrap();
+
+        assert_eq!(ob.cancel_order(7), Err(OrderError::UnknownOrder { id: 7 }));
+        assert_eq!(
+            ob.modify_order(7, 5),
+            Err(OrderError::UnknownOrder { id: 7 })
+        );
+        assert_eq!(ob.book().get_top_levels(Side::Bid, 10), vec![(10000, 100)]);
+
+        ob.cancel_order(1).unwrap();
+        assert_eq!(ob.cancel_order(1), Err(OrderError::UnknownOrder { id: 1 }));
+    }
+
+    #[test]
+    fn test_modify_last_order_rescans_best() {
+        let mut ob = OrderBookL3::new();
+        ob.add_order(1, 10000, 100, Side::Bid).unwrap();
+        ob.add_order(2, 9990, 50, Side::Bid).unwrap();
+        ob.add_order(3, 10010, 30, Side::Ask).unwrap();
+        ob.add_order(4, 10020, 20, Side::Ask).unwrap();
+
+        ob.modify_order(1, 0).unwrap();
+        assert_eq!(ob.book().get_best_bid(), Some(9990));
+        assert_eq!(ob.book().get_quantity_at(10000, Side::Bid), None);
+
+        ob.execute_order(3, 30).unwrap();
+        assert_eq!(ob.book().get_best_ask(), Some(10020));
+        assert_eq!(ob.book().get_spread(), Some(30));
+    }
+
+    #[test]
+    fn test_random_operations_match_live_orders() {
+        let mut ob = OrderBookL3::new();
+        let mut rng = 7;
+        let mut live: Vec<OrderId> = Vec::new();
+
+        for id in 0..5_000 {
+            let roll = splitmix64(&mut rng);
+            let target = (!live.is_empty()).then(|| live[(roll >> 32) as usize % live.len()]);
+            match (roll % 4, target) {
+                (1, Some(target)) => {
+                    ob.cancel_order(target).unwrap();
+                }
+                (2, Some(target)) => ob.modify_order(target, roll >> 60).unwrap(),
+                (3, Some(target)) => {
+                    ob.execute_order(target, (roll >> 8) % 40).unwrap();
+                }
+                _ => {
+                    let side = if roll & 16 == 0 { Side::Bid } else { Side::Ask };
+                    let price = 10_000 + ((roll >> 16) % 64) as Price;
+                    ob.add_order(id, price, 1 + (roll >> 24) % 100, side)
+                        .unwrap();
+                    live.push(id);
+                }
+            }
+            live.retain(|&id| ob.order(id).is_some());
+        }
+
+        for side in [Side::Bid, Side::Ask] {
+            let mut expected = BTreeMap::new();
+            for order in ob.orders.values().filter(|o| o.side == side) {
+                *expected.entry(order.price).or_insert(0) += order.quantity;
+            }
+            let mut expected: Vec<_> = expected.into_iter().collect();
+            if side == Side::Bid {
+                expected.reverse();
+            }
+            let total: Quantity = expected.iter().map(|&(_, qty)| qty).sum();
+
+            assert_eq!(ob.book().get_top_levels(side, usize::MAX), expected);
+            assert_eq!(ob.book().get_total_quantity(side), total);
+        }
+        assert!(ob.book().self_check().is_healthy());
+
+        for (queues, side) in [(&ob.bid_queues, Side::Bid), (&ob.ask_queues, Side::Ask)] {
+            let queued: usize = queues.values().map(VecDeque::len).sum();
+            assert_eq!(
+                queued,
+                ob.orders.values().filter(|o| o.side == side).count()
+            );
+            for (&price, queue) in queues {
+                assert!(!queue.is_empty());
+                assert!(queue.iter().all(|id| ob.orders[id].price == price));
+            }
+        }
+    }
+
+    #[test]
+    fn test_best_bid_excluding_own_orders() {
+        const ME: OwnerId = 1;
+        let mut ob = OrderBookL3::new();
+        ob.add_owned_order(1, 10000, 100, Side::Bid, ME).unwrap();
+        ob.add_owned_order(2, 10000, 20, Side::Bid, ME).unwrap();
+        ob.add_order(3, 9990, 70, Side::Bid).unwrap();
+        ob.add_owned_order(4, 9990, 30, Side::Bid, ME).unwrap();
+        ob.add_owned_order(5, 9980, 40, Side::Bid, 2).unwrap();
+
+        assert_eq!(ob.order(1).unwrap().owner, Some(ME));
+        assert_eq!(ob.order(3).unwrap().owner, None);
+        assert_eq!(ob.book().get_best_bid(), Some(10000));
+        assert_eq!(ob.best_bid_excluding(ME), Some((9990, 70)));
+        assert_eq!(ob.best_bid_excluding(2), Some((10000, 120)));
+
+        ob.cancel_order(3).unwrap();
+        assert_eq!(ob.best_bid_excluding(ME), Some((9980, 40)));
+        ob.cancel_order(5).unwrap();
+        assert_eq!(ob.best_bid_excluding(ME), None);
+    }
+
+    #[test]
+    fn test_queue_position() {
+        let mut ob = OrderBookL3::new();
+        ob.add_order(1, 10010, 40, Side::Ask).unwrap();
+        ob.add_order(2, 10010, 25, Side::Ask).unwrap();
+        ob.add_order(3, 10010, 60, Side::Ask).unwrap();
+
+        assert_eq!(ob.queue_position(10010, Side::Ask, 2), Some((1, 40)));
+        assert_eq!(ob.queue_position(10010, Side::Ask, 1), Some((0, 0)));
+        assert_eq!(ob.queue_position(10010, Side::Ask, 3), Some((2, 65)));
+        assert_eq!(ob.queue_position(10020, Side::Ask, 2), None);
+        assert_eq!(ob.queue_position(10010, Side::Bid, 2), None);
+        assert_eq!(ob.queue_position(10010, Side::Ask, 9), None);
+
+        ob.execute_order(1, 40).unwrap();
+        assert_eq!(ob.queue_position(10010, Side::Ask, 2), Some((0, 0)));
+        assert_eq!(ob.queue_position(10010, Side::Ask, 3), Some((1, 25)));
+    }
+
+    #[test]
+    fn test_qty_to_reach_rank() {
+        let mut ob = OrderBookL3::new();
+        ob.add_order(1, 10000, 100, Side::Bid).unwrap();
+        ob.add_order(2, 10000, 50, Side::Bid).unwrap();
+        ob.add_order(3, 10000, 70, Side::Bid).unwrap();
+        ob.add_order(4, 9990, 30, Side::Bid).unwrap();
+
+        let ranks: Vec<_> = (0..5)
+            .map(|rank| ob.qty_to_reach_rank(10000, Side::Bid, rank))
+            .collect();
+        assert_eq!(ranks, [Some(0), Some(100), Some(150), Some(220), None]);
+        assert_eq!(ob.qty_to_reach_rank(10000, Side::Ask, 0), None);
+        assert_eq!(ob.qty_to_reach_rank(10010, Side::Bid, 0), None);
+
+        // Partial fills keep their place, cancels close the gap
+        ob.execute_order(1, 60).unwrap();
+        ob.cancel_order(2).unwrap();
+        assert_eq!(ob.qty_to_reach_rank(10000, Side::Bid, 2), Some(110));
+        ob.add_order(5, 10000, 10, Side::Bid).unwrap();
+        assert_eq!(ob.qty_to_reach_rank(10000, Side::Bid, 3), Some(120));
+
+        ob.cancel_order(4).unwrap();
+        assert_eq!(ob.qty_to_reach_rank(9990, Side::Bid, 0), None);
+    }
+
+    #[test]
+    fn test_size_increase_loses_priority() {
+        let mut ob = OrderBookL3::new();
+        ob.add_order(1, 10000, 100, Side::Bid).unwrap();
+        ob.add_order(2, 10000, 50, Side::Bid).unwrap();
+        ob.add_order(3, 10000, 70, Side::Bid).unwrap();
+
+        ob.modify_order(1, 80).unwrap();
+        assert_eq!(ob.queue_position(10000, Side::Bid, 1), Some((0, 0)));
+
+        ob.modify_order(1, 90).unwrap();
+        assert_eq!(ob.queue_position(10000, Side::Bid, 1), Some((2, 120)));
+        assert_eq!(ob.queue_position(10000, Side::Bid, 2), Some((0, 0)));
+        assert_eq!(ob.qty_to_reach_rank(10000, Side::Bid, 3), Some(210));
+        assert_eq!(ob.book().get_quantity_at(10000, Side::Bid), Some(210));
+    }
+}
@@ -0,0 +1,161 @@
+// ============================================================================
+// SEQLOCK-PUBLISHED BBO
+// ============================================================================
+// A single writer owns the book and republishes the best bid/ask into a shared
+// cell after every update that moves them. The cell is guarded by a version
+// counter that is odd while a write is in progress; readers copy the prices
+// and retry until they see the same even version before and after the copy.
+// Readers never block the writer and never take a lock.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering, fence};
+
+use crate::interfaces::{OrderBook, Price, Update};
+use crate::orderbook::OrderBookImpl;
+
+/// Stored in place of a price when the side is empty
+const EMPTY: Price = Price::MIN;
+
+struct SharedBbo {
+    version: AtomicU64,
+    bid: AtomicI64,
+    ask: AtomicI64,
+}
+
+/// Writer side: owns the book and publishes its BBO to every `SeqlockReader`
+pub struct SeqlockBook {
+    book: OrderBookImpl,
+    shared: Arc<SharedBbo>,
+    published: (Option<Price>, Option<Price>),
+}
+
+/// Cheap, cloneable read handle onto a `SeqlockBook`'s BBO
+#[derive(Clone)]
+pub struct SeqlockReader {
+    shared: Arc<SharedBbo>,
+}
+
+impl SeqlockBook {
+    pub fn new(book: OrderBookImpl) -> Self {
+        let published = (book.get_best_bid(), book.get_best_ask());
+        let shared = Arc::new(SharedBbo {
+            version: AtomicU64::new(0),
+            bid: AtomicI64::new(published.0.unwrap_or(EMPTY)),
+            ask: AtomicI64::new(published.1.unwrap_or(EMPTY)),
+        });
+        Self {
+            book,
+            shared,
+            published,
+        }
+    }
+
+    pub fn reader(&self) -> SeqlockReader {
+        SeqlockReader {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    pub fn book(&self) -> &OrderBookImpl {
+        &self.book
+    }
+
+    /// Apply `update`, republishing the BBO if it moved
+    pub fn apply_update(&mut self, update: Update) {
+        self.book.apply_update(update);
+        let bbo = (self.book.get_best_bid(), self.book.get_best_ask());
+        if bbo != self.published {
+            self.publish(bbo);
+        }
+    }
+
+    fn publish(&mut self, (bid, ask): (Option<Price>, Option<Price>)) {
+        let shared = &self.shared;
+        let version = shared.version.load(Ordering::Relaxed);
+        shared.version.store(version + 1, Ordering::Relaxed);
+        // Keep the price stores from becoming visible before the odd version
+        fence(Ordering::Release);
+        shared.bid.store(bid.unwrap_or(EMPTY), Ordering::Relaxed);
+        shared.ask.store(ask.unwrap_or(EMPTY), Ordering::Relaxed);
+        shared.version.store(version + 2, Ordering::Release);
+        self.published = (bid, ask);
+    }
+}
+
+impl SeqlockReader {
+    /// Consistent (best bid, best ask), spinning while a write is in progress
+    pub fn read_bbo(&self) -> (Option<Price>, Option<Price>) {
+        let shared = &self.shared;
+        loop {
+            let before = shared.version.load(Ordering::Acquire);
+            if before & 1 != 0 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let bid = shared.bid.load(Ordering::Relaxed);
+            let ask = shared.ask.load(Ordering::Relaxed);
+            // Keep the price loads from moving past the version re-check
+            fence(Ordering::Acquire);
+            if shared.version.load(Ordering::Relaxed) == before {
+                let present = |p: Price| (p != EMPTY).then_some(p);
+                return (present(bid), present(ask));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interfaces::Side;
+    use std::collections::HashSet;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    #[test]
+    fn test_reader_never_sees_torn_bbo() {
+        let mut book = SeqlockBook::new(OrderBookImpl::new());
+        let reader = book.reader();
+        assert_eq!(reader.read_bbo(), (None, None));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let observer = {
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                let mut seen = HashSet::new();
+                while !done.load(Ordering::Relaxed) {
+                    seen.insert(reader.read_bbo());
+                }
+                seen.insert(reader.read_bbo());
+                seen
+            })
+        };
+
+        // Every state the writer passes through
+        let mut states = HashSet::from([(None, None)]);
+        for i in 0..20_000 {
+            let (bid, ask) = (10_000 + i % 500, 20_000 - i % 700);
+            for (price, side) in [(bid, Side::Bid), (ask, Side::Ask)] {
+                book.apply_update(Update::Set {
+                    price,
+                    quantity: 1,
+                    side,
+                });
+                states.insert((book.book().get_best_bid(), book.book().get_best_ask()));
+            }
+            for (price, side) in [(bid, Side::Bid), (ask, Side::Ask)] {
+                book.apply_update(Update::Remove { price, side });
+                states.insert((book.book().get_best_bid(), book.book().get_best_ask()));
+            }
+        }
+        done.store(true, Ordering::Relaxed);
+
+        let seen = observer.join().unwrap();
+        assert!(
+            seen.is_subset(&states),
+            "torn reads: {:?}",
+            seen.difference(&states)
+        );
+        assert!(seen.contains(&(None, None)));
+    }
+}